@@ -33,8 +33,11 @@ impl DownloadTask {
         //check if all blocks are available, if not, request through dht
         while !queue.is_empty() {
             let chk = queue.pop_front().unwrap();
-            //check if available on kvdb
-            match dht_manager.get_value_local(&chk.query)? {
+            //local kvdb first, then through dht
+            match dht_manager
+                .do_find_value(&chk.query, chk.block_type)
+                .await?
+            {
                 Some(data) => match BlockType::from_u32(chk.block_type)? {
                     BlockType::IBlock => {
                         let i_block = IBlock::from_bytes(&data);
@@ -43,8 +46,10 @@ impl DownloadTask {
                     BlockType::KBlock => {}
                 },
                 None => {
-                    //request
-                    dht_manager.do_find_value(&chk.query).await;
+                    return Err(anyhow::Error::msg(format!(
+                        "Block (bf index {}) is not found on the network",
+                        chk.bf_index
+                    )));
                 }
             }
         }
@@ -1,11 +1,13 @@
 mod block;
 mod chk;
 mod encryption;
+mod validator;
 
 //exports
 pub use block::*;
 pub use chk::{CHK, SERIALIZED_CHK_BUFFER_SIZE};
 pub use encryption::{decode_blocks_to_file, encode_file_to_blocks};
+pub use validator::register_block_validators;
 
 #[cfg(test)]
 mod tests {}
@@ -0,0 +1,100 @@
+use crate::ecrs::{BlockType, MAX_ENCRYPTED_DBLOCK_BUFFER_SIZE, MAX_ENCRYPTED_IBLOCK_BUFFER_SIZE};
+use anyhow::Result;
+use cocoon_core::{BlockValidator, ContentHashValidator, DHTManager, ReplyEvaluation, StoreError};
+use std::sync::Arc;
+
+/// Validator of encrypted DBlocks and IBlocks.
+/// They are stored under the query hash (the hash of the encrypted block).
+struct EncryptedBlockValidator {
+    max_size: usize,
+}
+
+impl BlockValidator for EncryptedBlockValidator {
+    fn validate_store(&self, key: &[u8], data: &[u8]) -> Result<(), StoreError> {
+        if data.len() > self.max_size {
+            return Err(StoreError::Unsupported(format!(
+                "Block of {} bytes is too large",
+                data.len()
+            )));
+        }
+        ContentHashValidator.validate_store(key, data)
+    }
+
+    fn evaluate_reply(&self, key: &[u8], data: &[u8]) -> ReplyEvaluation {
+        if data.len() > self.max_size {
+            return ReplyEvaluation::Invalid;
+        }
+        ContentHashValidator.evaluate_reply(key, data)
+    }
+}
+
+/// Validator of encrypted KBlocks.
+/// They are stored under the keyword hash, so one key has a KBlock for each file with
+/// the keyword. The content is encrypted with the keyword and can not be checked by others.
+/// Known gap: any non-empty blob is accepted under any keyword, so the replies for a keyword
+/// can be flooded with junk. KBlocks would have to be signed (with a key derived from the
+/// keyword) to be checked here.
+struct KBlockValidator;
+
+impl BlockValidator for KBlockValidator {
+    fn validate_store(&self, key: &[u8], data: &[u8]) -> Result<(), StoreError> {
+        if key.len() != 64 || data.len() == 0 {
+            return Err(StoreError::Invalid("Malformed KBlock".to_string()));
+        }
+        Ok(())
+    }
+
+    fn evaluate_reply(&self, key: &[u8], data: &[u8]) -> ReplyEvaluation {
+        match self.validate_store(key, data) {
+            Ok(()) => ReplyEvaluation::More,
+            Err(_) => ReplyEvaluation::Invalid,
+        }
+    }
+}
+
+/// Register the validators of the ECRS blocks, so the DHT accepts them.
+pub fn register_block_validators(dht_manager: &DHTManager) {
+    dht_manager.register_validator(
+        BlockType::DBlock as u32,
+        Arc::new(EncryptedBlockValidator {
+            max_size: MAX_ENCRYPTED_DBLOCK_BUFFER_SIZE,
+        }),
+    );
+    dht_manager.register_validator(
+        BlockType::IBlock as u32,
+        Arc::new(EncryptedBlockValidator {
+            max_size: MAX_ENCRYPTED_IBLOCK_BUFFER_SIZE,
+        }),
+    );
+    dht_manager.register_validator(BlockType::KBlock as u32, Arc::new(KBlockValidator));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EncryptedBlockValidator, KBlockValidator};
+    use cocoon_core::{content_hash, BlockValidator, ReplyEvaluation};
+
+    #[test]
+    fn block_validators() {
+        let validator = EncryptedBlockValidator { max_size: 16 };
+        let block = [7; 16];
+        let query = content_hash(&block);
+        assert!(validator.validate_store(&query, &block).is_ok());
+        assert!(validator.validate_store(&[0; 64], &block).is_err());
+        let large_block = [7; 17];
+        assert!(validator
+            .validate_store(&content_hash(&large_block), &large_block)
+            .is_err());
+        assert_eq!(
+            validator.evaluate_reply(&query, &block),
+            ReplyEvaluation::Last
+        );
+
+        //any number of KBlocks for a keyword
+        assert_eq!(
+            KBlockValidator.evaluate_reply(&[1; 64], &block),
+            ReplyEvaluation::More
+        );
+        assert!(KBlockValidator.validate_store(&[1; 64], &[]).is_err());
+    }
+}
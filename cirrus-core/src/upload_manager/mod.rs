@@ -115,6 +115,25 @@ impl UploadManager {
         infos
     }
 
+    /// Abandon the task: unpublish its blocks and delete its working directory.
+    pub async fn remove_task(&mut self, task_uuid: &Uuid) -> anyhow::Result<()> {
+        let task = match self.task_map.remove(task_uuid) {
+            Some(task) => task,
+            None => {
+                return Err(anyhow::Error::msg(format!(
+                    "Task with ID = {} is not found in upload manager!",
+                    task_uuid
+                )))
+            }
+        };
+        self.tasks.retain(|t| !Arc::ptr_eq(t, &task));
+        let task = task.lock().await;
+        task.unpublish(&self.dht_manager).await?;
+        std::fs::remove_dir_all(&task.working_directory)?;
+        event!(Level::DEBUG, "Removed the upload task {}", task_uuid);
+        Ok(())
+    }
+
     pub async fn start_task(&self, task_uuid: &Uuid) -> anyhow::Result<()> {
         let opt = self.task_map.get(task_uuid);
         if opt.is_none() {
@@ -1,6 +1,7 @@
 use crate::block_file::BlockFile;
 use crate::ecrs::{encode_file_to_blocks, CHK};
 use crate::upload_manager::upload_task_info;
+use anyhow::anyhow;
 use async_std::fs::OpenOptions;
 use async_std::prelude::*;
 use cocoon_core::DHTManager;
@@ -112,6 +113,10 @@ impl UploadTask {
     }
 
     /// Upload encoded blocks with DHTManager.
+    /// Fails if no node confirmed the storage of a block, the upload is done only
+    /// when every block has a replica.
+    /// With store_locally, the blocks are also kept and republished by this node
+    /// until the task is removed.
     pub async fn upload(
         &self,
         dht_manager: &Arc<DHTManager>,
@@ -140,13 +145,25 @@ impl UploadTask {
         for i in 0..d_block_bf.n() as usize {
             let d_block_chk = CHK::from_bytes(&d_block_chk_bf.read_nth_block(i).await?);
             let encrypted_d_block_buffer = d_block_bf.read_nth_block(i).await?;
-            dht_manager
-                .do_store(&d_block_chk.key, &encrypted_d_block_buffer)
-                .await; //upload DBlock
+            //blocks are stored under the query hash, which other nodes can verify
+            let replicas = dht_manager
+                .do_store(
+                    &d_block_chk.query,
+                    &encrypted_d_block_buffer,
+                    d_block_chk.block_type,
+                )
+                .await?; //upload DBlock
+            if replicas.is_empty() {
+                return Err(anyhow!("No node confirmed the storage of the DBlock {}", i));
+            }
 
             //store locally
             if store_locally {
-                dht_manager.store_on_local(&d_block_chk.key, &encrypted_d_block_buffer);
+                dht_manager.publish_local(
+                    &d_block_chk.query,
+                    &encrypted_d_block_buffer,
+                    d_block_chk.block_type,
+                )?;
             }
         }
 
@@ -160,13 +177,24 @@ impl UploadTask {
         for i in 0..i_block_bf.n() as usize {
             let encrypted_i_block_buffer = i_block_bf.read_nth_block(i).await?;
             let i_block_chk = CHK::from_bytes(&i_block_chk_bf.read_nth_block(i).await?);
-            dht_manager
-                .do_store(&i_block_chk.key, &encrypted_i_block_buffer)
-                .await; //upload IBlock
+            let replicas = dht_manager
+                .do_store(
+                    &i_block_chk.query,
+                    &encrypted_i_block_buffer,
+                    i_block_chk.block_type,
+                )
+                .await?; //upload IBlock
+            if replicas.is_empty() {
+                return Err(anyhow!("No node confirmed the storage of the IBlock {}", i));
+            }
 
             //store locally
             if store_locally {
-                dht_manager.store_on_local(&i_block_chk.key, &encrypted_i_block_buffer);
+                dht_manager.publish_local(
+                    &i_block_chk.query,
+                    &encrypted_i_block_buffer,
+                    i_block_chk.block_type,
+                )?;
             }
         }
 
@@ -178,6 +206,23 @@ impl UploadTask {
         Ok(())
     }
 
+    /// Stop republishing the blocks kept by upload, and remove them from the local kvdb.
+    /// Does nothing if the file is not encoded yet.
+    pub async fn unpublish(&self, dht_manager: &Arc<DHTManager>) -> anyhow::Result<()> {
+        if !*self.is_encode_done.lock().unwrap() {
+            return Ok(());
+        }
+        for chk_bf_name in ["blocks.d.chk", "blocks.i.chk"] {
+            let mut chk_bf = BlockFile::open(&self.working_directory.join(chk_bf_name)).await?;
+            for i in 0..chk_bf.n() as usize {
+                let chk = CHK::from_bytes(&chk_bf.read_nth_block(i).await?);
+                dht_manager.unpublish(&chk.query)?;
+            }
+        }
+        event!(Level::DEBUG, "Unpublished the blocks of {:?}", self.uuid);
+        Ok(())
+    }
+
     pub fn info(&self) -> UploadTaskInfo {
         UploadTaskInfo {
             id: self.uuid.to_string(),
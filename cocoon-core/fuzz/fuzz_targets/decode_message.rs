@@ -0,0 +1,10 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+//same steps as the receive loop, any bytes must be rejected with an error instead of a panic
+fuzz_target!(|data: &[u8]| {
+    let _ = cocoon_core::decode_message(data);
+    if let Ok((_, message)) = cocoon_core::verify_message(data) {
+        let _ = cocoon_core::decode_message(message);
+    }
+});
@@ -1,9 +1,20 @@
+use crate::constant;
 use config::{Config, ConfigError, Environment, File};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 #[derive(Debug, Deserialize)]
 pub struct KVDatabaseConfig {
     pub db_path: PathBuf,
+    /// Bytes of values to keep at most.
+    /// Values we published ourselves are kept over the quota.
+    #[serde(default = "default_kvdb_quota")]
+    pub quota: u64,
+}
+
+fn default_kvdb_quota() -> u64 {
+    constant::DEFAULT_KVDB_QUOTA
 }
 
 #[derive(Debug, Deserialize)]
@@ -11,10 +22,127 @@ pub struct SqliteConfig {
     pub db_path: PathBuf,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct NetworkManagerConfig {
+    /// Addresses to bind the UDP sockets to, IPv4 and/or IPv6.
+    /// An unspecified IPv6 address ("[::]:port") is dual-stack if no IPv4 address is given.
+    #[serde(default = "default_bind_addresses")]
+    pub bind_addresses: Vec<SocketAddr>,
+    /// Endpoint other peers reach us by (e.g. with a port forwarding).
+    /// The first bound address if not set.
+    #[serde(default)]
+    pub announced_address: Option<SocketAddr>,
+    /// Peers to contact on startup.
+    #[serde(default)]
+    pub bootstrap_nodes: Vec<SocketAddr>,
+    /// Largest datagram to send, larger messages are fragmented.
+    #[serde(default = "default_mtu")]
+    pub mtu: usize,
+    /// Bucket size of the route table ('K').
+    #[serde(default = "default_k")]
+    pub k: u16,
+    /// Size of the node IDs in the route table in bytes, 1 to KEY_SIZE.
+    /// Smaller IDs are for small test networks.
+    #[serde(default = "default_node_id_size")]
+    pub node_id_size: usize,
+    /// Most received messages waiting for the handlers, the rest are dropped.
+    #[serde(default = "default_receive_queue_size")]
+    pub receive_queue_size: usize,
+    /// Number of tasks handling the received messages.
+    #[serde(default = "default_receive_workers")]
+    pub receive_workers: usize,
+    #[serde(default)]
+    pub maintenance: MaintenanceConfig,
+}
+
+impl Default for NetworkManagerConfig {
+    fn default() -> Self {
+        NetworkManagerConfig {
+            bind_addresses: default_bind_addresses(),
+            announced_address: None,
+            bootstrap_nodes: Vec::new(),
+            mtu: default_mtu(),
+            k: default_k(),
+            node_id_size: default_node_id_size(),
+            receive_queue_size: default_receive_queue_size(),
+            receive_workers: default_receive_workers(),
+            maintenance: MaintenanceConfig::default(),
+        }
+    }
+}
+
+fn default_bind_addresses() -> Vec<SocketAddr> {
+    vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)]
+}
+
+fn default_mtu() -> usize {
+    constant::DEFAULT_MTU
+}
+
+fn default_k() -> u16 {
+    constant::DEFAULT_K
+}
+
+fn default_node_id_size() -> usize {
+    constant::KEY_SIZE
+}
+
+fn default_receive_queue_size() -> usize {
+    constant::RECEIVE_QUEUE_SIZE
+}
+
+fn default_receive_workers() -> usize {
+    constant::RECEIVE_WORKERS
+}
+
+/// Background maintenance of the route table, see DHTManager::start_maintenance.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct MaintenanceConfig {
+    pub enabled: bool,
+    /// Seconds between the maintenance rounds.
+    pub interval_secs: u64,
+    /// Buckets without a lookup for this many seconds are refreshed,
+    /// with a lookup of a random ID in their range.
+    pub bucket_refresh_secs: u64,
+    /// Nodes not seen for this many seconds are pinged.
+    pub quiet_node_secs: u64,
+    /// Nodes which failed this many requests in a row are dropped.
+    pub max_failures: u32,
+}
+
+impl MaintenanceConfig {
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs)
+    }
+
+    pub fn bucket_refresh_interval(&self) -> Duration {
+        Duration::from_secs(self.bucket_refresh_secs)
+    }
+
+    pub fn quiet_node_timeout(&self) -> Duration {
+        Duration::from_secs(self.quiet_node_secs)
+    }
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> Self {
+        MaintenanceConfig {
+            enabled: true,
+            interval_secs: constant::MAINTENANCE_INTERVAL.as_secs(),
+            bucket_refresh_secs: constant::BUCKET_REFRESH_INTERVAL.as_secs(),
+            quiet_node_secs: constant::NODE_QUIET_TIMEOUT.as_secs(),
+            max_failures: constant::MAX_NODE_FAILURES,
+        }
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct DaemonConfig {
     pub kv_database_config: KVDatabaseConfig,
     pub sqlite_config: SqliteConfig,
+    #[serde(default)]
+    pub network_manager_config: NetworkManagerConfig,
     pub working_directory: PathBuf,
 }
 
@@ -1 +1,84 @@
-pub const MESSAGE_HEADER_SIZE: usize = 4;
+use std::time::Duration;
+
+pub const MESSAGE_HEADER_SIZE: usize = 8;
+/// Size of the keys and node IDs (SHA3-512).
+pub const KEY_SIZE: usize = 64;
+/// Default bucket size and number of nodes returned by a lookup ('K').
+pub const DEFAULT_K: u16 = 20;
+/// Number of parallel requests in an iterative lookup ('alpha').
+pub const LOOKUP_ALPHA: usize = 3;
+/// Retransmission timeout of the requests to a node without a measured round trip time.
+pub const INITIAL_RTO: Duration = Duration::from_millis(500);
+/// Shortest retransmission timeout, however fast the node answers.
+pub const MIN_RTO: Duration = Duration::from_millis(100);
+/// Longest retransmission timeout, the backoff stops doubling here.
+pub const MAX_RTO: Duration = Duration::from_secs(3);
+/// How many times an unanswered request is sent again before it fails.
+pub const MAX_RETRANSMITS: u32 = 2;
+/// How long a store waits for the replicas to confirm, the request takes several hops.
+pub const STORE_ACK_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often the route table is saved to the database.
+pub const ROUTE_TABLE_SAVE_INTERVAL: Duration = Duration::from_secs(300);
+/// Replication level of the values we publish.
+pub const REPLICATION_LEVEL: u32 = 10;
+/// How long other nodes keep the values we publish.
+pub const RECORD_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// Longest lifetime accepted from a store request.
+pub const MAX_RECORD_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// How often the values we publish are sent again, before they expire on other nodes.
+pub const REPUBLISH_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+/// Default quota of the kvdb, 1GiB.
+pub const DEFAULT_KVDB_QUOTA: u64 = 1 << 30;
+/// How often expired values are removed from the kvdb.
+pub const RECORD_SWEEP_INTERVAL: Duration = Duration::from_secs(600);
+/// Largest datagram we send by default, larger messages are fragmented.
+/// Fits in the minimum IPv6 MTU (1280) with the IP and UDP headers.
+pub const DEFAULT_MTU: usize = 1200;
+/// Smallest MTU accepted from the config.
+pub const MIN_MTU: usize = 512;
+/// Largest value which can be stored on the DHT.
+/// Fits an encrypted DBlock or IBlock.
+pub const MAX_VALUE_SIZE: usize = 64 * 1024;
+/// Largest message (before fragmentation), a value with the key and the framing.
+pub const MAX_MESSAGE_SIZE: usize = MAX_VALUE_SIZE + 4096;
+/// Large enough for any UDP datagram.
+pub const UDP_RECEIVE_BUFFER_SIZE: usize = 65536;
+/// How long the fragments of a message are kept waiting for the rest.
+pub const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Most bytes of fragments buffered for reassembly.
+pub const MAX_REASSEMBLY_BYTES: usize = 8 * 1024 * 1024;
+/// Nodes which must report the same endpoint of ours before we take it.
+pub const MIN_ADDRESS_REPORTS: usize = 3;
+/// Most reports of our endpoint kept, one per node.
+pub const MAX_ADDRESS_REPORTS: usize = 64;
+/// How long a report of our endpoint counts.
+pub const ADDRESS_REPORT_TTL: Duration = Duration::from_secs(60 * 60);
+/// How long a session is used to send, a new handshake is made after.
+pub const SESSION_REKEY_INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// How long a session accepts datagrams, a bit longer than it is used to send.
+pub const SESSION_LIFETIME: Duration = Duration::from_secs(11 * 60);
+/// How long a handshake waits for the response before the init is sent again.
+pub const HANDSHAKE_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+/// How long the datagrams queued for a handshake wait, they are dropped after.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Largest difference between the time of a handshake init and ours,
+/// with the clock differences of the nodes. Older inits are rejected as replayed.
+pub const HANDSHAKE_MAX_AGE: Duration = Duration::from_secs(120);
+/// Most datagrams queued for a peer while the handshake is in progress.
+pub const MAX_QUEUED_DATAGRAMS: usize = 32;
+/// Most sessions kept, the oldest ones are dropped first.
+pub const MAX_SESSIONS: usize = 4096;
+/// Most handshakes in progress.
+pub const MAX_HANDSHAKES: usize = 1024;
+/// How often the route table maintenance runs by default.
+pub const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60);
+/// Buckets without a lookup for this long are refreshed by default.
+pub const BUCKET_REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Nodes not seen for this long are pinged by default.
+pub const NODE_QUIET_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+/// Nodes which failed this many requests in a row are dropped by default.
+pub const MAX_NODE_FAILURES: u32 = 3;
+/// Most received messages waiting to be handled by default, the rest are dropped.
+pub const RECEIVE_QUEUE_SIZE: usize = 1024;
+/// Number of tasks handling the received messages by default.
+pub const RECEIVE_WORKERS: usize = 4;
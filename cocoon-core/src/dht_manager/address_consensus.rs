@@ -0,0 +1,138 @@
+use crate::constant::{ADDRESS_REPORT_TTL, MAX_ADDRESS_REPORTS, MIN_ADDRESS_REPORTS};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use tokio::time::Instant;
+
+struct Report {
+    endpoint: SocketAddr,
+    received: Instant,
+}
+
+/// AddressConsensus
+/// Our endpoint as the nodes we pinged saw it, reported in their ping responses.
+/// A node has one report, so it can not outvote the others by answering many pings.
+/// The endpoint reported by the majority (and at least MIN_ADDRESS_REPORTS nodes) is ours.
+pub struct AddressConsensus {
+    /// Reports keyed by the node ID of the reporter.
+    reports: HashMap<Vec<u8>, Report>,
+}
+
+impl AddressConsensus {
+    pub fn new() -> Self {
+        AddressConsensus {
+            reports: HashMap::new(),
+        }
+    }
+
+    /// Add the endpoint the node saw us at, replacing its previous report.
+    /// Returns the endpoint of the majority of the address family, if any.
+    pub fn report(
+        &mut self,
+        node_id: &[u8],
+        endpoint: &SocketAddr,
+        now: Instant,
+    ) -> Option<SocketAddr> {
+        self.expire(now);
+        if endpoint.ip().is_unspecified() || endpoint.port() == 0 {
+            return None;
+        }
+        if !self.reports.contains_key(node_id) && self.reports.len() >= MAX_ADDRESS_REPORTS {
+            //make room, dropping the oldest report
+            let oldest = self
+                .reports
+                .iter()
+                .min_by_key(|(_, report)| report.received)
+                .map(|(id, _)| id.clone())
+                .unwrap();
+            self.reports.remove(&oldest);
+        }
+        self.reports.insert(
+            node_id.to_vec(),
+            Report {
+                endpoint: *endpoint,
+                received: now,
+            },
+        );
+        self.majority(endpoint.is_ipv4())
+    }
+
+    /// Endpoint reported by more than half of the nodes which reported one of the address family.
+    pub fn majority(&self, is_ipv4: bool) -> Option<SocketAddr> {
+        let mut counts: HashMap<SocketAddr, usize> = HashMap::new();
+        let mut total = 0;
+        for report in self
+            .reports
+            .values()
+            .filter(|report| report.endpoint.is_ipv4() == is_ipv4)
+        {
+            *counts.entry(report.endpoint).or_insert(0) += 1;
+            total += 1;
+        }
+        counts
+            .into_iter()
+            .find(|(_, count)| *count >= MIN_ADDRESS_REPORTS && *count * 2 > total)
+            .map(|(endpoint, _)| endpoint)
+    }
+
+    /// Drop the old reports, our endpoint may have changed since (e.g. NAT rebinding).
+    fn expire(&mut self, now: Instant) {
+        self.reports
+            .retain(|_, report| now.duration_since(report.received) < ADDRESS_REPORT_TTL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AddressConsensus;
+    use crate::constant::{ADDRESS_REPORT_TTL, MAX_ADDRESS_REPORTS, MIN_ADDRESS_REPORTS};
+    use std::net::SocketAddr;
+    use tokio::time::Instant;
+
+    #[test]
+    fn majority() {
+        let public: SocketAddr = "203.0.113.7:4870".parse().unwrap();
+        let lying: SocketAddr = "198.51.100.1:1".parse().unwrap();
+        let now = Instant::now();
+        let mut consensus = AddressConsensus::new();
+
+        //not enough reports yet
+        for i in 0..MIN_ADDRESS_REPORTS - 1 {
+            assert_eq!(consensus.report(&[i as u8], &public, now), None);
+        }
+        //a node does not count twice
+        assert_eq!(consensus.report(&[0], &public, now), None);
+        assert_eq!(consensus.report(&[100], &public, now), Some(public));
+
+        //the liars are the minority
+        for i in 0..MIN_ADDRESS_REPORTS - 1 {
+            let id = [200, i as u8];
+            assert_eq!(consensus.report(&id, &lying, now), Some(public));
+        }
+        //tie, no majority
+        assert_eq!(consensus.report(&[201], &lying, now), None);
+        //other address family
+        assert_eq!(consensus.majority(false), None);
+        assert_eq!(
+            consensus.report(&[202], &"0.0.0.0:4870".parse().unwrap(), now),
+            None
+        );
+
+        //old reports expire
+        let later = now + ADDRESS_REPORT_TTL;
+        assert_eq!(consensus.report(&[1], &lying, later), None);
+        assert_eq!(consensus.reports.len(), 1);
+    }
+
+    #[test]
+    fn bounded() {
+        let public: SocketAddr = "[2001:db8::7]:4870".parse().unwrap();
+        let now = Instant::now();
+        let mut consensus = AddressConsensus::new();
+        for i in 0..MAX_ADDRESS_REPORTS * 2 {
+            let id = (i as u32).to_le_bytes();
+            let majority = consensus.report(&id, &public, now);
+            assert_eq!(majority.is_some(), i + 1 >= MIN_ADDRESS_REPORTS);
+        }
+        assert_eq!(consensus.reports.len(), MAX_ADDRESS_REPORTS);
+    }
+}
@@ -0,0 +1,644 @@
+use super::address_consensus::AddressConsensus;
+use super::pending_request::{Completion, PendingRequests};
+use super::{
+    pong, reply_store, select_next_hops, send_message, spawn_eviction_probe, update_own_endpoint,
+};
+use crate::constant::{MAX_RECORD_TTL, STORE_ACK_TIMEOUT};
+use crate::identity::{is_valid_public_key, public_key_to_node_id, Identity};
+use crate::message::*;
+use crate::record_store::RecordStore;
+use crate::route_table::RouteTable;
+use crate::session::SessionSocket;
+use crate::utility;
+use crate::validation::{BlockValidators, ReplyEvaluation, StoreError};
+use anyhow::{anyhow, Result};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+use tracing::{event, Level};
+
+/// A decoded message from an authenticated peer, queued for the handlers.
+pub struct Incoming {
+    pub header: MessageHeader,
+    pub message: Message,
+    /// Header and body, as received.
+    pub bytes: Vec<u8>,
+    pub sender: SocketAddr,
+    pub public_key: Vec<u8>,
+}
+
+/// MessageHandler
+/// Handles the received messages, one function per message type.
+/// Each handler worker has a clone, an error only ends the handling of one message.
+#[derive(Clone)]
+pub struct MessageHandler {
+    pub socket: Arc<SessionSocket>,
+    pub route_table: Arc<Mutex<RouteTable>>,
+    pub record_store: Arc<RecordStore>,
+    pub pending_requests: Arc<std::sync::Mutex<PendingRequests>>,
+    pub identity: Arc<Identity>,
+    pub validators: Arc<std::sync::RwLock<BlockValidators>>,
+    pub rejected_values: Arc<AtomicU64>,
+    pub address_consensus: Arc<std::sync::Mutex<AddressConsensus>>,
+    pub is_address_announced: bool,
+}
+
+impl MessageHandler {
+    pub async fn handle(&self, incoming: Incoming) {
+        let Incoming {
+            header,
+            message,
+            bytes,
+            sender,
+            public_key,
+        } = incoming;
+        let result = match message {
+            Message::PingRequest(_) => {
+                self.handle_ping_request(&header, &sender, &public_key)
+                    .await
+            }
+            Message::StoreValueRequest(msg) => {
+                self.handle_store_value_request(&header, &sender, &public_key, msg)
+                    .await
+            }
+            Message::FindNodeRequest(msg) => {
+                self.handle_find_node_request(&header, &sender, msg).await
+            }
+            Message::FindValueRequest(msg) => {
+                self.handle_find_value_request(&header, &sender, msg).await
+            }
+            Message::PingResponse(msg) => {
+                self.handle_ping_response(&header, &sender, &public_key, &bytes, msg)
+                    .await
+            }
+            Message::FindNodeResponse(msg) => {
+                self.handle_find_node_response(&header, &sender, &public_key, &bytes, msg)
+                    .await
+            }
+            Message::StoreValueResponse(msg) => {
+                self.handle_store_value_response(&header, &sender, &public_key, &bytes, msg)
+                    .await
+            }
+            Message::FindValueResponse(_) => {
+                self.handle_find_value_response(&header, &sender, &public_key, &bytes)
+                    .await
+            }
+        };
+        if let Err(e) = result {
+            event!(
+                Level::DEBUG,
+                "Dropped a message (type {}) from {}: {}",
+                header.message_type,
+                sender,
+                e
+            );
+        }
+    }
+
+    /// Pass a response to the request waiting for it.
+    /// Returns the round trip time of the request, if it tells one.
+    fn complete(
+        &self,
+        header: &MessageHeader,
+        sender: &SocketAddr,
+        public_key: &[u8],
+        bytes: &[u8],
+    ) -> Result<Option<Duration>> {
+        let completion = self
+            .pending_requests
+            .lock()
+            .unwrap()
+            .complete(header, sender, public_key, bytes);
+        match completion {
+            Completion::Answered(rtt) => Ok(rtt),
+            //TODO: block the sender(not permanently)
+            Completion::Unexpected | Completion::Relayed(..) => Err(anyhow!("not asked for")),
+        }
+    }
+
+    fn spawn_eviction_probe(&self, probed: PeerInfo) {
+        spawn_eviction_probe(
+            &self.route_table,
+            &self.socket,
+            &self.pending_requests,
+            &self.identity,
+            probed,
+        );
+    }
+
+    async fn handle_ping_request(
+        &self,
+        header: &MessageHeader,
+        sender: &SocketAddr,
+        public_key: &[u8],
+    ) -> Result<()> {
+        event!(Level::DEBUG, "Received ping request from {}", sender);
+        //TODO: should I add the sender to route table?
+        // for now add
+        let probe;
+        {
+            let mut rt = self.route_table.lock().await;
+            let is_handled = rt.add_node(public_key, sender)?;
+            probe = if is_handled {
+                None
+            } else {
+                event!(Level::DEBUG, "Space not available for the new node");
+                rt.add_replacement(&PeerInfo::new(sender, public_key))
+            };
+        }
+        if let Some(lrs) = probe {
+            self.spawn_eviction_probe(lrs);
+        }
+        //send ping reply(pong)
+        pong(&self.socket, &self.identity, sender, header.transaction_id).await
+    }
+
+    async fn handle_store_value_request(
+        &self,
+        header: &MessageHeader,
+        sender: &SocketAddr,
+        public_key: &[u8],
+        msg: StoreValueRequestMessage,
+    ) -> Result<()> {
+        //answered hop by hop, only to the node the request came from
+        if msg.data.len() == 0 || msg.ttl == 0 {
+            let response =
+                StoreValueResponseMessage::reject(&msg.key, "empty value or no lifetime");
+            self.reply_store(sender, header, msg.nonce, response).await;
+            return Ok(());
+        }
+        let validated =
+            self.validators
+                .read()
+                .unwrap()
+                .validate_store(msg.block_type, &msg.key, &msg.data);
+        if let Err(e) = validated {
+            event!(
+                Level::DEBUG,
+                "Rejected a store value request from {}: {}",
+                sender,
+                e
+            );
+            self.rejected_values.fetch_add(1, Ordering::Relaxed);
+            //the sender stored or forwarded a bad value, stop routing through it,
+            //unlike a value of a block type (or size) only other versions take
+            if let StoreError::Invalid(_) = e {
+                self.route_table
+                    .lock()
+                    .await
+                    .remove_node(&public_key_to_node_id(public_key));
+                self.socket.close_session(sender);
+            }
+            let response = StoreValueResponseMessage::reject(&msg.key, &e.to_string());
+            self.reply_store(sender, header, msg.nonce, response).await;
+            return Ok(());
+        }
+
+        let stored;
+        let nodes_to_foward;
+        {
+            let route_table = self.route_table.lock().await;
+            let network_size = route_table.estimate_network_size();
+            let is_random_walk = msg.hop_count < utility::random_walk_length(network_size);
+            //Am I closest to the key?
+            //every node is a replica in a network smaller than the replication level
+            if !is_random_walk
+                && (route_table.is_closest_to(&msg.key)
+                    || network_size <= msg.replication_level as usize)
+            {
+                //yes, save data on local
+                let ttl = std::cmp::min(Duration::from_secs(msg.ttl), MAX_RECORD_TTL);
+                stored = Some(
+                    match self.record_store.put(
+                        &msg.key,
+                        &msg.data,
+                        ttl,
+                        false,
+                        msg.block_type,
+                        utility::unix_time_now(),
+                    ) {
+                        Ok(true) => StoreValueResponseMessage::accept(&msg.key),
+                        Ok(false) => {
+                            event!(
+                                Level::DEBUG,
+                                "Ignore a store value request, the kvdb is full"
+                            );
+                            StoreValueResponseMessage::reject(&msg.key, "kvdb is full")
+                        }
+                        Err(e) => {
+                            event!(
+                                Level::ERROR,
+                                "Failed to save a store request data on kvdb: {}",
+                                e
+                            );
+                            StoreValueResponseMessage::reject(&msg.key, "failed to save the value")
+                        }
+                    },
+                );
+                nodes_to_foward = Vec::new();
+            } else {
+                stored = None;
+                nodes_to_foward =
+                    select_next_hops(&route_table, &msg.key, msg.hop_count, msg.replication_level);
+            }
+        }
+        if let Some(response) = stored {
+            self.reply_store(sender, header, msg.nonce, response).await;
+            return Ok(());
+        }
+
+        if nodes_to_foward.len() == 0 {
+            //too many hops, or no one to forward to
+            event!(
+                Level::DEBUG,
+                "Drop a store value request at hop {}",
+                msg.hop_count
+            );
+            let response = StoreValueResponseMessage::reject(&msg.key, "no node to forward to");
+            self.reply_store(sender, header, msg.nonce, response).await;
+            return Ok(());
+        }
+        event!(
+            Level::DEBUG,
+            "Foward a store value request message to {} nodes (hop {})",
+            nodes_to_foward.len(),
+            msg.hop_count
+        );
+        let mut foward_msg = StoreValueRequestMessage::new(
+            &msg.key,
+            &msg.data,
+            msg.replication_level,
+            msg.hop_count + 1,
+            msg.ttl,
+            msg.block_type,
+        );
+        foward_msg.nonce = msg.nonce;
+        //the responses come back to us, and are passed back to the sender
+        let transaction_id = self.pending_requests.lock().unwrap().register_relay(
+            sender,
+            header.transaction_id,
+            MessageType::StoreValueResponse,
+            STORE_ACK_TIMEOUT,
+        );
+        let foward_bytes = foward_msg.to_bytes(transaction_id);
+        for peer in &nodes_to_foward {
+            if let Err(e) =
+                send_message(&self.socket, &self.identity, &foward_bytes, &peer.endpoint).await
+            {
+                event!(
+                    Level::DEBUG,
+                    "Failed to forward a store request to {}: {}",
+                    peer.endpoint,
+                    e
+                );
+            }
+        }
+        Ok(())
+    }
+
+    async fn reply_store(
+        &self,
+        endpoint: &SocketAddr,
+        header: &MessageHeader,
+        nonce: u64,
+        mut response: StoreValueResponseMessage,
+    ) {
+        response.sign(&self.identity, nonce);
+        reply_store(
+            &self.socket,
+            &self.identity,
+            endpoint,
+            header.transaction_id,
+            &response,
+        )
+        .await;
+    }
+
+    async fn handle_find_node_request(
+        &self,
+        header: &MessageHeader,
+        sender: &SocketAddr,
+        msg: FindNodeRequestMessage,
+    ) -> Result<()> {
+        //TODO when to forward the messsage?
+        let nodes;
+        {
+            let route_table = self.route_table.lock().await;
+            nodes = route_table.find_nodes(&msg.key, route_table.k().into());
+        }
+        if nodes.len() == 0 {
+            //reply anyway, so the requester does not wait for us
+            event!(Level::DEBUG, "Closest peer not found");
+        }
+        let peers: Vec<PeerInfo> = nodes
+            .iter()
+            .map(|node| node.lock().unwrap().peer_info())
+            .collect();
+        let response_msg = FindNodeResponseMessage::new(&msg.key, &peers);
+        let response_bytes = response_msg.to_bytes(header.transaction_id);
+        send_message(&self.socket, &self.identity, &response_bytes, sender).await
+    }
+
+    async fn handle_find_value_request(
+        &self,
+        header: &MessageHeader,
+        sender: &SocketAddr,
+        msg: FindValueRequestMessage,
+    ) -> Result<()> {
+        event!(Level::DEBUG, "Received find value request");
+
+        //check kvdb
+        let get_opt = match self.record_store.get(&msg.key, utility::unix_time_now()) {
+            Ok(get_opt) => get_opt,
+            Err(e) => {
+                event!(Level::ERROR, "Failed to perform kvdb get operation: {}", e);
+                None
+            }
+        };
+        //do not reply with a value the requester would reject
+        let get_opt = get_opt.filter(|value| {
+            self.validators
+                .read()
+                .unwrap()
+                .evaluate_reply(msg.block_type, &msg.key, value)
+                != ReplyEvaluation::Invalid
+        });
+        if let Some(value) = get_opt {
+            //value with the key found in (local) kvdb
+            let reply_bytes = FindValueResponseMessage::new(&msg.key, &[], Some(&value))
+                .to_bytes(header.transaction_id);
+            return send_message(&self.socket, &self.identity, &reply_bytes, sender).await;
+        }
+
+        //value with the key not found in local,
+        //reply with random nodes during the random walk phase,
+        //the closest nodes to the key after
+        let nodes;
+        {
+            let route_table = self.route_table.lock().await;
+            let network_size = route_table.estimate_network_size();
+            if msg.hop_count < utility::random_walk_length(network_size) {
+                nodes = route_table.random_nodes(route_table.k().into());
+            } else {
+                nodes = route_table.find_nodes(&msg.key, route_table.k().into());
+            }
+        }
+        if nodes.len() == 0 {
+            //reply anyway, so the requester does not wait for us
+            event!(Level::DEBUG, "Closest peer not found");
+        }
+        let peers: Vec<PeerInfo> = nodes
+            .iter()
+            .map(|node| node.lock().unwrap().peer_info())
+            .collect();
+        let response_msg = FindValueResponseMessage::new(&msg.key, &peers, None);
+        let response_bytes = response_msg.to_bytes(header.transaction_id);
+        send_message(&self.socket, &self.identity, &response_bytes, sender).await
+    }
+
+    async fn handle_ping_response(
+        &self,
+        header: &MessageHeader,
+        sender: &SocketAddr,
+        public_key: &[u8],
+        bytes: &[u8],
+        msg: PingResponseMessage,
+    ) -> Result<()> {
+        event!(Level::DEBUG, "Received a ping response from {}", sender);
+        //I have not pinged the sender, malicious
+        let rtt = self.complete(header, sender, public_key, bytes)?;
+        let majority = self.address_consensus.lock().unwrap().report(
+            &public_key_to_node_id(public_key),
+            &msg.observed_endpoint,
+            Instant::now(),
+        );
+        let probe;
+        {
+            let mut rt = self.route_table.lock().await;
+            if let Some(endpoint) = majority {
+                update_own_endpoint(&mut rt, &endpoint, self.is_address_announced);
+            }
+            let is_handled = rt.add_node(public_key, sender)?;
+            if let Some(rtt) = rtt {
+                rt.record_rtt(&public_key_to_node_id(public_key), rtt);
+            }
+            probe = if is_handled {
+                None
+            } else {
+                event!(Level::DEBUG, "Space not available for the new node");
+                rt.add_replacement(&PeerInfo::new(sender, public_key))
+            };
+        }
+        if let Some(lrs) = probe {
+            self.spawn_eviction_probe(lrs);
+        }
+        Ok(())
+    }
+
+    async fn handle_find_node_response(
+        &self,
+        header: &MessageHeader,
+        sender: &SocketAddr,
+        public_key: &[u8],
+        bytes: &[u8],
+        msg: FindNodeResponseMessage,
+    ) -> Result<()> {
+        //did I sent request?
+        let rtt = self.complete(header, sender, public_key, bytes)?;
+        event!(
+            Level::DEBUG,
+            "Received find node response from {}. Contains {} nodes)",
+            sender,
+            msg.nodes.len()
+        );
+
+        let mut probes = Vec::new();
+        {
+            let mut route_table = self.route_table.lock().await;
+            if let Some(rtt) = rtt {
+                route_table.record_rtt(&public_key_to_node_id(public_key), rtt);
+            }
+            for n in &msg.nodes {
+                if !is_valid_public_key(&n.public_key) {
+                    continue;
+                }
+                //hearsay, the known nodes are not changed
+                let is_handled = match route_table.add_hearsay(n) {
+                    Ok(is_handled) => is_handled,
+                    //ourselves
+                    Err(_) => continue,
+                };
+                if !is_handled {
+                    if let Some(lrs) = route_table.add_replacement(n) {
+                        probes.push(lrs);
+                    }
+                }
+            }
+        }
+        for lrs in probes {
+            self.spawn_eviction_probe(lrs);
+        }
+        Ok(())
+    }
+
+    async fn handle_store_value_response(
+        &self,
+        header: &MessageHeader,
+        sender: &SocketAddr,
+        public_key: &[u8],
+        bytes: &[u8],
+        msg: StoreValueResponseMessage,
+    ) -> Result<()> {
+        let completion = self
+            .pending_requests
+            .lock()
+            .unwrap()
+            .complete(header, sender, public_key, bytes);
+        match completion {
+            Completion::Answered(_) => Ok(()),
+            //as it is, signed by the node which answered
+            Completion::Relayed(endpoint, transaction_id) => {
+                reply_store(
+                    &self.socket,
+                    &self.identity,
+                    &endpoint,
+                    transaction_id,
+                    &msg,
+                )
+                .await;
+                Ok(())
+            }
+            Completion::Unexpected => Err(anyhow!(
+                "store value response for {}: not asked for",
+                hex::encode(&msg.key)
+            )),
+        }
+    }
+
+    async fn handle_find_value_response(
+        &self,
+        header: &MessageHeader,
+        sender: &SocketAddr,
+        public_key: &[u8],
+        bytes: &[u8],
+    ) -> Result<()> {
+        event!(Level::DEBUG, "Received find value response from {}", sender);
+        //pass the response to the lookup which requested the data,
+        //values which nobody asked for are dropped here
+        if let Some(rtt) = self.complete(header, sender, public_key, bytes)? {
+            self.route_table
+                .lock()
+                .await
+                .record_rtt(&public_key_to_node_id(public_key), rtt);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Incoming, MessageHandler};
+    use crate::constant::DEFAULT_MTU;
+    use crate::dht_manager::address_consensus::AddressConsensus;
+    use crate::dht_manager::pending_request::PendingRequests;
+    use crate::fragment::FragmentSocket;
+    use crate::identity::Identity;
+    use crate::message::{
+        Message, MessageHeader, MessageType, StoreValueRequestMessage, StoreValueResponseMessage,
+    };
+    use crate::record_store::RecordStore;
+    use crate::route_table::RouteTable;
+    use crate::session::SessionSocket;
+    use crate::transport::{DatagramTransport, MemoryNetwork};
+    use crate::validation::{content_hash, BlockValidators, ContentHashValidator};
+    use std::net::SocketAddr;
+    use std::sync::atomic::AtomicU64;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::Mutex;
+    use tokio::time::timeout;
+
+    fn handler(network: &MemoryNetwork, endpoint: &SocketAddr) -> anyhow::Result<MessageHandler> {
+        let identity = Arc::new(Identity::generate()?);
+        let socket = FragmentSocket::new(Arc::new(network.bind(endpoint)?), DEFAULT_MTU)?;
+        let mut path = std::env::temp_dir();
+        path.push(format!("cocoon_handler_{}", std::process::id()));
+        let record_store = RecordStore::open(&path, &identity.node_id(), 1 << 20)?;
+        let mut validators = BlockValidators::new();
+        validators.register(1, Arc::new(ContentHashValidator));
+        Ok(MessageHandler {
+            socket: Arc::new(SessionSocket::new(socket, identity.clone())),
+            route_table: Arc::new(Mutex::new(RouteTable::new(
+                identity.public_key(),
+                endpoint,
+                20,
+                64,
+            ))),
+            record_store: Arc::new(record_store),
+            pending_requests: Arc::new(std::sync::Mutex::new(PendingRequests::new())),
+            identity: identity,
+            validators: Arc::new(std::sync::RwLock::new(validators)),
+            rejected_values: Arc::new(AtomicU64::new(0)),
+            address_consensus: Arc::new(std::sync::Mutex::new(AddressConsensus::new())),
+            is_address_announced: false,
+        })
+    }
+
+    /// A store claiming to be forwarded is answered to the sender, never to someone else.
+    #[tokio::test]
+    async fn store_answered_to_sender() -> anyhow::Result<()> {
+        let network = MemoryNetwork::new(1);
+        let handler_endpoint: SocketAddr = "10.0.0.1:4870".parse()?;
+        let sender_endpoint: SocketAddr = "10.0.0.2:4870".parse()?;
+        let victim_endpoint: SocketAddr = "10.0.0.3:4870".parse()?;
+        let handler = handler(&network, &handler_endpoint)?;
+        let sender_identity = Arc::new(Identity::generate()?);
+        let sender = Arc::new(SessionSocket::new(
+            FragmentSocket::new(Arc::new(network.bind(&sender_endpoint)?), DEFAULT_MTU)?,
+            sender_identity.clone(),
+        ));
+        let victim = network.bind(&victim_endpoint)?;
+
+        //the handshake responses are handled while receiving
+        let cloned_socket = handler.socket.clone();
+        let handler_receiver = tokio::spawn(async move { cloned_socket.recv_from().await });
+        let cloned_sender = sender.clone();
+        let receiver = tokio::spawn(async move {
+            timeout(Duration::from_millis(500), cloned_sender.recv_from()).await
+        });
+        let data = b"a block".to_vec();
+        let key = content_hash(&data);
+        //forged hop count, as if the publisher was someone else
+        let mut msg = StoreValueRequestMessage::new(&key, &data, 1, 2, 60, 1);
+        msg.nonce = 7;
+        let bytes = msg.to_bytes(9);
+        handler
+            .handle(Incoming {
+                header: MessageHeader::new(MessageType::StoreValueRequest, 9),
+                message: Message::StoreValueRequest(msg),
+                bytes: bytes,
+                sender: sender_endpoint,
+                public_key: sender_identity.public_key().to_vec(),
+            })
+            .await;
+
+        let received = receiver.await???;
+        let (header, response) = StoreValueResponseMessage::from_bytes(&received.message)?;
+        assert_eq!(header.transaction_id, 9);
+        assert!(response.accepted);
+        assert!(response.verify(7));
+        assert_eq!(response.replica, handler.identity.public_key());
+
+        handler_receiver.abort();
+        let mut buffer = vec![0; 2048];
+        assert!(
+            timeout(Duration::from_millis(200), victim.recv_from(&mut buffer))
+                .await
+                .is_err()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,268 @@
+use crate::identity::public_key_to_node_id;
+use crate::message::PeerInfo;
+use crate::route_table::node_id_distance;
+
+/// The shortlist keeps at most this many times K candidates, the farthest are dropped.
+const SHORTLIST_CAPACITY_FACTOR: usize = 4;
+
+/// A reply which belongs to an iterative lookup.
+#[derive(Debug)]
+pub enum LookupReply {
+    /// Nodes closer to the key.
+    Nodes(Vec<PeerInfo>),
+    /// The value of the key.
+    Value(Vec<u8>),
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum CandidateState {
+    NotQueried,
+    InFlight,
+    Responded,
+    Failed,
+}
+
+struct Candidate {
+    distance: Vec<u8>,
+    id: Vec<u8>,
+    peer: PeerInfo,
+    state: CandidateState,
+}
+
+/// Shortlist
+/// Candidates of an iterative lookup, ordered by distance to the key.
+pub struct Shortlist {
+    key: Vec<u8>,
+    own_id: Vec<u8>,
+    /// constant 'K'
+    k: usize,
+    candidates: Vec<Candidate>,
+}
+
+impl Shortlist {
+    pub fn new(key: &[u8], own_id: &[u8], k: usize) -> Self {
+        debug_assert!(key.len() != 0);
+        debug_assert!(k != 0);
+        Shortlist {
+            key: key.to_vec(),
+            own_id: own_id.to_vec(),
+            k: k,
+            candidates: Vec::new(),
+        }
+    }
+
+    /// constant 'K'
+    pub fn k(&self) -> usize {
+        self.k
+    }
+
+    /// Insert a node to the shortlist.
+    /// When the shortlist is full, the farthest candidate which is not in flight is dropped.
+    /// Returns false if the node is ourselves, already in the shortlist,
+    /// or farther than all the candidates of a full shortlist.
+    pub fn insert(&mut self, peer: &PeerInfo) -> bool {
+        let id = public_key_to_node_id(&peer.public_key);
+        if id == self.own_id {
+            return false;
+        }
+        if self.candidates.iter().any(|c| c.id == id) {
+            return false;
+        }
+        let distance = node_id_distance(&id, &self.key);
+        let index = self.candidates.partition_point(|c| c.distance <= distance);
+        if self.candidates.len() >= self.k * SHORTLIST_CAPACITY_FACTOR {
+            if index == self.candidates.len() {
+                return false;
+            }
+            match self
+                .candidates
+                .iter()
+                .rposition(|c| c.state != CandidateState::InFlight)
+            {
+                Some(farthest) if farthest >= index => {
+                    self.candidates.remove(farthest);
+                }
+                _ => return false,
+            }
+        }
+        self.candidates.insert(
+            index,
+            Candidate {
+                distance: distance,
+                id: id,
+                peer: peer.clone(),
+                state: CandidateState::NotQueried,
+            },
+        );
+        true
+    }
+
+    /// Select up to n nodes which have not been queried yet among the K closest nodes,
+    /// and mark them as in flight.
+    pub fn next_to_query(&mut self, n: usize) -> Vec<PeerInfo> {
+        let mut selected = Vec::new();
+        for candidate in self
+            .candidates
+            .iter_mut()
+            .filter(|c| c.state != CandidateState::Failed)
+            .take(self.k)
+        {
+            if selected.len() >= n {
+                break;
+            }
+            if candidate.state == CandidateState::NotQueried {
+                candidate.state = CandidateState::InFlight;
+                selected.push(candidate.peer.clone());
+            }
+        }
+        selected
+    }
+
+    /// Mark an in flight node as responded.
+    /// Returns false if we are not waiting for a reply from the node.
+    pub fn mark_responded(&mut self, id: &[u8]) -> bool {
+        self.set_state_if_in_flight(id, CandidateState::Responded)
+    }
+
+    /// Mark an in flight node as failed.
+    pub fn mark_failed(&mut self, id: &[u8]) -> bool {
+        self.set_state_if_in_flight(id, CandidateState::Failed)
+    }
+
+    pub fn in_flight_count(&self) -> usize {
+        self.candidates
+            .iter()
+            .filter(|c| c.state == CandidateState::InFlight)
+            .count()
+    }
+
+    /// Returns true if the K closest (non failed) nodes have responded.
+    pub fn is_finished(&self) -> bool {
+        self.candidates
+            .iter()
+            .filter(|c| c.state != CandidateState::Failed)
+            .take(self.k)
+            .all(|c| c.state == CandidateState::Responded)
+    }
+
+    /// The K closest nodes which have responded.
+    pub fn closest_responded(&self) -> Vec<PeerInfo> {
+        self.candidates
+            .iter()
+            .filter(|c| c.state == CandidateState::Responded)
+            .take(self.k)
+            .map(|c| c.peer.clone())
+            .collect()
+    }
+
+    fn set_state_if_in_flight(&mut self, id: &[u8], state: CandidateState) -> bool {
+        match self
+            .candidates
+            .iter_mut()
+            .find(|c| c.id == id && c.state == CandidateState::InFlight)
+        {
+            Some(candidate) => {
+                candidate.state = state;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Shortlist;
+    use crate::identity::public_key_to_node_id;
+    use crate::message::PeerInfo;
+    use crate::route_table::node_id_distance;
+    use std::net::SocketAddr;
+
+    fn peers(n: u8) -> Vec<PeerInfo> {
+        (0..n)
+            .map(|i| {
+                PeerInfo::new(
+                    &SocketAddr::from(([127, 0, 0, 1], 10000 + i as u16)),
+                    &[i; 32],
+                )
+            })
+            .collect()
+    }
+
+    fn id(peer: &PeerInfo) -> Vec<u8> {
+        public_key_to_node_id(&peer.public_key)
+    }
+
+    #[test]
+    fn shortlist_order() {
+        let own_id = public_key_to_node_id(&[0xff; 32]);
+        let key = vec![0x42; 64];
+        let mut shortlist = Shortlist::new(&key, &own_id, 20);
+        for peer in peers(30) {
+            assert!(shortlist.insert(&peer));
+        }
+        //duplicate and own node are ignored
+        assert!(!shortlist.insert(&peers(1)[0]));
+        let own = PeerInfo::new(&"127.0.0.1:9999".parse().unwrap(), &[0xff; 32]);
+        assert!(!shortlist.insert(&own));
+
+        let queried = shortlist.next_to_query(30);
+        //only K closest are queried
+        assert_eq!(queried.len(), 20);
+        for w in queried.windows(2) {
+            let d0 = node_id_distance(&id(&w[0]), &key);
+            let d1 = node_id_distance(&id(&w[1]), &key);
+            assert!(d0 <= d1);
+        }
+    }
+
+    #[test]
+    fn shortlist_capacity() {
+        let own_id = public_key_to_node_id(&[0xff; 32]);
+        let key = vec![0x42; 64];
+        let mut shortlist = Shortlist::new(&key, &own_id, 2);
+        let mut peers = peers(20);
+        peers.sort_by_key(|peer| node_id_distance(&id(peer), &key));
+        //the farthest first, each closer one takes the place of a farther one
+        for peer in peers.iter().rev() {
+            assert!(shortlist.insert(peer));
+        }
+        assert_eq!(shortlist.candidates.len(), 8);
+        //too far for a full shortlist
+        let farthest = peers.last().unwrap();
+        assert!(!shortlist.insert(farthest));
+
+        let queried = shortlist.next_to_query(2);
+        assert_eq!(queried, peers[..2].to_vec());
+    }
+
+    #[test]
+    fn shortlist_finish() {
+        let own_id = public_key_to_node_id(&[0xff; 32]);
+        let key = vec![0x42; 64];
+        let mut shortlist = Shortlist::new(&key, &own_id, 3);
+        for peer in peers(5) {
+            shortlist.insert(&peer);
+        }
+        assert!(!shortlist.is_finished());
+
+        let first = shortlist.next_to_query(2);
+        assert_eq!(first.len(), 2);
+        assert_eq!(shortlist.in_flight_count(), 2);
+        assert!(shortlist.mark_responded(&id(&first[0])));
+        //not in flight anymore
+        assert!(!shortlist.mark_responded(&id(&first[0])));
+        assert!(shortlist.mark_failed(&id(&first[1])));
+        assert!(!shortlist.is_finished());
+
+        //failed node is replaced by the next closest ones
+        let second = shortlist.next_to_query(3);
+        assert_eq!(second.len(), 2);
+        second.iter().for_each(|peer| {
+            shortlist.mark_responded(&id(peer));
+        });
+        assert!(shortlist.is_finished());
+        assert_eq!(shortlist.closest_responded().len(), 3);
+        assert!(!shortlist.closest_responded().contains(&first[1]));
+    }
+}
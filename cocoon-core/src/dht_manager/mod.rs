@@ -1,410 +1,346 @@
 use crate::cocoon_config;
 use crate::constant;
+use crate::fragment;
+use crate::identity;
 use crate::message;
+use crate::record_store;
 use crate::route_table;
+use crate::session;
+use crate::transport;
 use crate::utility;
+use crate::validation;
+use address_consensus::AddressConsensus;
 use anyhow::{anyhow, Result};
-use cocoon_config::{KVDatabaseConfig, SqliteConfig};
-use constant::MESSAGE_HEADER_SIZE;
+use cocoon_config::{KVDatabaseConfig, MaintenanceConfig, NetworkManagerConfig, SqliteConfig};
+use constant::{
+    KEY_SIZE, LOOKUP_ALPHA, MAX_RETRANSMITS, MESSAGE_HEADER_SIZE, RECORD_SWEEP_INTERVAL,
+    RECORD_TTL, REPLICATION_LEVEL, REPUBLISH_INTERVAL, ROUTE_TABLE_SAVE_INTERVAL,
+    STORE_ACK_TIMEOUT,
+};
+use fragment::FragmentSocket;
+use handler::{Incoming, MessageHandler};
+use identity::{is_valid_public_key, public_key_to_node_id, Identity};
+use lookup::{LookupReply, Shortlist};
 use message::*;
-use rocksdb::{Options, ReadOptions, WriteOptions, DB};
-use route_table::{endpoint_to_node_id, RouteTable};
+use pending_request::{PendingRequests, PendingResponse};
+use record_store::{RecordStore, StorageUsage};
+use rocksdb::{ReadOptions, WriteOptions};
+use route_table::{backoff, RouteTable};
 use rusqlite::{params, Connection};
-use std::collections::HashSet;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::sync::Arc;
-use tokio::net::UdpSocket;
-use tokio::sync::Mutex;
+use session::SessionSocket;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, watch, Mutex};
+use tokio::time::Instant;
 use tracing::{event, span, Level};
-const DHT_DATA_COLUMN_FAMILY: &str = "dht-data-cf";
+use transport::{DatagramTransport, UdpTransport};
+use validation::{BlockValidator, BlockValidators, ValueReplies};
+
+mod address_consensus;
+mod handler;
+mod lookup;
+mod pending_request;
+
+/// Result of DHTManager::bootstrap.
+#[derive(Debug, PartialEq)]
+pub struct BootstrapStatus {
+    /// Number of seed peers which answered the ping.
+    pub responded_seeds: usize,
+    /// Number of nodes in the route table after the self lookup.
+    pub known_nodes: usize,
+}
+
+impl BootstrapStatus {
+    /// Returns true if we know at least one node.
+    pub fn is_success(&self) -> bool {
+        self.known_nodes != 0
+    }
+}
+
+/// Result of DHTManager::maintain.
+#[derive(Debug, PartialEq)]
+pub struct MaintenanceStatus {
+    /// Number of quiet nodes pinged.
+    pub pinged_nodes: usize,
+    /// Number of pinged nodes which did not answer.
+    pub unresponsive_nodes: usize,
+    /// Number of nodes dropped after failing too many requests.
+    pub removed_nodes: usize,
+    /// Number of buckets refreshed with a lookup.
+    pub refreshed_buckets: usize,
+}
 
 /// DHTManager
-/// TODO implement route table save&load (with file)
+/// The route table is loaded from the sqlite database on creation,
+/// and saved periodically and on shutdown.
 pub struct DHTManager {
+    /// Our keypair, the node ID is derived from the public key.
+    identity: Arc<Identity>,
     pub route_table: Arc<Mutex<RouteTable>>,
-    udp_socket: Arc<UdpSocket>,
-    kvdb: Arc<DB>,
-    db: std::sync::Mutex<Connection>,
-    ping_list: Arc<std::sync::Mutex<HashSet<SocketAddr>>>,
+    /// Encrypts the messages with a session key shared with each peer.
+    udp_socket: Arc<SessionSocket>,
+    /// Values of the DHT.
+    record_store: Arc<RecordStore>,
+    db: Arc<std::sync::Mutex<Connection>>,
+    /// Requests waiting for the response, keyed by transaction ID.
+    pending_requests: Arc<std::sync::Mutex<PendingRequests>>,
+    /// Set to true to stop the background tasks.
+    shutdown_sender: watch::Sender<bool>,
+    /// Number of dropped messages which were malformed, the socket counts the badly signed ones.
+    rejected_datagrams: Arc<AtomicU64>,
+    /// Number of messages dropped because the handler workers were behind.
+    dropped_messages: Arc<AtomicU64>,
+    /// Most received messages waiting for the handler workers.
+    receive_queue_size: usize,
+    receive_workers: usize,
+    /// Validators of the block types we store, registered by the higher layer.
+    validators: Arc<std::sync::RwLock<BlockValidators>>,
+    /// Number of received values (stores and replies) which failed the validation.
+    rejected_values: Arc<AtomicU64>,
+    /// Our endpoint as other nodes see it.
+    address_consensus: Arc<std::sync::Mutex<AddressConsensus>>,
+    /// The own endpoint is from the config, and not changed by the consensus.
+    is_address_announced: bool,
+    maintenance_config: MaintenanceConfig,
 }
 
 impl DHTManager {
+    /// Create a DHTManager on UDP sockets bound on the bind addresses of the config.
     pub async fn new(
         kvdb_config: &KVDatabaseConfig,
         sqlite_config: &SqliteConfig,
-        ownep: &SocketAddr,
+        network_config: &NetworkManagerConfig,
+        identity: Identity,
+    ) -> Result<Self> {
+        let transport = UdpTransport::bind(&network_config.bind_addresses)?;
+        Self::with_transport(
+            kvdb_config,
+            sqlite_config,
+            network_config,
+            identity,
+            Arc::new(transport),
+        )
+        .await
+    }
+
+    /// Create a DHTManager on the transport, e.g. a MemoryTransport for simulations.
+    /// The bind addresses of the config are not used.
+    pub async fn with_transport(
+        kvdb_config: &KVDatabaseConfig,
+        sqlite_config: &SqliteConfig,
+        network_config: &NetworkManagerConfig,
+        identity: Identity,
+        transport: Arc<dyn DatagramTransport>,
     ) -> Result<Self> {
-        //kvdb options
-        let mut db_options = Options::default();
-        db_options.create_if_missing(true);
-        db_options.create_missing_column_families(true);
+        if network_config.k == 0 {
+            return Err(anyhow!("k must be at least 1"));
+        }
+        if network_config.node_id_size == 0 || network_config.node_id_size > KEY_SIZE {
+            return Err(anyhow!(
+                "node_id_size {} is not in 1..={}",
+                network_config.node_id_size,
+                KEY_SIZE
+            ));
+        }
+        if network_config.receive_queue_size == 0 || network_config.receive_workers == 0 {
+            return Err(anyhow!(
+                "receive_queue_size and receive_workers must be at least 1"
+            ));
+        }
+
         //open kvdb
-        let kvdb = DB::open_cf(&db_options, &kvdb_config.db_path, [DHT_DATA_COLUMN_FAMILY]).expect(
-            &format!("Failed to open the kvdb: {:?}", &kvdb_config.db_path),
-        );
+        let record_store =
+            RecordStore::open(&kvdb_config.db_path, &identity.node_id(), kvdb_config.quota)?;
+        event!(Level::INFO, "Opened the kvdb: {:?}", record_store.usage());
 
         // open sqlite
         let db = Connection::open(&sqlite_config.db_path)?;
 
-        //udpsocket
-        let sock =
-            UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0)).await?;
-
-        //use bound addr as own ep (for cocoon virtual)
-        let cls = || {
-            if cfg!(feature = "dht-dev") {
-                event!(Level::WARN, "Executing codes for dht-dev");
-                return sock.local_addr().unwrap();
-            } else {
-                return *ownep;
-            }
+        let identity = Arc::new(identity);
+        let udp_socket = SessionSocket::new(
+            FragmentSocket::new(transport, network_config.mtu)?,
+            identity.clone(),
+        );
+        let own_endpoint = match network_config.announced_address {
+            Some(announced_address) => announced_address,
+            None => udp_socket.local_addrs()[0],
         };
+        if own_endpoint.ip().is_unspecified() {
+            event!(
+                Level::WARN,
+                "Own endpoint {} is not a reachable address, set announced_address",
+                own_endpoint
+            );
+        }
+        event!(Level::INFO, "Own endpoint {}", own_endpoint);
+
+        //restore known nodes
+        let mut route_table = RouteTable::new(
+            identity.public_key(),
+            &own_endpoint,
+            network_config.k,
+            network_config.node_id_size,
+        );
+        let loaded = route_table.load(&db)?;
+        event!(Level::INFO, "Loaded {} nodes to the route table", loaded);
+
+        let (shutdown_sender, _) = watch::channel(false);
+        event!(
+            Level::INFO,
+            "Own node id {}",
+            hex::encode(route_table.own_id())
+        );
         Ok(DHTManager {
-            route_table: Arc::new(Mutex::new(RouteTable::new(&cls(), 20, 77))),
-            udp_socket: Arc::new(sock),
-            kvdb: Arc::new(kvdb),
-            db: std::sync::Mutex::new(db),
-            ping_list: Arc::new(std::sync::Mutex::new(HashSet::new())),
+            identity: identity,
+            route_table: Arc::new(Mutex::new(route_table)),
+            udp_socket: Arc::new(udp_socket),
+            record_store: Arc::new(record_store),
+            db: Arc::new(std::sync::Mutex::new(db)),
+            pending_requests: Arc::new(std::sync::Mutex::new(PendingRequests::new())),
+            shutdown_sender: shutdown_sender,
+            rejected_datagrams: Arc::new(AtomicU64::new(0)),
+            dropped_messages: Arc::new(AtomicU64::new(0)),
+            receive_queue_size: network_config.receive_queue_size,
+            receive_workers: network_config.receive_workers,
+            validators: Arc::new(std::sync::RwLock::new(BlockValidators::new())),
+            rejected_values: Arc::new(AtomicU64::new(0)),
+            address_consensus: Arc::new(std::sync::Mutex::new(AddressConsensus::new())),
+            is_address_announced: network_config.announced_address.is_some(),
+            maintenance_config: network_config.maintenance.clone(),
         })
     }
 
-    //TODO: maybe separate each match handlers to functions
     //TODO: test with malformed messages
-    /// Start receiving messages from network
+    /// Start receiving messages from network.
+    /// A receiver task queues the messages for the handler workers,
+    /// the messages which do not fit in the queue are dropped (see dropped_message_count).
     pub async fn start_receive(&self) {
-        let cloned_socket = self.udp_socket.clone();
-        let cloned_route_table = self.route_table.clone();
-        let cloned_kvdb = self.kvdb.clone();
-        let cloned_ping_list = self.ping_list.clone();
-        tokio::spawn(async move {
-            loop {
-                let mut buffer = vec![0; 50000]; //todo define max size
-                event!(Level::DEBUG, "Waiting for incoming message...");
-                let (received_size, sender) = cloned_socket
-                    .recv_from(&mut buffer)
-                    .await
-                    .expect("Failed to receive"); //TODO: maybe separate receive cycle and handle cycle
+        //save the route table periodically
+        self.start_route_table_saver();
+        self.start_record_sweeper();
+        self.start_republisher();
 
-                //resize buffer(truncate)
-                debug_assert!(received_size <= buffer.len());
-                buffer.resize(received_size, 0xff);
+        let (queue_sender, queue_receiver) = mpsc::channel(self.receive_queue_size);
+        self.start_receiver(queue_sender);
 
-                //deserialize message header
-                if buffer.len() < MESSAGE_HEADER_SIZE {
-                    //malformed
-                    //TODO: maybe block sender
-                    continue;
+        let handler = MessageHandler {
+            socket: self.udp_socket.clone(),
+            route_table: self.route_table.clone(),
+            record_store: self.record_store.clone(),
+            pending_requests: self.pending_requests.clone(),
+            identity: self.identity.clone(),
+            validators: self.validators.clone(),
+            rejected_values: self.rejected_values.clone(),
+            address_consensus: self.address_consensus.clone(),
+            is_address_announced: self.is_address_announced,
+        };
+        //the workers stop when the receiver has stopped and the queue is empty
+        let queue_receiver = Arc::new(Mutex::new(queue_receiver));
+        for _ in 0..self.receive_workers {
+            let queue_receiver = queue_receiver.clone();
+            let handler = handler.clone();
+            tokio::spawn(async move {
+                loop {
+                    //the lock is released before handling, the other workers take the next messages
+                    let incoming = queue_receiver.lock().await.recv().await;
+                    match incoming {
+                        Some(incoming) => handler.handle(incoming).await,
+                        None => break,
+                    }
                 }
+            });
+        }
+    }
 
-                let message_header = MessageHeader::from_bytes(&buffer);
-
-                let msg_type: Option<MessageType> =
-                    num::FromPrimitive::from_u32(message_header.message_type);
-
-                if msg_type.is_none() {
-                    todo!("Message type is 'none' TODO should handle this");
-                }
-                match msg_type.unwrap() {
-                    MessageType::PingRequest => {
-                        event!(Level::DEBUG, "Received ping request from {}", &sender);
-                        //TODO: should I add the sender to route table?
-                        // for now add
-
-                        {
-                            let mut rt = cloned_route_table.lock().await;
-                            let is_handled = rt.add_node(&sender).unwrap();
-                            if !is_handled {
-                                event!(Level::DEBUG, "Space not available for the new node");
-                                let bucket = rt.find_bucket(&endpoint_to_node_id(&sender));
-                                for node in &bucket.nodes {
-                                    let ep;
-                                    {
-                                        let node = node.lock().unwrap();
-                                        ep = node.endpoint;
-                                    }
-                                    {
-                                        let mut ping_list = cloned_ping_list.lock().unwrap();
-                                        //insert to ping list
-                                        ping_list.insert(ep);
-                                    }
-                                    if do_ping_impl(&cloned_socket, &ep).await.is_err() {
-                                        event!(Level::ERROR, "Failed to ping");
-                                    }
-                                }
-
-                                //todo
-                                event!(
-                                    Level::WARN,
-                                    "TODO: remove dead nodes from the route table and add new node"
-                                );
-                                return;
-                            }
-                        }
-                        //send ping reply(pong)
-                        pong(&cloned_socket, &sender).await;
-                    }
-                    MessageType::StoreValueRequest => {
-                        let (_, msg) = StoreValueRequestMessage::from_bytes(&buffer);
-                        if msg.data.len() == 0 {
-                            //TODO: reject?
-                            return;
-                        }
-                        assert_eq!(msg.key.len(), 64);
-
-                        //Am I closest to the key?
-                        {
-                            let route_table = cloned_route_table.lock().await;
-                            if route_table.is_closest_to(&msg.key) {
-                                //yes, save data on local
-                                let cfh = cloned_kvdb.cf_handle(DHT_DATA_COLUMN_FAMILY).unwrap();
-                                cloned_kvdb.put_cf(cfh, &msg.key, &msg.data).expect(
-                                    "Failed to save a store request data on kvdb (put failed)",
-                                );
-                                return;
-                            }
-                        }
-
-                        event!(Level::DEBUG, "Foward a store value request message");
-                        let foward_count = utility::calculate_foward_count(
-                            1000,
-                            77, /*dummy hopcount TODO*/
-                            msg.replication_level,
-                        ); //TODO implement network size estimate
-
-                        //find a node which is closest to the key
-                        let nodes_to_foward;
-                        {
-                            let route_table = cloned_route_table.lock().await;
-                            nodes_to_foward = route_table.find_nodes(&msg.key, foward_count.into());
-                        }
-
-                        if nodes_to_foward.len() == 0 {
-                            //could not find
-                            //TODO: handle, but what to do?
-                            //TODO print hop count
-                            event!(Level::ERROR, "Could not find closest peer");
-                            return;
-                        }
-                        //TODO: modify hop count and etc here if needed
-                        for node in &nodes_to_foward {
-                            let ep;
-                            {
-                                let node = node.lock().unwrap();
-                                ep = node.endpoint;
-                            }
-                            let wrriten_size = cloned_socket
-                                .send_to(&msg.to_bytes(), ep)
-                                .await
-                                .expect("Failed to forward a store request");
-                            assert_eq!(wrriten_size, buffer.len());
-                        }
-                    }
-                    MessageType::FindNodeRequest => {
-                        //TODO when to forward the messsage?
-                        let (_, msg) = FindNodeRequestMessage::from_bytes(&buffer);
-                        //   reject malformed messages
-
-                        let nodes;
-                        {
-                            let route_table = cloned_route_table.lock().await;
-                            nodes = route_table.find_nodes(&msg.key, 20); //todo set 'K'
-                            event!(Level::ERROR, "TODO set k");
-                        }
-                        if nodes.len() == 0 {
-                            //TODO: do something
-                            event!(Level::DEBUG, "Closest peer not found");
-                            return;
-                        }
-                        //TODO implement message and return
-                        let mut addrs = Vec::with_capacity(nodes.len());
-                        for node in &nodes {
-                            let node = node.lock().unwrap();
-                            addrs.push(node.endpoint);
-                        }
-                        let msg = FindNodeResponseMessage::new(&addrs);
-                        let wrriten_size = cloned_socket
-                            .send_to(&msg.to_bytes(), &sender)
-                            .await
-                            .expect("Failed to send find node response");
-                        assert_eq!(wrriten_size, buffer.len());
-                    }
-                    MessageType::FindValueRequest => {
-                        event!(Level::DEBUG, "Received find value request");
-
-                        let (header, msg) = FindValueRequestMessage::from_bytes(&buffer);
-                        debug_assert_eq!(header.message_type, MessageType::FindValueRequest as u32);
-                        debug_assert_ne!(msg.key.len(), 0);
-
-                        //check kvdb
-                        let get_opt;
-                        {
-                            let cfh = cloned_kvdb.cf_handle(DHT_DATA_COLUMN_FAMILY).unwrap();
-                            get_opt = cloned_kvdb
-                                .get_cf(cfh, &msg.key)
-                                .expect("Failed to perform kvdb get operation");
-                        }
-                        if get_opt.is_some() {
-                            //value with the key found in (local) kvdb
-                            let value = get_opt.unwrap();
-                            let reply_msg =
-                                FindValueResponseMessage::new(&msg.key, None, Some(&value));
-                            let wrriten_size = cloned_socket
-                                .send_to(&reply_msg.to_bytes(), sender)
-                                .await
-                                .expect("Failed to send a find value response (with value)");
-                            assert_eq!(wrriten_size, reply_msg.to_bytes().len());
-                            return;
-                        }
-
-                        assert!(get_opt.is_none());
-                        //value with the key not found in local,
-                        //reply with a closest node to the key
-
-                        let nodes;
-                        {
-                            let route_table = cloned_route_table.lock().await;
-                            nodes = route_table.find_nodes(&msg.key, 1);
-                        }
-                        //only ask to a closest peer
-                        //plain implementation
-                        //TODO: customize this
-                        if nodes.len() == 0 {
-                            //peer not found
-                            //TODO something
-                            event!(Level::DEBUG, "Closest peer not found");
-                            return;
-                        }
-                        assert!(nodes.len() == 1);
-                        let node = &nodes[0];
-                        let response_msg;
-                        {
-                            let node = node.lock().unwrap();
-                            response_msg =
-                                FindValueResponseMessage::new(&msg.key, Some(&node.endpoint), None);
-                        }
-                        let wrriten_size = cloned_socket
-                            .send_to(&response_msg.to_bytes(), sender)
-                            .await
-                            .expect("Failed to send a find value response (with node)");
-                        assert_eq!(wrriten_size, response_msg.to_bytes().len());
+    /// Receive and decode the messages until shutdown, and queue them for the handler workers.
+    fn start_receiver(&self, queue: mpsc::Sender<Incoming>) {
+        let cloned_socket = self.udp_socket.clone();
+        let cloned_pending_requests = self.pending_requests.clone();
+        let cloned_rejected_datagrams = self.rejected_datagrams.clone();
+        let cloned_dropped_messages = self.dropped_messages.clone();
+        let mut shutdown_receiver = self.shutdown_sender.subscribe();
+        tokio::spawn(async move {
+            loop {
+                event!(Level::DEBUG, "Waiting for incoming message...");
+                //fragmented messages are reassembled, and encrypted ones decrypted, by the socket
+                let received = tokio::select! {
+                    result = cloned_socket.recv_from() => result,
+                    _ = shutdown_receiver.changed() => {
+                        event!(Level::DEBUG, "Stop receiving");
+                        break;
                     }
-                    MessageType::PingResponse => {
-                        event!(Level::DEBUG, "Received a ping response from {}", &sender);
-
-                        {
-                            {
-                                let mut ping_list = cloned_ping_list.lock().unwrap();
-                                if !ping_list.contains(&sender) {
-                                    //I have not pinged the sender, malicious
-                                    //TODO: block the sender(not permanently)
-                                    event!(
-                                        Level::DEBUG,
-                                        "Sender ({}) is not in the ping list",
-                                        sender
-                                    );
-
-                                    return;
-                                }
-                                //remove the sender from ping list
-                                assert!(ping_list.contains(&sender));
-                                if !ping_list.remove(&sender) {
-                                    panic!("Logic error");
-                                }
-                            }
-                            event!(Level::DEBUG, "removed the sender from ping list");
-
-                            let mut rt = cloned_route_table.lock().await;
-
-                            let is_handled = rt.add_node(&sender).unwrap();
-
-                            if !is_handled {
-                                event!(Level::DEBUG, "Space not available for the new node");
-                                let bucket = rt.find_bucket(&endpoint_to_node_id(&sender));
-                                for node in &bucket.nodes {
-                                    let ep;
-                                    {
-                                        let node = node.lock().unwrap();
-                                        ep = node.endpoint;
-                                    }
-                                    {
-                                        let mut ping_list = cloned_ping_list.lock().unwrap();
-                                        //insert to ping list
-                                        ping_list.insert(ep);
-                                    }
-                                    do_ping_impl(&cloned_socket, &ep).await;
-                                }
-
-                                //todo
-                                event!(
-                                    Level::WARN,
-                                    "TODO: remove dead nodes from the route table and add new node"
-                                );
-                                return;
-                            }
-                        }
-                        event!(Level::DEBUG, "add node");
+                };
+                //the signature is verified and the trailer stripped by the socket,
+                //so only authenticated peers can change the route table and kvdb
+                let received = match received {
+                    Ok(received) => received,
+                    Err(e) => {
+                        //e.g. ICMP port unreachable of a datagram we sent
+                        event!(Level::DEBUG, "Failed to receive: {}", e);
+                        continue;
                     }
-                    MessageType::FindNodeResponse => {
-                        //TODO: did I sent request?
-                        //deserialize message
-                        let (_, msg) = FindNodeResponseMessage::from_bytes(&buffer);
+                };
+                debug_assert!(received.message.len() >= MESSAGE_HEADER_SIZE);
 
+                //one malformed datagram must not stop the loop
+                let (header, message) = match decode_message(&received.message) {
+                    Ok(decoded) => decoded,
+                    Err(e) => {
+                        cloned_rejected_datagrams.fetch_add(1, Ordering::Relaxed);
                         event!(
                             Level::DEBUG,
-                            "Received find node response from {}. Contains {} nodes)",
-                            &sender,
-                            msg.nodes.len()
+                            "Dropped a malformed message from {}: {}",
+                            received.sender,
+                            e
                         );
-
-                        for n in &msg.nodes {
-                            {
-                                let mut route_table = cloned_route_table.lock().await;
-                                let is_handled = route_table.add_node(n).unwrap();
-                                //todo handle branch if route table(bucket) is full
-                            }
-                        }
+                        continue;
                     }
-                    MessageType::FindValueResponse => {
-                        //TODO check if I actually requested the data
+                };
+
+                //forget requests which have not been answered in time
+                {
+                    let mut pending_requests = cloned_pending_requests.lock().unwrap();
+                    let expired = pending_requests.expire();
+                    if expired != 0 {
                         event!(
                             Level::DEBUG,
-                            "Received find value response from {}",
-                            &sender
+                            "{} pending requests expired, {} still pending",
+                            expired,
+                            pending_requests.len()
                         );
-                        let (_, msg) = FindValueResponseMessage::from_bytes(&buffer);
-                        if msg.data.is_some() && msg.node.is_some() {
-                            //malformed
-                            //TODO maybe block the sender
-                            return;
-                        }
-                        if msg.data.is_some() {
-                            //save data
-                            let cfh = cloned_kvdb.cf_handle(DHT_DATA_COLUMN_FAMILY).unwrap();
-                            cloned_kvdb
-                                .put_cf(cfh, &msg.key, &msg.data.unwrap())
-                                .unwrap();
-                            return;
-                        }
-                        if msg.node.is_some() {
-                            //TODO
-                            //maybe disable this feature for privacy reasons
-                            event!(Level::ERROR, "TODO");
-                            return;
-                        }
-                    }
-                    _ => {
-                        unreachable!();
                     }
+                }
+
+                let incoming = Incoming {
+                    header: header,
+                    message: message,
+                    bytes: received.message,
+                    sender: received.sender,
+                    public_key: received.public_key,
                 };
+                //never wait for the workers, the datagrams would pile up unseen in the socket
+                match queue.try_send(incoming) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(incoming)) => {
+                        cloned_dropped_messages.fetch_add(1, Ordering::Relaxed);
+                        event!(
+                            Level::DEBUG,
+                            "Dropped a message from {}, the handlers are busy",
+                            incoming.sender
+                        );
+                    }
+                    Err(TrySendError::Closed(_)) => break,
+                }
             }
         });
     }
 
     /// Check whether there is value with the given key on kvdb or not.
     pub fn is_available_on_local(&self, key: &[u8]) -> anyhow::Result<bool> {
-        let cfh = self.kvdb.cf_handle(DHT_DATA_COLUMN_FAMILY).unwrap();
-
-        let opt = self.kvdb.get_cf(&cfh, &key)?;
+        let opt = self.get_value_local(key)?;
         if opt.is_some() {
             Ok(true)
         } else {
@@ -412,59 +348,160 @@ impl DHTManager {
         }
     }
 
+    /// Retransmission timeout of the requests to the endpoint.
+    async fn request_timeout(&self, endpoint: &SocketAddr) -> Duration {
+        self.route_table.lock().await.request_timeout(endpoint)
+    }
+
     /// Initiate a ping request.
-    pub async fn do_ping(&self, endpoint: &SocketAddr) -> Result<()> {
-        {
-            //insert to ping list
-            let mut ping_list = match self.ping_list.lock() {
-                Ok(pl) => pl,
-                Err(e) => {
-                    return Err(anyhow!(e.to_string()));
-                }
-            };
-            ping_list.insert(*endpoint);
-            event!(Level::DEBUG, "Inserted {} to the ping list", endpoint);
-        }
-        do_ping_impl(&self.udp_socket, endpoint).await?;
-        Ok(())
+    /// Returns the responder's endpoint and public key,
+    /// Err if the node does not respond in time.
+    pub async fn do_ping(&self, endpoint: &SocketAddr) -> Result<PeerInfo> {
+        let response = do_ping_impl(
+            &self.udp_socket,
+            &self.pending_requests,
+            &self.identity,
+            endpoint,
+            self.request_timeout(endpoint).await,
+        )
+        .await?;
+        let response = response.wait().await?;
+        Ok(PeerInfo::new(endpoint, &response.public_key))
     }
 
-    // TODO: maybe return Result<bool>
-    // TODO: maybe only accept (key, data) such that key == hash(data)
     /// Store a value(data) at the given key on network.
-    /// This function will not store the given data locally.
-    pub async fn do_store(&self, key: &[u8], data: &[u8]) -> Result<()> {
-        let request_msg = StoreValueRequestMessage::new(key, data, 10); //todo implement replication level
-        let tmp = 10; //TODO implement
-        let nodes_to_foward;
+    /// The value expires on other nodes after RECORD_TTL, unless it is kept with publish_local.
+    /// Returns the nodes which confirmed they stored the value, waiting for them until
+    /// STORE_ACK_TIMEOUT (or the replication level is reached). Empty if nobody did.
+    /// Only the acks of the closest nodes to the key (found with a lookup) count, once each.
+    /// The acks come back along the hops of the request, signed by the replicas.
+    /// Returns Err if the value does not pass the validation of the block type
+    /// or is larger than MAX_VALUE_SIZE, other nodes would reject it.
+    /// Values larger than the MTU are sent in fragments.
+    pub async fn do_store(
+        &self,
+        key: &[u8],
+        data: &[u8],
+        block_type: u32,
+    ) -> Result<Vec<PeerInfo>> {
+        self.validators
+            .read()
+            .unwrap()
+            .validate_store(block_type, key, data)?;
+        //only the nodes closest to the key store it, the acks of the others do not count,
+        //e.g. a forwarder signing acks with keys of its own
+        let closest = self.do_find_node(key).await?;
+        let mut responses = self
+            .pending_requests
+            .lock()
+            .unwrap()
+            .register_collection(MessageType::StoreValueResponse, STORE_ACK_TIMEOUT);
+        let transaction_id = responses.transaction_id;
+        let nonce = utility::new_nonce();
+        if let Err(e) = publish(
+            &self.udp_socket,
+            &self.identity,
+            &self.route_table,
+            key,
+            data,
+            block_type,
+            transaction_id,
+            nonce,
+        )
+        .await
         {
-            let route_table = self.route_table.lock().await;
-            nodes_to_foward = route_table.find_nodes(key, tmp);
-        }
-        if nodes_to_foward.len() == 0 {
-            //TODO: do something
-            return Err(anyhow!("Could not find peers to foward"));
+            self.pending_requests.lock().unwrap().remove(transaction_id);
+            return Err(e);
         }
-        for node in &nodes_to_foward {
-            let ep;
+
+        let mut replicas: Vec<PeerInfo> = Vec::new();
+        while replicas.len() < REPLICATION_LEVEL as usize {
+            let response = match responses.next().await {
+                Some(response) => response,
+                None => break,
+            };
+            let msg = match StoreValueResponseMessage::from_bytes(&response.bytes) {
+                Ok((_, msg)) => msg,
+                Err(_) => continue,
+            };
+            //made up or replayed by a forwarder
+            if msg.key != key || !msg.verify(nonce) {
+                continue;
+            }
+            if !msg.accepted {
+                event!(
+                    Level::DEBUG,
+                    "{} refused to store {}: {}",
+                    response.endpoint,
+                    hex::encode(key),
+                    msg.reason
+                );
+                continue;
+            }
+            //the request may be routed back to us, we are not a replica of our own value
+            if msg.replica == self.identity.public_key()
+                || replicas
+                    .iter()
+                    .any(|replica| replica.public_key == msg.replica)
             {
-                let node = node.lock().unwrap();
-                ep = node.endpoint;
+                continue;
             }
-            self.udp_socket
-                .send_to(&request_msg.to_bytes(), ep)
-                .await
-                .expect("Failed to send a store request");
+            let replica = match closest.iter().find(|peer| peer.public_key == msg.replica) {
+                Some(replica) => replica,
+                None => {
+                    event!(
+                        Level::DEBUG,
+                        "Ignore an ack for {} from {}, not one of the closest nodes",
+                        hex::encode(key),
+                        hex::encode(&msg.replica)
+                    );
+                    continue;
+                }
+            };
+            replicas.push(replica.clone());
         }
+        self.pending_requests.lock().unwrap().remove(transaction_id);
+        event!(
+            Level::DEBUG,
+            "{} replicas confirmed {}",
+            replicas.len(),
+            hex::encode(key)
+        );
+        Ok(replicas)
+    }
+
+    /// Keep a value on the local kvdb as a local record.
+    /// Local records do not expire, are not evicted when the kvdb is full,
+    /// and are republished before they expire on other nodes until unpublished.
+    /// Returns Err if the value does not pass the validation of the block type.
+    pub fn publish_local(&self, key: &[u8], data: &[u8], block_type: u32) -> Result<()> {
+        self.validators
+            .read()
+            .unwrap()
+            .validate_store(block_type, key, data)?;
+        self.record_store.put(
+            key,
+            data,
+            RECORD_TTL,
+            true,
+            block_type,
+            utility::unix_time_now(),
+        )?;
         Ok(())
     }
 
-    /// Initiate a find value request.
-    pub async fn do_find_value(&self, key: &[u8]) -> Result<()> {
-        let request_msg = FindValueRequestMessage::new(key);
+    /// Stop republishing the value kept with publish_local, and remove it from the local kvdb.
+    /// Copies on other nodes expire.
+    pub fn unpublish(&self, key: &[u8]) -> Result<()> {
+        self.record_store.remove(key)
+    }
+
+    /// Find a value with the given key.
+    /// Checks the local kvdb first, then performs a get on the network.
+    /// Returns Ok(None) if the value is not found.
+    pub async fn do_find_value(&self, key: &[u8], block_type: u32) -> Result<Option<Vec<u8>>> {
         //check local first
-        let cfh = self.kvdb.cf_handle(DHT_DATA_COLUMN_FAMILY).unwrap();
-        let opt = self.kvdb.get_cf(cfh, key)?;
+        let opt = self.get_value_local(key)?;
 
         if opt.is_some() {
             //found on local
@@ -473,98 +510,1021 @@ impl DHTManager {
                 "value for the key {} is found on the local kvdb",
                 hex::encode(key)
             );
-            return Ok(());
+            return Ok(opt);
         }
-        //not found, ask to peers
+        Ok(self
+            .do_find_values(key, block_type)
+            .await?
+            .into_iter()
+            .next())
+    }
+
+    /// Find the values with the given key on the network.
+    /// Stops at the first value if only one value exists for the key (e.g. content addressed
+    /// values), collects the values of the whole lookup otherwise.
+    /// The values are checked by the validator of the block type, and duplicates are dropped.
+    /// Found values are also stored on the local kvdb.
+    pub async fn do_find_values(&self, key: &[u8], block_type: u32) -> Result<Vec<Vec<u8>>> {
+        let validator = match self.validators.read().unwrap().get(block_type) {
+            Some(validator) => validator,
+            None => return Err(anyhow!("No validator for the block type {}", block_type)),
+        };
+        let mut replies = ValueReplies::new(key, validator);
         event!(
             Level::DEBUG,
-            "value for the key {} is not found on the local kvdb",
+            "Find values for the key {} on the network",
             hex::encode(key)
         );
-        let tmp = 10; //TODO implement
-        let nodes_to_foward;
+        let replication_level = REPLICATION_LEVEL;
+        let walk_length;
+        let walks;
         {
             let route_table = self.route_table.lock().await;
-            nodes_to_foward = route_table.find_nodes(key, tmp);
+            let network_size = route_table.estimate_network_size();
+            walk_length = utility::random_walk_length(network_size);
+            walks = utility::calculate_foward_count(network_size, 0, replication_level);
         }
-        if nodes_to_foward.len() == 0 {
-            //TODO: do something
-            return Err(anyhow!("Could not find peers to foward"));
+        let seeds = self
+            .random_walk(
+                key,
+                block_type,
+                replication_level,
+                walk_length,
+                walks,
+                &mut replies,
+            )
+            .await;
+
+        if !replies.is_finished() {
+            //greedy phase, starting from the nodes we reached
+            let request_msg =
+                FindValueRequestMessage::new(key, replication_level, walk_length + 1, block_type);
+            self.iterative_lookup(
+                key,
+                &seeds,
+                MessageType::FindValueResponse,
+                |transaction_id| request_msg.to_bytes(transaction_id),
+                Some(&mut replies),
+            )
+            .await?;
         }
-        for node in &nodes_to_foward {
-            let node = node.lock().unwrap();
-            debug_assert_ne!(request_msg.key.len(), 0);
-            let _ = self
-                .udp_socket
-                .send_to(&request_msg.to_bytes(), &node.endpoint)
-                .await?;
+        let values = replies.into_values();
+        for value in &values {
+            self.store_on_local(key, value, block_type)?;
         }
-        Ok(())
+        Ok(values)
     }
 
-    /// Initiate a find node request.
-    pub async fn do_find_node(&self, key: &[u8]) -> Result<()> {
+    /// Find the K closest nodes to the given key with an iterative lookup.
+    pub async fn do_find_node(&self, key: &[u8]) -> Result<Vec<PeerInfo>> {
         let request_msg = FindNodeRequestMessage::new(key);
-        let peers;
+        self.iterative_lookup(
+            key,
+            &[],
+            MessageType::FindNodeResponse,
+            |transaction_id| request_msg.to_bytes(transaction_id),
+            None,
+        )
+        .await
+    }
+
+    /// Random walk phase of a get (R5N).
+    /// Follows 'walks' random walks of walk_length hops, and returns the closest nodes
+    /// known by the last node of each walk.
+    /// A walk ends early at a node which has a value, the values are added to replies.
+    async fn random_walk(
+        &self,
+        key: &[u8],
+        block_type: u32,
+        replication_level: u32,
+        walk_length: u32,
+        walks: u16,
+        replies: &mut ValueReplies,
+    ) -> Vec<PeerInfo> {
+        let mut seeds = Vec::new();
+        if walk_length == 0 {
+            return seeds;
+        }
+        let starts: Vec<PeerInfo>;
         {
             let route_table = self.route_table.lock().await;
-            peers = route_table.find_nodes(key, 100); //TODO change 100 to reasonable value
+            starts = route_table
+                .random_nodes(walks.into())
+                .iter()
+                .map(|node| node.lock().unwrap().peer_info())
+                .collect();
         }
-        if peers.len() == 0 {
-            event!(
-                Level::DEBUG,
-                "Could not find peers to send find node request"
-            );
+
+        for start in starts {
+            let mut peer = start;
+            let mut hop_count = 1;
+            loop {
+                let request_msg =
+                    FindValueRequestMessage::new(key, replication_level, hop_count, block_type);
+                let result = match send_request_impl(
+                    &self.udp_socket,
+                    &self.pending_requests,
+                    &self.identity,
+                    &peer.endpoint,
+                    self.request_timeout(&peer.endpoint).await,
+                    MessageType::FindValueResponse,
+                    |transaction_id| request_msg.to_bytes(transaction_id),
+                )
+                .await
+                {
+                    Ok(response) => response.wait().await,
+                    Err(e) => Err(e),
+                };
+                let response_bytes = match result {
+                    Ok(response) if response.public_key == peer.public_key => response.bytes,
+                    Ok(_) => {
+                        event!(
+                            Level::DEBUG,
+                            "Random walk response from {} is signed by another key",
+                            peer.endpoint
+                        );
+                        break;
+                    }
+                    Err(e) => {
+                        event!(
+                            Level::DEBUG,
+                            "Random walk stopped at {} (hop {}): {}",
+                            peer.endpoint,
+                            hop_count,
+                            e
+                        );
+                        let id = public_key_to_node_id(&peer.public_key);
+                        self.route_table.lock().await.record_failure(&id);
+                        break;
+                    }
+                };
+                let msg = match FindValueResponseMessage::from_bytes(&response_bytes) {
+                    Ok((_, msg)) => msg,
+                    Err(e) => {
+                        event!(
+                            Level::DEBUG,
+                            "Random walk stopped at {} (hop {}): {}",
+                            peer.endpoint,
+                            hop_count,
+                            e
+                        );
+                        break;
+                    }
+                };
+                if let Some(data) = msg.data {
+                    if !replies.add(&data) {
+                        self.reject_reply(&peer).await;
+                    } else if replies.is_finished() {
+                        return seeds;
+                    }
+                    break;
+                }
+                if hop_count >= walk_length {
+                    //end of the walk, the node replied with the closest nodes it knows
+                    seeds.extend(msg.nodes);
+                    break;
+                }
+                let candidates: Vec<PeerInfo> = msg
+                    .nodes
+                    .into_iter()
+                    .filter(|node| {
+                        node.public_key != self.identity.public_key()
+                            && self.udp_socket.can_reach(&node.endpoint)
+                    })
+                    .collect();
+                if candidates.len() == 0 {
+                    break;
+                }
+                peer = candidates[utility::random_index(candidates.len())].clone();
+                hop_count += 1;
+            }
         }
+        seeds
+    }
 
-        for peer in &peers {
-            let peer = peer.lock().unwrap();
-            let _ = self
-                .udp_socket
-                .send_to(&request_msg.to_bytes(), &peer.endpoint)
-                .await?;
+    /// Iterative lookup.
+    /// Sends the request to 'alpha' nodes in parallel, merges the returned nodes into
+    /// a shortlist ordered by distance to the key, and stops once the K closest nodes
+    /// have responded or no more values are needed.
+    /// The shortlist starts with our closest nodes and the seeds.
+    /// Returned values are added to replies, which must be given for a get.
+    /// Returns the K closest responded nodes.
+    async fn iterative_lookup<F>(
+        &self,
+        key: &[u8],
+        seeds: &[PeerInfo],
+        response_type: MessageType,
+        request_to_bytes: F,
+        mut replies: Option<&mut ValueReplies>,
+    ) -> Result<Vec<PeerInfo>>
+    where
+        F: Fn(u32) -> Vec<u8>,
+    {
+        let mut shortlist;
+        {
+            let mut route_table = self.route_table.lock().await;
+            route_table.mark_lookup(key, Instant::now());
+            //the shortlist has full length IDs
+            shortlist = Shortlist::new(key, &self.identity.node_id(), route_table.k().into());
+            for node in route_table.find_nodes(key, route_table.k().into()) {
+                let node = node.lock().unwrap();
+                shortlist.insert(&node.peer_info());
+            }
         }
-        Ok(())
+        for peer in seeds.iter().filter(|peer| {
+            is_valid_public_key(&peer.public_key) && self.udp_socket.can_reach(&peer.endpoint)
+        }) {
+            shortlist.insert(peer);
+        }
+
+        //responses (or timeouts) of the in flight requests
+        let (tx, mut rx) = mpsc::unbounded_channel();
+
+        loop {
+            if shortlist.is_finished() {
+                break;
+            }
+            let in_flight = shortlist.in_flight_count();
+            for peer in shortlist.next_to_query(LOOKUP_ALPHA.saturating_sub(in_flight)) {
+                let response = match send_request_impl(
+                    &self.udp_socket,
+                    &self.pending_requests,
+                    &self.identity,
+                    &peer.endpoint,
+                    self.request_timeout(&peer.endpoint).await,
+                    response_type,
+                    &request_to_bytes,
+                )
+                .await
+                {
+                    Ok(response) => response,
+                    Err(e) => {
+                        event!(
+                            Level::DEBUG,
+                            "Failed to send a lookup request to {}: {}",
+                            peer.endpoint,
+                            e
+                        );
+                        shortlist.mark_failed(&public_key_to_node_id(&peer.public_key));
+                        continue;
+                    }
+                };
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    let _ = tx.send((peer, response.wait().await));
+                });
+            }
+            if shortlist.in_flight_count() == 0 {
+                //no one left to ask
+                break;
+            }
+
+            let (sender, result) = rx.recv().await.unwrap(); //we hold a sender
+            let sender_id = public_key_to_node_id(&sender.public_key);
+            let response_bytes = match result {
+                Ok(response) if response.public_key == sender.public_key => response.bytes,
+                Ok(_) => {
+                    //someone else answered from the endpoint
+                    event!(
+                        Level::DEBUG,
+                        "Lookup response from {} is signed by another key",
+                        sender.endpoint
+                    );
+                    shortlist.mark_failed(&sender_id);
+                    continue;
+                }
+                Err(e) => {
+                    event!(
+                        Level::DEBUG,
+                        "Lookup request to {} failed: {}",
+                        sender.endpoint,
+                        e
+                    );
+                    shortlist.mark_failed(&sender_id);
+                    self.route_table.lock().await.record_failure(&sender_id);
+                    continue;
+                }
+            };
+            let reply = match decode_message(&response_bytes) {
+                Ok((_, Message::FindNodeResponse(msg))) => LookupReply::Nodes(msg.nodes),
+                Ok((_, Message::FindValueResponse(msg))) => match msg.data {
+                    Some(data) => LookupReply::Value(data),
+                    None => LookupReply::Nodes(msg.nodes),
+                },
+                Ok((header, _)) => {
+                    event!(
+                        Level::DEBUG,
+                        "Unexpected response type {} from {}",
+                        header.message_type,
+                        sender.endpoint
+                    );
+                    shortlist.mark_failed(&sender_id);
+                    continue;
+                }
+                Err(e) => {
+                    event!(
+                        Level::DEBUG,
+                        "Malformed lookup response from {}: {}",
+                        sender.endpoint,
+                        e
+                    );
+                    shortlist.mark_failed(&sender_id);
+                    continue;
+                }
+            };
+            match reply {
+                LookupReply::Nodes(nodes) => {
+                    shortlist.mark_responded(&sender_id);
+                    //no more than K nodes count from a response,
+                    //nor the nodes of the address families we have no socket for
+                    for node in nodes
+                        .iter()
+                        .take(shortlist.k())
+                        .filter(|node| self.udp_socket.can_reach(&node.endpoint))
+                    {
+                        shortlist.insert(node);
+                    }
+                }
+                LookupReply::Value(data) => {
+                    //values only answer get requests
+                    let replies = replies.as_mut().unwrap();
+                    if !replies.add(&data) {
+                        shortlist.mark_failed(&sender_id);
+                        self.reject_reply(&sender).await;
+                        continue;
+                    }
+                    shortlist.mark_responded(&sender_id);
+                    if replies.is_finished() {
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(shortlist.closest_responded())
+    }
+
+    /// Drop a node which returned a value failing the validation.
+    async fn reject_reply(&self, peer: &PeerInfo) {
+        event!(
+            Level::DEBUG,
+            "Rejected a value returned by {}",
+            peer.endpoint
+        );
+        self.rejected_values.fetch_add(1, Ordering::Relaxed);
+        self.route_table
+            .lock()
+            .await
+            .remove_node(&public_key_to_node_id(&peer.public_key));
+        self.udp_socket.close_session(&peer.endpoint);
+    }
+
+    /// Join the network.
+    /// Ping the seed peers, then look up our own node ID to fill the buckets.
+    /// Nodes loaded from the database are used as well, so this works without seeds
+    /// if we have been online before.
+    pub async fn bootstrap(&self, seeds: &[SocketAddr]) -> Result<BootstrapStatus> {
+        let own_endpoint = self.route_table.lock().await.own_endpoint();
+
+        //ping all seeds at once, then wait for them
+        let mut pings = Vec::new();
+        let local_addrs = self.udp_socket.local_addrs();
+        for seed in seeds
+            .iter()
+            .filter(|&seed| *seed != own_endpoint && !local_addrs.contains(seed))
+        {
+            match do_ping_impl(
+                &self.udp_socket,
+                &self.pending_requests,
+                &self.identity,
+                seed,
+                self.request_timeout(seed).await,
+            )
+            .await
+            {
+                Ok(response) => pings.push((*seed, response)),
+                Err(e) => event!(Level::WARN, "Failed to ping the seed {}: {}", seed, e),
+            }
+        }
+        let mut responded_seeds = 0;
+        for (seed, response) in pings {
+            match response.wait().await {
+                Ok(response) => {
+                    responded_seeds += 1;
+                    //the response handler adds it too, but may not have done it yet
+                    let mut route_table = self.route_table.lock().await;
+                    if let Err(e) = route_table.add_node(&response.public_key, &seed) {
+                        event!(Level::WARN, "Ignore the seed {}: {}", seed, e);
+                    }
+                }
+                Err(e) => event!(Level::WARN, "Seed {} did not respond: {}", seed, e),
+            }
+        }
+        event!(
+            Level::INFO,
+            "{}/{} seeds responded",
+            responded_seeds,
+            seeds.len()
+        );
+
+        if self.route_table.lock().await.len() == 0 {
+            event!(Level::WARN, "No nodes to bootstrap from");
+            return Ok(BootstrapStatus {
+                responded_seeds: responded_seeds,
+                known_nodes: 0,
+            });
+        }
+
+        //self lookup
+        let closest = self.do_find_node(&self.identity.node_id()).await?;
+        event!(
+            Level::DEBUG,
+            "Self lookup found {} closest nodes",
+            closest.len()
+        );
+
+        let known_nodes = self.route_table.lock().await.len();
+        event!(Level::INFO, "Bootstrapped, {} nodes known", known_nodes);
+        Ok(BootstrapStatus {
+            responded_seeds: responded_seeds,
+            known_nodes: known_nodes,
+        })
+    }
+
+    /// Current estimate of the number of nodes in the network (including ourselves).
+    pub async fn network_size_estimate(&self) -> usize {
+        self.route_table.lock().await.estimate_network_size()
+    }
+
+    /// Save the route table to the sqlite database.
+    pub async fn save_route_table(&self) -> Result<()> {
+        save_route_table_impl(&self.route_table, &self.db).await
+    }
+
+    /// Stop the background tasks and save the route table.
+    pub async fn shutdown(&self) -> Result<()> {
+        event!(Level::INFO, "Shutting down the dht manager");
+        //send_replace does not fail without receivers
+        self.shutdown_sender.send_replace(true);
+        self.save_route_table().await
+    }
+
+    /// Start the background maintenance of the route table, if enabled in the config.
+    /// The task stops on shutdown, or when the manager is dropped.
+    pub fn start_maintenance(self: &Arc<Self>) {
+        if !self.maintenance_config.enabled {
+            event!(Level::INFO, "Route table maintenance is disabled");
+            return;
+        }
+        let weak_self: Weak<Self> = Arc::downgrade(self);
+        let period = self.maintenance_config.interval();
+        let mut shutdown_receiver = self.shutdown_sender.subscribe();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            //the first tick completes immediately
+            interval.tick().await;
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = shutdown_receiver.changed() => break,
+                }
+                let dht_manager = match weak_self.upgrade() {
+                    Some(dht_manager) => dht_manager,
+                    None => break,
+                };
+                //lookups take a while, do not wait for them on shutdown
+                tokio::select! {
+                    status = dht_manager.maintain() => {
+                        event!(Level::DEBUG, "Maintained the route table: {:?}", status);
+                    }
+                    _ = shutdown_receiver.changed() => break,
+                }
+            }
+        });
+    }
+
+    /// One round of the route table maintenance.
+    /// Ping the nodes which have gone quiet, drop the nodes which failed too many requests,
+    /// and look up a random ID in the range of each bucket without a recent lookup.
+    pub async fn maintain(&self) -> MaintenanceStatus {
+        let config = &self.maintenance_config;
+        let quiet_nodes = self
+            .route_table
+            .lock()
+            .await
+            .quiet_nodes(config.quiet_node_timeout());
+
+        //send all pings first, so they time out together
+        let mut pings = Vec::with_capacity(quiet_nodes.len());
+        for peer in &quiet_nodes {
+            let response = do_ping_impl(
+                &self.udp_socket,
+                &self.pending_requests,
+                &self.identity,
+                &peer.endpoint,
+                self.request_timeout(&peer.endpoint).await,
+            )
+            .await;
+            pings.push((peer, response));
+        }
+        let mut unresponsive_nodes = 0;
+        for (peer, response) in pings {
+            //the response updates the node in the receive loop
+            let is_alive = match response {
+                Ok(response) => match response.wait().await {
+                    Ok(response) => response.public_key == peer.public_key,
+                    Err(_) => false,
+                },
+                Err(_) => false,
+            };
+            if !is_alive {
+                unresponsive_nodes += 1;
+                self.route_table
+                    .lock()
+                    .await
+                    .record_failure(&public_key_to_node_id(&peer.public_key));
+            }
+        }
+
+        let removed = self
+            .route_table
+            .lock()
+            .await
+            .remove_failed_nodes(config.max_failures);
+        for peer in &removed {
+            self.udp_socket.close_session(&peer.endpoint);
+        }
+
+        let refresh_keys: Vec<Vec<u8>>;
+        {
+            let route_table = self.route_table.lock().await;
+            refresh_keys = route_table
+                .buckets_to_refresh(Instant::now(), config.bucket_refresh_interval())
+                .iter()
+                .map(|index| route_table.random_id_in_bucket(*index))
+                .collect();
+        }
+        for key in &refresh_keys {
+            if let Err(e) = self.do_find_node(key).await {
+                event!(Level::DEBUG, "Failed to refresh a bucket: {}", e);
+            }
+        }
+
+        MaintenanceStatus {
+            pinged_nodes: quiet_nodes.len(),
+            unresponsive_nodes: unresponsive_nodes,
+            removed_nodes: removed.len(),
+            refreshed_buckets: refresh_keys.len(),
+        }
+    }
+
+    fn start_route_table_saver(&self) {
+        let cloned_route_table = self.route_table.clone();
+        let cloned_db = self.db.clone();
+        let mut shutdown_receiver = self.shutdown_sender.subscribe();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(ROUTE_TABLE_SAVE_INTERVAL);
+            //the first tick completes immediately
+            interval.tick().await;
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = shutdown_receiver.changed() => break,
+                }
+                if let Err(e) = save_route_table_impl(&cloned_route_table, &cloned_db).await {
+                    event!(Level::ERROR, "Failed to save the route table: {}", e);
+                }
+            }
+        });
+    }
+
+    fn start_record_sweeper(&self) {
+        let cloned_record_store = self.record_store.clone();
+        let mut shutdown_receiver = self.shutdown_sender.subscribe();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(RECORD_SWEEP_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = shutdown_receiver.changed() => break,
+                }
+                match cloned_record_store.remove_expired(utility::unix_time_now()) {
+                    Ok(removed) => {
+                        event!(Level::DEBUG, "Removed {} expired values", removed)
+                    }
+                    Err(e) => event!(Level::ERROR, "Failed to remove expired values: {}", e),
+                }
+            }
+        });
+    }
+
+    fn start_republisher(&self) {
+        let cloned_socket = self.udp_socket.clone();
+        let cloned_identity = self.identity.clone();
+        let cloned_route_table = self.route_table.clone();
+        let cloned_record_store = self.record_store.clone();
+        let mut shutdown_receiver = self.shutdown_sender.subscribe();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(REPUBLISH_INTERVAL);
+            //the first tick completes immediately
+            interval.tick().await;
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = shutdown_receiver.changed() => break,
+                }
+                let records = match cloned_record_store.local_records() {
+                    Ok(records) => records,
+                    Err(e) => {
+                        event!(Level::ERROR, "Failed to read the local records: {}", e);
+                        continue;
+                    }
+                };
+                event!(Level::DEBUG, "Republish {} local records", records.len());
+                for (key, data, block_type) in &records {
+                    if let Err(e) = publish(
+                        &cloned_socket,
+                        &cloned_identity,
+                        &cloned_route_table,
+                        key,
+                        data,
+                        *block_type,
+                        //nobody waits for the responses
+                        utility::new_transaction_id(),
+                        utility::new_nonce(),
+                    )
+                    .await
+                    {
+                        event!(
+                            Level::WARN,
+                            "Failed to republish {}: {}",
+                            hex::encode(key),
+                            e
+                        );
+                    }
+                }
+            }
+        });
     }
 
     /// Store a value to kvdb.
-    pub fn store_on_local(&self, key: &[u8], data: &[u8]) -> Result<()> {
-        let cfh = self.kvdb.cf_handle(DHT_DATA_COLUMN_FAMILY).unwrap();
-        self.kvdb.put_cf(cfh, key, data)?;
+    /// The value expires like the values stored by other nodes,
+    /// and is not stored if the kvdb is full.
+    /// Returns Err if the value does not pass the validation of the block type.
+    pub fn store_on_local(&self, key: &[u8], data: &[u8], block_type: u32) -> Result<()> {
+        self.validators
+            .read()
+            .unwrap()
+            .validate_store(block_type, key, data)?;
+        self.record_store.put(
+            key,
+            data,
+            RECORD_TTL,
+            false,
+            block_type,
+            utility::unix_time_now(),
+        )?;
         Ok(())
     }
 
+    /// Current usage and quota of the kvdb.
+    pub fn storage_usage(&self) -> StorageUsage {
+        self.record_store.usage()
+    }
+
     /// Get value with the given key from kvdb
-    /// Returns Ok(None) if not found
+    /// Returns Ok(None) if not found or expired
     pub fn get_value_local(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
-        let cfh = self.kvdb.cf_handle(DHT_DATA_COLUMN_FAMILY).unwrap();
-        self.kvdb
-            .get_cf(cfh, key)
-            .map_err(|e| anyhow::Error::from(e))
+        self.record_store.get(key, utility::unix_time_now())
+    }
+
+    pub fn node_id(&self) -> Vec<u8> {
+        self.identity.node_id()
+    }
+
+    pub fn public_key(&self) -> &[u8] {
+        self.identity.public_key()
+    }
+
+    /// Number of received datagrams which were dropped because they were unsigned,
+    /// badly signed, failed the decryption or were malformed.
+    pub fn rejected_datagram_count(&self) -> u64 {
+        self.rejected_datagrams.load(Ordering::Relaxed) + self.udp_socket.rejected_count()
+    }
+
+    /// Number of received messages which were dropped because the handler workers
+    /// could not keep up.
+    pub fn dropped_message_count(&self) -> u64 {
+        self.dropped_messages.load(Ordering::Relaxed)
+    }
+
+    /// Number of encrypted sessions with other nodes.
+    pub fn session_count(&self) -> usize {
+        self.udp_socket.session_count()
+    }
+
+    /// Number of received values (stores and replies) which failed the validation.
+    pub fn rejected_value_count(&self) -> u64 {
+        self.rejected_values.load(Ordering::Relaxed)
+    }
+
+    /// Register the validator of the block type.
+    /// Values of a block type without validator are neither stored nor returned.
+    pub fn register_validator(&self, block_type: u32, validator: Arc<dyn BlockValidator>) {
+        self.validators
+            .write()
+            .unwrap()
+            .register(block_type, validator);
+    }
+
+    /// Endpoint other nodes reach us at, as far as we know.
+    pub async fn own_endpoint(&self) -> SocketAddr {
+        self.route_table.lock().await.own_endpoint()
     }
 
     /* dht-dev features */
     /// Convenience function for cocoon-virtual.
     #[cfg(feature = "dht-dev")]
     pub fn local_endpoint(&self) -> Result<SocketAddr> {
-        self.udp_socket
-            .local_addr()
-            .map_err(|e| anyhow::Error::from(e))
+        Ok(self.udp_socket.local_addrs()[0])
+    }
+}
+
+async fn save_route_table_impl(
+    route_table: &Mutex<RouteTable>,
+    db: &std::sync::Mutex<Connection>,
+) -> Result<()> {
+    let route_table = route_table.lock().await;
+    let mut db = db.lock().unwrap();
+    route_table.save(&mut db)?;
+    Ok(())
+}
+
+/// Ping the least recently seen node of a full bucket.
+/// If the ping times out, the node is evicted and replaced with the most recently seen
+/// node in the bucket's replacement cache.
+fn spawn_eviction_probe(
+    route_table: &Arc<Mutex<RouteTable>>,
+    udp_socket: &Arc<SessionSocket>,
+    pending_requests: &Arc<std::sync::Mutex<PendingRequests>>,
+    identity: &Arc<Identity>,
+    probed: PeerInfo,
+) {
+    let route_table = route_table.clone();
+    let udp_socket = udp_socket.clone();
+    let pending_requests = pending_requests.clone();
+    let identity = identity.clone();
+    tokio::spawn(async move {
+        let rto = route_table.lock().await.request_timeout(&probed.endpoint);
+        let response = do_ping_impl(
+            &udp_socket,
+            &pending_requests,
+            &identity,
+            &probed.endpoint,
+            rto,
+        )
+        .await;
+        let is_alive = match response {
+            //someone else may be using the endpoint now
+            Ok(response) => match response.wait().await {
+                Ok(response) => response.public_key == probed.public_key,
+                Err(_) => false,
+            },
+            Err(_) => false,
+        };
+        let probed_id = public_key_to_node_id(&probed.public_key);
+        let inserted = route_table.lock().await.finish_probe(&probed_id, is_alive);
+        if !is_alive {
+            //evicted, the sessions are kept only with the nodes we know
+            udp_socket.close_session(&probed.endpoint);
+        }
+        if let Some(peer) = inserted {
+            event!(
+                Level::DEBUG,
+                "Replaced {} with {}",
+                probed.endpoint,
+                peer.endpoint
+            );
+        }
+    });
+}
+
+/// Sign and send a message, encrypted with the session of the endpoint.
+async fn send_message(
+    udp_socket: &SessionSocket,
+    identity: &Identity,
+    message: &[u8],
+    endpoint: &SocketAddr,
+) -> Result<()> {
+    let datagram = sign_message(identity, message);
+    udp_socket.send_to(&datagram, endpoint).await
+}
+
+/// Send store requests for the value, as the hop 0.
+/// The answers of the nodes which store or refuse the value come back hop by hop
+/// with the transaction ID, signed with the nonce.
+async fn publish(
+    udp_socket: &SessionSocket,
+    identity: &Identity,
+    route_table: &Mutex<RouteTable>,
+    key: &[u8],
+    data: &[u8],
+    block_type: u32,
+    transaction_id: u32,
+    nonce: u64,
+) -> Result<()> {
+    let mut request_msg = StoreValueRequestMessage::new(
+        key,
+        data,
+        REPLICATION_LEVEL,
+        1,
+        RECORD_TTL.as_secs(),
+        block_type,
+    );
+    request_msg.nonce = nonce;
+    let nodes_to_foward;
+    {
+        let route_table = route_table.lock().await;
+        nodes_to_foward = select_next_hops(&route_table, key, 0, REPLICATION_LEVEL);
+    }
+    if nodes_to_foward.len() == 0 {
+        //TODO: do something
+        return Err(anyhow!("Could not find peers to foward"));
+    }
+    let request_bytes = request_msg.to_bytes(transaction_id);
+    for peer in &nodes_to_foward {
+        send_message(udp_socket, identity, &request_bytes, &peer.endpoint).await?;
+    }
+    Ok(())
+}
+
+/// R5N routing.
+/// Select the nodes to route a request to: random nodes during the random walk phase,
+/// the closest nodes to the key after. Empty if the request has taken too many hops.
+fn select_next_hops(
+    route_table: &RouteTable,
+    key: &[u8],
+    hop_count: u32,
+    replication_level: u32,
+) -> Vec<PeerInfo> {
+    let network_size = route_table.estimate_network_size();
+    let foward_count: usize =
+        utility::calculate_foward_count(network_size, hop_count, replication_level).into();
+    let nodes = if hop_count < utility::random_walk_length(network_size) {
+        route_table.random_nodes(foward_count)
+    } else {
+        route_table.find_nodes(key, foward_count)
+    };
+    nodes
+        .iter()
+        .map(|node| node.lock().unwrap().peer_info())
+        .collect()
+}
+
+/// Send a request and register it to the pending request table.
+/// The request is sent again with exponential backoff from the retransmission timeout
+/// (see RouteTable::request_timeout) until it is answered, MAX_RETRANSMITS times at most.
+/// Returns the future of the response, which fails after the last timeout.
+async fn send_request_impl<F>(
+    udp_socket: &Arc<SessionSocket>,
+    pending_requests: &Arc<std::sync::Mutex<PendingRequests>>,
+    identity: &Identity,
+    endpoint: &SocketAddr,
+    rto: Duration,
+    response_type: MessageType,
+    request_to_bytes: F,
+) -> Result<PendingResponse>
+where
+    F: FnOnce(u32) -> Vec<u8>,
+{
+    let timeout = (0..=MAX_RETRANSMITS)
+        .map(|attempt| backoff(rto, attempt))
+        .sum();
+    let response = pending_requests
+        .lock()
+        .unwrap()
+        .register(endpoint, response_type, timeout);
+    let transaction_id = response.transaction_id;
+    let datagram = sign_message(identity, &request_to_bytes(transaction_id));
+    if let Err(e) = udp_socket.send_to(&datagram, endpoint).await {
+        pending_requests.lock().unwrap().remove(transaction_id);
+        return Err(e);
     }
+
+    let udp_socket = udp_socket.clone();
+    let pending_requests = pending_requests.clone();
+    let endpoint = *endpoint;
+    tokio::spawn(async move {
+        for attempt in 0..MAX_RETRANSMITS {
+            tokio::time::sleep(backoff(rto, attempt)).await;
+            if !pending_requests.lock().unwrap().retransmit(transaction_id) {
+                return;
+            }
+            event!(
+                Level::DEBUG,
+                "Retransmit the request {} to {}",
+                transaction_id,
+                endpoint
+            );
+            if let Err(e) = udp_socket.send_to(&datagram, &endpoint).await {
+                event!(Level::DEBUG, "Failed to retransmit to {}: {}", endpoint, e);
+                return;
+            }
+        }
+    });
+    Ok(response)
 }
 
-async fn do_ping_impl(udp_socket: &Arc<UdpSocket>, endpoint: &SocketAddr) -> Result<()> {
+async fn do_ping_impl(
+    udp_socket: &Arc<SessionSocket>,
+    pending_requests: &Arc<std::sync::Mutex<PendingRequests>>,
+    identity: &Identity,
+    endpoint: &SocketAddr,
+    rto: Duration,
+) -> Result<PendingResponse> {
     let msg = PingRequestMessage::new();
-    udp_socket.send_to(&msg.to_bytes(), endpoint).await?;
+    let response = send_request_impl(
+        udp_socket,
+        pending_requests,
+        identity,
+        endpoint,
+        rto,
+        MessageType::PingResponse,
+        |transaction_id| msg.to_bytes(transaction_id),
+    )
+    .await?;
 
     event!(Level::DEBUG, "Sent a ping message to {}", &endpoint);
-    Ok(())
+    Ok(response)
+}
+
+/// Take the endpoint the majority of the nodes see us at as our own.
+/// Our endpoint is only replaced by one of the same address family,
+/// unless it is unspecified (bound on "0.0.0.0" or "[::]").
+fn update_own_endpoint(route_table: &mut RouteTable, endpoint: &SocketAddr, is_announced: bool) {
+    let own_endpoint = route_table.own_endpoint();
+    if own_endpoint == *endpoint {
+        return;
+    }
+    if is_announced {
+        event!(
+            Level::WARN,
+            "Other nodes see us at {}, not at the announced endpoint {}",
+            endpoint,
+            own_endpoint
+        );
+        return;
+    }
+    if own_endpoint.is_ipv4() != endpoint.is_ipv4() && !own_endpoint.ip().is_unspecified() {
+        return;
+    }
+    event!(
+        Level::INFO,
+        "Own endpoint changed from {} to {}",
+        own_endpoint,
+        endpoint
+    );
+    route_table.set_own_endpoint(endpoint);
+}
+
+/// Answer a store request, to the publisher of the value.
+async fn reply_store(
+    udp_socket: &SessionSocket,
+    identity: &Identity,
+    endpoint: &SocketAddr,
+    transaction_id: u32,
+    response: &StoreValueResponseMessage,
+) {
+    if let Err(e) = send_message(
+        udp_socket,
+        identity,
+        &response.to_bytes(transaction_id),
+        endpoint,
+    )
+    .await
+    {
+        event!(
+            Level::DEBUG,
+            "Failed to send a store value response to {}: {}",
+            endpoint,
+            e
+        );
+    }
 }
 
-//send ping reply
-async fn pong(udp_socket: &UdpSocket, endpoint: &SocketAddr) -> Result<()> {
-    let msg = PingResponseMessage::new();
-    udp_socket.send_to(&msg.to_bytes(), endpoint).await?;
+async fn pong(
+    udp_socket: &SessionSocket,
+    identity: &Identity,
+    endpoint: &SocketAddr,
+    transaction_id: u32,
+) -> Result<()> {
+    let msg = PingResponseMessage::new(endpoint);
+    send_message(
+        udp_socket,
+        identity,
+        &msg.to_bytes(transaction_id),
+        endpoint,
+    )
+    .await?;
     event!(Level::DEBUG, "Sent pong message to {}", &endpoint);
     Ok(())
 }
@@ -0,0 +1,456 @@
+use crate::message::{MessageHeader, MessageType};
+use crate::utility;
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+
+/// Most responses buffered for a collection, the rest are dropped.
+const COLLECTION_BUFFER_SIZE: usize = 64;
+
+struct PendingRequest {
+    endpoint: SocketAddr,
+    response_type: MessageType,
+    sent_at: Instant,
+    /// The response may be of any copy, so it does not tell the round trip time.
+    is_retransmitted: bool,
+    deadline: Instant,
+    sender: oneshot::Sender<Response>,
+}
+
+/// Result of PendingRequests::complete.
+#[derive(Debug, PartialEq)]
+pub enum Completion {
+    /// We have never sent the matching request
+    /// (unknown transaction ID, other sender or unexpected message type).
+    Unexpected,
+    /// Passed to the waiter, with the round trip time of the request
+    /// unless it was retransmitted (Karn's algorithm) or answered by any node.
+    Answered(Option<Duration>),
+    /// Answers a request we forwarded, to be passed back to the endpoint
+    /// it came from with the transaction ID it came with.
+    Relayed(SocketAddr, u32),
+}
+
+/// A request answered by any number of nodes, e.g. the replicas of a store request.
+struct PendingCollection {
+    response_type: MessageType,
+    deadline: Instant,
+    sender: mpsc::Sender<Response>,
+}
+
+/// A request we forwarded, answered by any number of nodes.
+struct PendingRelay {
+    response_type: MessageType,
+    deadline: Instant,
+    endpoint: SocketAddr,
+    transaction_id: u32,
+}
+
+/// An authenticated response.
+pub struct Response {
+    pub endpoint: SocketAddr,
+    /// Public key which signed the response.
+    pub public_key: Vec<u8>,
+    /// Message header and body.
+    pub bytes: Vec<u8>,
+}
+
+/// Future side of a pending request.
+/// Completes with the whole response message (header included).
+pub struct PendingResponse {
+    pub transaction_id: u32,
+    receiver: oneshot::Receiver<Response>,
+    deadline: Instant,
+}
+
+impl PendingResponse {
+    /// Wait for the response.
+    /// Returns Err if the request timed out or expired from the table.
+    pub async fn wait(self) -> Result<Response> {
+        match tokio::time::timeout_at(self.deadline, self.receiver).await {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(_)) => Err(anyhow!(
+                "Request {} expired without response",
+                self.transaction_id
+            )),
+            Err(_) => Err(anyhow!("Request {} timed out", self.transaction_id)),
+        }
+    }
+}
+
+/// Future side of a pending collection.
+pub struct PendingResponses {
+    pub transaction_id: u32,
+    receiver: mpsc::Receiver<Response>,
+    deadline: Instant,
+}
+
+impl PendingResponses {
+    /// Wait for the next response.
+    /// Returns None once the deadline has passed.
+    pub async fn next(&mut self) -> Option<Response> {
+        tokio::time::timeout_at(self.deadline, self.receiver.recv())
+            .await
+            .unwrap_or_default()
+    }
+}
+
+/// PendingRequests
+/// Outgoing requests waiting for a response, keyed by transaction ID.
+pub struct PendingRequests {
+    requests: HashMap<u32, PendingRequest>,
+    collections: HashMap<u32, PendingCollection>,
+    relays: HashMap<u32, PendingRelay>,
+}
+
+impl PendingRequests {
+    pub fn new() -> Self {
+        PendingRequests {
+            requests: HashMap::new(),
+            collections: HashMap::new(),
+            relays: HashMap::new(),
+        }
+    }
+
+    fn new_transaction_id(&self) -> u32 {
+        loop {
+            let id = utility::new_transaction_id();
+            if !self.requests.contains_key(&id)
+                && !self.collections.contains_key(&id)
+                && !self.relays.contains_key(&id)
+            {
+                break id;
+            }
+        }
+    }
+
+    /// Register an outgoing request to the endpoint.
+    /// The timeout covers the retransmissions.
+    /// Returns a future which completes when the matching response arrives.
+    pub fn register(
+        &mut self,
+        endpoint: &SocketAddr,
+        response_type: MessageType,
+        timeout: Duration,
+    ) -> PendingResponse {
+        let transaction_id = self.new_transaction_id();
+        let (sender, receiver) = oneshot::channel();
+        let now = Instant::now();
+        let deadline = now + timeout;
+        self.requests.insert(
+            transaction_id,
+            PendingRequest {
+                endpoint: *endpoint,
+                response_type: response_type,
+                sent_at: now,
+                is_retransmitted: false,
+                deadline: deadline,
+                sender: sender,
+            },
+        );
+        PendingResponse {
+            transaction_id: transaction_id,
+            receiver: receiver,
+            deadline: deadline,
+        }
+    }
+
+    /// Register a request which any node may answer, any number of times until the timeout.
+    pub fn register_collection(
+        &mut self,
+        response_type: MessageType,
+        timeout: Duration,
+    ) -> PendingResponses {
+        let transaction_id = self.new_transaction_id();
+        let (sender, receiver) = mpsc::channel(COLLECTION_BUFFER_SIZE);
+        let deadline = Instant::now() + timeout;
+        self.collections.insert(
+            transaction_id,
+            PendingCollection {
+                response_type: response_type,
+                deadline: deadline,
+                sender: sender,
+            },
+        );
+        PendingResponses {
+            transaction_id: transaction_id,
+            receiver: receiver,
+            deadline: deadline,
+        }
+    }
+
+    /// Register a request received from the endpoint which we forward to other nodes.
+    /// Returns the transaction ID of the forwarded request, its responses
+    /// are passed back until the timeout.
+    pub fn register_relay(
+        &mut self,
+        endpoint: &SocketAddr,
+        transaction_id: u32,
+        response_type: MessageType,
+        timeout: Duration,
+    ) -> u32 {
+        let relay_transaction_id = self.new_transaction_id();
+        self.relays.insert(
+            relay_transaction_id,
+            PendingRelay {
+                response_type: response_type,
+                deadline: Instant::now() + timeout,
+                endpoint: *endpoint,
+                transaction_id: transaction_id,
+            },
+        );
+        relay_transaction_id
+    }
+
+    /// Mark the request as sent again.
+    /// Returns false if it is not pending anymore, answered or expired.
+    pub fn retransmit(&mut self, transaction_id: u32) -> bool {
+        match self.requests.get_mut(&transaction_id) {
+            Some(request) => {
+                request.is_retransmitted = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Complete the pending request which the response answers.
+    pub fn complete(
+        &mut self,
+        header: &MessageHeader,
+        sender: &SocketAddr,
+        sender_public_key: &[u8],
+        response_bytes: &[u8],
+    ) -> Completion {
+        if let Some(collection) = self.collections.get(&header.transaction_id) {
+            if collection.response_type as u32 != header.message_type {
+                return Completion::Unexpected;
+            }
+            //dropped if the waiter is behind or has given up
+            let _ = collection.sender.try_send(Response {
+                endpoint: *sender,
+                public_key: sender_public_key.to_vec(),
+                bytes: response_bytes.to_vec(),
+            });
+            return Completion::Answered(None);
+        }
+        if let Some(relay) = self.relays.get(&header.transaction_id) {
+            if relay.response_type as u32 != header.message_type {
+                return Completion::Unexpected;
+            }
+            return Completion::Relayed(relay.endpoint, relay.transaction_id);
+        }
+        match self.requests.get(&header.transaction_id) {
+            Some(request) => {
+                if request.endpoint != *sender
+                    || request.response_type as u32 != header.message_type
+                {
+                    return Completion::Unexpected;
+                }
+            }
+            None => {
+                return Completion::Unexpected;
+            }
+        }
+        let request = self.requests.remove(&header.transaction_id).unwrap();
+        let rtt = if request.is_retransmitted {
+            None
+        } else {
+            Some(request.sent_at.elapsed())
+        };
+        //the waiter may have given up already
+        let _ = request.sender.send(Response {
+            endpoint: *sender,
+            public_key: sender_public_key.to_vec(),
+            bytes: response_bytes.to_vec(),
+        });
+        Completion::Answered(rtt)
+    }
+
+    /// Remove the request, collection or relay.
+    pub fn remove(&mut self, transaction_id: u32) {
+        self.requests.remove(&transaction_id);
+        self.collections.remove(&transaction_id);
+        self.relays.remove(&transaction_id);
+    }
+
+    /// Remove all requests, collections and relays past their deadline.
+    /// Returns the number of removed requests.
+    pub fn expire(&mut self) -> usize {
+        let now = Instant::now();
+        let before = self.len();
+        self.requests.retain(|_, r| r.deadline > now);
+        self.collections.retain(|_, c| c.deadline > now);
+        self.relays.retain(|_, r| r.deadline > now);
+        before - self.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.requests.len() + self.collections.len() + self.relays.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Completion, PendingRequests};
+    use crate::message::{MessageHeader, MessageType};
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn complete_matching_response() {
+        let mut pending = PendingRequests::new();
+        let ep: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        let other: SocketAddr = "127.0.0.1:4001".parse().unwrap();
+        let response = pending.register(&ep, MessageType::PingResponse, Duration::from_secs(5));
+        let tid = response.transaction_id;
+
+        //unknown transaction id
+        let header = MessageHeader::new(MessageType::PingResponse, tid.wrapping_add(1));
+        assert_eq!(
+            pending.complete(&header, &ep, &[9], &[1]),
+            Completion::Unexpected
+        );
+        //other sender
+        let header = MessageHeader::new(MessageType::PingResponse, tid);
+        assert_eq!(
+            pending.complete(&header, &other, &[9], &[1]),
+            Completion::Unexpected
+        );
+        //unexpected type
+        let header = MessageHeader::new(MessageType::FindNodeResponse, tid);
+        assert_eq!(
+            pending.complete(&header, &ep, &[9], &[1]),
+            Completion::Unexpected
+        );
+        assert_eq!(pending.len(), 1);
+
+        let header = MessageHeader::new(MessageType::PingResponse, tid);
+        assert!(matches!(
+            pending.complete(&header, &ep, &[9], &[1, 2, 3]),
+            Completion::Answered(Some(_))
+        ));
+        assert_eq!(pending.len(), 0);
+        let response = response.wait().await.unwrap();
+        assert_eq!(response.public_key, vec![9]);
+        assert_eq!(response.bytes, vec![1, 2, 3]);
+
+        //answered only once
+        assert_eq!(
+            pending.complete(&header, &ep, &[9], &[1, 2, 3]),
+            Completion::Unexpected
+        );
+    }
+
+    #[tokio::test]
+    async fn expire() {
+        let mut pending = PendingRequests::new();
+        let ep: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        let response = pending.register(&ep, MessageType::PingResponse, Duration::from_millis(10));
+        let _ = pending.register(&ep, MessageType::PingResponse, Duration::from_secs(60));
+        assert_eq!(pending.len(), 2);
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(pending.expire(), 1);
+        assert_eq!(pending.len(), 1);
+        assert!(response.wait().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn retransmitted_request() {
+        let mut pending = PendingRequests::new();
+        let ep: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        let response = pending.register(&ep, MessageType::PingResponse, Duration::from_secs(5));
+        let tid = response.transaction_id;
+        assert!(pending.retransmit(tid));
+
+        //no round trip time, the response may be of the first copy
+        let header = MessageHeader::new(MessageType::PingResponse, tid);
+        assert_eq!(
+            pending.complete(&header, &ep, &[9], &[1]),
+            Completion::Answered(None)
+        );
+        assert!(response.wait().await.is_ok());
+        //answered, nothing to send again
+        assert!(!pending.retransmit(tid));
+    }
+
+    #[tokio::test]
+    async fn collect_responses() {
+        let mut pending = PendingRequests::new();
+        let ep: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        let other: SocketAddr = "127.0.0.1:4001".parse().unwrap();
+        let mut responses =
+            pending.register_collection(MessageType::StoreValueResponse, Duration::from_millis(50));
+        let tid = responses.transaction_id;
+
+        //any sender, any number of times
+        let header = MessageHeader::new(MessageType::StoreValueResponse, tid);
+        assert_eq!(
+            pending.complete(&header, &ep, &[1], &[1]),
+            Completion::Answered(None)
+        );
+        assert_eq!(
+            pending.complete(&header, &other, &[2], &[2]),
+            Completion::Answered(None)
+        );
+        //unexpected type
+        let header = MessageHeader::new(MessageType::PingResponse, tid);
+        assert_eq!(
+            pending.complete(&header, &ep, &[1], &[1]),
+            Completion::Unexpected
+        );
+        assert_eq!(pending.len(), 1);
+
+        let response = responses.next().await.unwrap();
+        assert_eq!((response.endpoint, response.public_key), (ep, vec![1]));
+        let response = responses.next().await.unwrap();
+        assert_eq!((response.endpoint, response.public_key), (other, vec![2]));
+        //nothing more until the deadline
+        assert!(responses.next().await.is_none());
+        assert_eq!(pending.expire(), 1);
+        assert_eq!(pending.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn relay_responses() {
+        let mut pending = PendingRequests::new();
+        let upstream: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        let ep: SocketAddr = "127.0.0.1:4001".parse().unwrap();
+        let other: SocketAddr = "127.0.0.1:4002".parse().unwrap();
+        let tid = pending.register_relay(
+            &upstream,
+            7,
+            MessageType::StoreValueResponse,
+            Duration::from_millis(10),
+        );
+
+        //any sender, any number of times, back to the upstream
+        let header = MessageHeader::new(MessageType::StoreValueResponse, tid);
+        assert_eq!(
+            pending.complete(&header, &ep, &[1], &[1]),
+            Completion::Relayed(upstream, 7)
+        );
+        assert_eq!(
+            pending.complete(&header, &other, &[2], &[2]),
+            Completion::Relayed(upstream, 7)
+        );
+        //unexpected type
+        let header = MessageHeader::new(MessageType::PingResponse, tid);
+        assert_eq!(
+            pending.complete(&header, &ep, &[1], &[1]),
+            Completion::Unexpected
+        );
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(pending.expire(), 1);
+        let header = MessageHeader::new(MessageType::StoreValueResponse, tid);
+        assert_eq!(
+            pending.complete(&header, &ep, &[1], &[1]),
+            Completion::Unexpected
+        );
+    }
+}
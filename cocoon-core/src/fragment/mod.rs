@@ -0,0 +1,351 @@
+use crate::constant::{
+    MAX_MESSAGE_SIZE, MAX_REASSEMBLY_BYTES, MIN_MTU, REASSEMBLY_TIMEOUT, UDP_RECEIVE_BUFFER_SIZE,
+};
+use crate::message::MessageType;
+use crate::transport::DatagramTransport;
+use crate::utility;
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::time::Instant;
+use tracing::{event, Level};
+
+/// Size of the fragment header.
+/// message type (Fragment), message ID, fragment index and fragment count.
+pub const FRAGMENT_HEADER_SIZE: usize = 12;
+
+/// Split a datagram into fragments which fit in the MTU.
+/// A datagram which fits is returned as it is.
+pub fn split(datagram: &[u8], mtu: usize, message_id: u32) -> Vec<Vec<u8>> {
+    debug_assert!(mtu > FRAGMENT_HEADER_SIZE);
+    if datagram.len() <= mtu {
+        return vec![datagram.to_vec()];
+    }
+    let chunks: Vec<&[u8]> = datagram.chunks(mtu - FRAGMENT_HEADER_SIZE).collect();
+    let count = chunks.len() as u16;
+    chunks
+        .iter()
+        .enumerate()
+        .map(|(index, chunk)| {
+            let mut fragment = Vec::with_capacity(FRAGMENT_HEADER_SIZE + chunk.len());
+            fragment.extend_from_slice(&(MessageType::Fragment as u32).to_le_bytes());
+            fragment.extend_from_slice(&message_id.to_le_bytes());
+            fragment.extend_from_slice(&(index as u16).to_le_bytes());
+            fragment.extend_from_slice(&count.to_le_bytes());
+            fragment.extend_from_slice(chunk);
+            fragment
+        })
+        .collect()
+}
+
+/// Returns true if the datagram is a fragment made by split.
+pub fn is_fragment(datagram: &[u8]) -> bool {
+    datagram.len() >= 4 && datagram[0..4] == (MessageType::Fragment as u32).to_le_bytes()
+}
+
+struct PartialMessage {
+    fragments: Vec<Option<Vec<u8>>>,
+    received: usize,
+    bytes: usize,
+    deadline: Instant,
+}
+
+/// Reassembler
+/// Collects the fragments of the messages being received, keyed by sender and message ID.
+/// Fragments are not signed, the reassembled datagram is verified as usual.
+/// Messages which are not complete in time are dropped, and the oldest ones are
+/// dropped when too many bytes are buffered.
+pub struct Reassembler {
+    messages: HashMap<(SocketAddr, u32), PartialMessage>,
+    bytes: usize,
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Reassembler {
+            messages: HashMap::new(),
+            bytes: 0,
+        }
+    }
+
+    /// Add a fragment received from the sender.
+    /// Returns the datagram once all fragments have been received.
+    pub fn add(
+        &mut self,
+        sender: &SocketAddr,
+        fragment: &[u8],
+        now: Instant,
+    ) -> Result<Option<Vec<u8>>> {
+        self.expire(now);
+        if fragment.len() <= FRAGMENT_HEADER_SIZE || !is_fragment(fragment) {
+            return Err(anyhow!("Malformed fragment"));
+        }
+        let message_id = u32::from_le_bytes(fragment[4..8].try_into()?);
+        let index = u16::from_le_bytes(fragment[8..10].try_into()?) as usize;
+        let count = u16::from_le_bytes(fragment[10..12].try_into()?) as usize;
+        let payload = &fragment[FRAGMENT_HEADER_SIZE..];
+        if count < 2 || index >= count {
+            return Err(anyhow!("Fragment {} of {} is out of range", index, count));
+        }
+        if count > max_fragment_count() {
+            return Err(anyhow!("Too many fragments ({})", count));
+        }
+
+        let message = self
+            .messages
+            .entry((*sender, message_id))
+            .or_insert_with(|| PartialMessage {
+                fragments: vec![None; count],
+                received: 0,
+                bytes: 0,
+                deadline: now + REASSEMBLY_TIMEOUT,
+            });
+        if message.fragments.len() != count {
+            return Err(anyhow!("Fragment count of {} changed", message_id));
+        }
+        if message.fragments[index].is_some() {
+            //duplicate
+            return Ok(None);
+        }
+        if message.bytes + payload.len() > MAX_MESSAGE_SIZE {
+            let message = self.messages.remove(&(*sender, message_id)).unwrap();
+            self.bytes -= message.bytes;
+            return Err(anyhow!("Message {} is too large", message_id));
+        }
+        message.fragments[index] = Some(payload.to_vec());
+        message.received += 1;
+        message.bytes += payload.len();
+        self.bytes += payload.len();
+
+        if message.received == count {
+            let message = self.messages.remove(&(*sender, message_id)).unwrap();
+            self.bytes -= message.bytes;
+            let mut datagram = Vec::with_capacity(message.bytes);
+            for fragment in message.fragments {
+                datagram.extend_from_slice(&fragment.unwrap());
+            }
+            return Ok(Some(datagram));
+        }
+
+        //make room, dropping the oldest messages
+        while self.bytes > MAX_REASSEMBLY_BYTES {
+            let oldest = *self
+                .messages
+                .iter()
+                .min_by_key(|(_, message)| message.deadline)
+                .unwrap()
+                .0;
+            let message = self.messages.remove(&oldest).unwrap();
+            self.bytes -= message.bytes;
+        }
+        Ok(None)
+    }
+
+    /// Drop the messages past their deadline.
+    fn expire(&mut self, now: Instant) {
+        let mut dropped_bytes = 0;
+        self.messages.retain(|_, message| {
+            if message.deadline > now {
+                return true;
+            }
+            dropped_bytes += message.bytes;
+            false
+        });
+        self.bytes -= dropped_bytes;
+    }
+}
+
+/// Most fragments a message of MAX_MESSAGE_SIZE is split into.
+fn max_fragment_count() -> usize {
+    let payload_size = MIN_MTU - FRAGMENT_HEADER_SIZE;
+    MAX_MESSAGE_SIZE.div_ceil(payload_size)
+}
+
+/// FragmentSocket
+/// Fragments the datagrams larger than the MTU before handing them to the transport,
+/// so they are not fragmented (and often dropped) on the IP layer.
+pub struct FragmentSocket {
+    transport: Arc<dyn DatagramTransport>,
+    mtu: usize,
+    next_message_id: AtomicU32,
+    reassembler: Mutex<Reassembler>,
+}
+
+impl FragmentSocket {
+    pub fn new(transport: Arc<dyn DatagramTransport>, mtu: usize) -> Result<Self> {
+        if mtu < MIN_MTU {
+            return Err(anyhow!("MTU {} is smaller than {}", mtu, MIN_MTU));
+        }
+        Ok(FragmentSocket {
+            transport: transport,
+            mtu: mtu,
+            next_message_id: AtomicU32::new(utility::new_transaction_id()),
+            reassembler: Mutex::new(Reassembler::new()),
+        })
+    }
+
+    /// Addresses of the transport, the first one is the main one.
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.transport.local_addrs()
+    }
+
+    /// Returns true if the transport can send to the endpoint.
+    pub fn can_reach(&self, endpoint: &SocketAddr) -> bool {
+        self.transport.can_reach(endpoint)
+    }
+
+    /// Send the datagram, in fragments if it is larger than the MTU.
+    /// Returns the size of the datagram.
+    pub async fn send_to(&self, datagram: &[u8], endpoint: &SocketAddr) -> Result<usize> {
+        if datagram.len() > MAX_MESSAGE_SIZE {
+            return Err(anyhow!(
+                "Datagram of {} bytes is larger than {}",
+                datagram.len(),
+                MAX_MESSAGE_SIZE
+            ));
+        }
+        let message_id = self.next_message_id.fetch_add(1, Ordering::Relaxed);
+        for fragment in split(datagram, self.mtu, message_id) {
+            self.transport.send_to(&fragment, endpoint).await?;
+        }
+        Ok(datagram.len())
+    }
+
+    /// Receive the next whole datagram.
+    /// Fragments are buffered until the rest arrives, malformed ones are dropped.
+    pub async fn recv_from(&self) -> std::io::Result<(Vec<u8>, SocketAddr)> {
+        let mut buffer = vec![0; UDP_RECEIVE_BUFFER_SIZE];
+        loop {
+            let (received_size, sender) = self.transport.recv_from(&mut buffer).await?;
+            let datagram = &buffer[..received_size];
+            if !is_fragment(datagram) {
+                return Ok((datagram.to_vec(), sender));
+            }
+            let reassembled =
+                self.reassembler
+                    .lock()
+                    .unwrap()
+                    .add(&sender, datagram, Instant::now());
+            match reassembled {
+                Ok(Some(datagram)) => return Ok((datagram, sender)),
+                Ok(None) => {}
+                Err(e) => {
+                    event!(Level::DEBUG, "Dropped a fragment from {}: {}", sender, e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_fragment, split, FragmentSocket, Reassembler, FRAGMENT_HEADER_SIZE};
+    use crate::constant::{DEFAULT_MTU, MAX_MESSAGE_SIZE, REASSEMBLY_TIMEOUT};
+    use crate::transport::MemoryNetwork;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use tokio::time::Instant;
+
+    #[test]
+    fn split_and_reassemble() {
+        let sender: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        let datagram: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        //small datagrams are not fragmented
+        assert_eq!(
+            split(&datagram[..1000], 1200, 1),
+            vec![datagram[..1000].to_vec()]
+        );
+
+        let fragments = split(&datagram, 1200, 7);
+        assert_eq!(fragments.len(), 5);
+        assert!(fragments.iter().all(|f| f.len() <= 1200 && is_fragment(f)));
+
+        let now = Instant::now();
+        let mut reassembler = Reassembler::new();
+        //in any order, duplicates are ignored
+        for fragment in fragments.iter().rev().skip(1) {
+            assert_eq!(reassembler.add(&sender, fragment, now).unwrap(), None);
+        }
+        assert_eq!(reassembler.add(&sender, &fragments[1], now).unwrap(), None);
+        assert_eq!(
+            reassembler.add(&sender, &fragments[4], now).unwrap(),
+            Some(datagram)
+        );
+        assert_eq!(reassembler.messages.len(), 0);
+    }
+
+    #[test]
+    fn reject_fragments() {
+        let sender: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        let other: SocketAddr = "127.0.0.1:4001".parse().unwrap();
+        let now = Instant::now();
+        let mut reassembler = Reassembler::new();
+        let fragments = split(&[1; 3000], 1200, 7);
+
+        //other sender, same message ID
+        assert_eq!(reassembler.add(&sender, &fragments[0], now).unwrap(), None);
+        assert_eq!(reassembler.add(&other, &fragments[1], now).unwrap(), None);
+        assert_eq!(reassembler.messages.len(), 2);
+
+        //malformed
+        assert!(reassembler
+            .add(&sender, &fragments[0][..FRAGMENT_HEADER_SIZE], now)
+            .is_err());
+        let mut out_of_range = fragments[0].clone();
+        out_of_range[8] = 9;
+        assert!(reassembler.add(&sender, &out_of_range, now).is_err());
+        let mut too_many = fragments[0].clone();
+        too_many[4] = 8;
+        too_many[10..12].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert!(reassembler.add(&sender, &too_many, now).is_err());
+
+        //too large
+        let large = split(&vec![0; MAX_MESSAGE_SIZE + 1], 1200, 9);
+        let results: Vec<bool> = large
+            .iter()
+            .map(|f| reassembler.add(&sender, f, now).is_err())
+            .collect();
+        assert!(results.contains(&true));
+
+        //expired
+        let later = now + REASSEMBLY_TIMEOUT;
+        assert_eq!(
+            reassembler.add(&sender, &fragments[2], later).unwrap(),
+            None
+        );
+        assert_eq!(reassembler.messages.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn fragment_socket() -> anyhow::Result<()> {
+        //every other datagram is delivered late
+        let network = MemoryNetwork::new(3);
+        network.set_reorder_rate(1.0);
+        let a = FragmentSocket::new(
+            Arc::new(network.bind(&"10.0.0.1:4870".parse()?)?),
+            DEFAULT_MTU,
+        )?;
+        let b = FragmentSocket::new(
+            Arc::new(network.bind(&"10.0.0.2:4870".parse()?)?),
+            DEFAULT_MTU,
+        )?;
+        let a_addr = a.local_addrs()[0];
+        let b_addr = b.local_addrs()[0];
+
+        //17 fragments, the last one is delivered after the ping
+        let large: Vec<u8> = (0..20000).map(|i| i as u8).collect();
+        a.send_to(&large, &b_addr).await?;
+        a.send_to(b"ping", &b_addr).await?;
+        assert_eq!(b.recv_from().await?, (b"ping".to_vec(), a_addr));
+        assert_eq!(b.recv_from().await?, (large, a_addr));
+
+        assert!(a
+            .send_to(&vec![0; MAX_MESSAGE_SIZE + 1], &b_addr)
+            .await
+            .is_err());
+        assert!(FragmentSocket::new(Arc::new(network.bind(&"10.0.0.3:0".parse()?)?), 100).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,227 @@
+use anyhow::{anyhow, Result};
+use openssl::hash::{hash, MessageDigest};
+use openssl::pkey::{Id, PKey, Private};
+use openssl::sign::{Signer, Verifier};
+use std::path::Path;
+use tracing::{event, Level};
+
+/// Size of a raw Ed25519 public key.
+pub const PUBLIC_KEY_SIZE: usize = 32;
+/// Size of an Ed25519 signature.
+pub const SIGNATURE_SIZE: usize = 64;
+
+/// Encodings of the points of small order (as in libsodium), ignoring the sign bit.
+/// Signatures by these keys can be forged, so they are rejected.
+const SMALL_ORDER_POINTS: [[u8; PUBLIC_KEY_SIZE]; 7] = [
+    //0 (order 4)
+    [0x00; 32],
+    //1 (order 1)
+    [
+        0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00,
+    ],
+    //order 8
+    [
+        0x26, 0xe8, 0x95, 0x8f, 0xc2, 0xb2, 0x27, 0xb0, 0x45, 0xc3, 0xf4, 0x89, 0xf2, 0xef, 0x98,
+        0xf0, 0xd5, 0xdf, 0xac, 0x05, 0xd3, 0xc6, 0x33, 0x39, 0xb1, 0x38, 0x02, 0x88, 0x6d, 0x53,
+        0xfc, 0x05,
+    ],
+    //order 8
+    [
+        0xc7, 0x17, 0x6a, 0x70, 0x3d, 0x4d, 0xd8, 0x4f, 0xba, 0x3c, 0x0b, 0x76, 0x0d, 0x10, 0x67,
+        0x0f, 0x2a, 0x20, 0x53, 0xfa, 0x2c, 0x39, 0xcc, 0xc6, 0x4e, 0xc7, 0xfd, 0x77, 0x92, 0xac,
+        0x03, 0x7a,
+    ],
+    //p-1 (order 2)
+    [
+        0xec, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0x7f,
+    ],
+    //p (=0, order 4)
+    [
+        0xed, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0x7f,
+    ],
+    //p+1 (=1, order 1)
+    [
+        0xee, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0x7f,
+    ],
+];
+
+/// Identity
+/// Ed25519 keypair of this node.
+/// The node ID is derived from the public key, so it does not change with the endpoint.
+pub struct Identity {
+    private_key: PKey<Private>,
+    public_key: Vec<u8>,
+}
+
+impl Identity {
+    /// Generate a new keypair.
+    pub fn generate() -> Result<Self> {
+        Self::from_private_key(PKey::generate_ed25519()?)
+    }
+
+    /// Load the keypair from the PEM file.
+    /// A new keypair is generated and saved if the file does not exist.
+    pub fn load_or_generate(path: &Path) -> Result<Self> {
+        if path.exists() {
+            let pem = std::fs::read(path)?;
+            let private_key = PKey::private_key_from_pem(&pem)?;
+            if private_key.id() != Id::ED25519 {
+                return Err(anyhow!("{:?} is not an Ed25519 private key", path));
+            }
+            event!(Level::INFO, "Loaded the identity from {:?}", path);
+            return Self::from_private_key(private_key);
+        }
+
+        let identity = Self::generate()?;
+        let pem = identity.private_key.private_key_to_pem_pkcs8()?;
+        write_private_file(path, &pem)?;
+        event!(Level::INFO, "Generated a new identity, saved to {:?}", path);
+        Ok(identity)
+    }
+
+    fn from_private_key(private_key: PKey<Private>) -> Result<Self> {
+        let public_key = private_key.raw_public_key()?;
+        debug_assert_eq!(public_key.len(), PUBLIC_KEY_SIZE);
+        Ok(Identity {
+            private_key: private_key,
+            public_key: public_key,
+        })
+    }
+
+    /// Raw public key.
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    pub fn node_id(&self) -> Vec<u8> {
+        public_key_to_node_id(&self.public_key)
+    }
+
+    /// Sign the data with our private key.
+    pub fn sign(&self, data: &[u8]) -> Vec<u8> {
+        let mut signer =
+            Signer::new_without_digest(&self.private_key).expect("Failed to create a signer");
+        let signature = signer
+            .sign_oneshot_to_vec(data)
+            .expect("Failed to sign data");
+        debug_assert_eq!(signature.len(), SIGNATURE_SIZE);
+        signature
+    }
+}
+
+/// Verify the signature made by the owner of the public key.
+/// Returns false for malformed keys and signatures too.
+pub fn verify(public_key: &[u8], data: &[u8], signature: &[u8]) -> bool {
+    if !is_valid_public_key(public_key) || signature.len() != SIGNATURE_SIZE {
+        return false;
+    }
+    let public_key = match PKey::public_key_from_raw_bytes(public_key, Id::ED25519) {
+        Ok(public_key) => public_key,
+        Err(_) => return false,
+    };
+    let is_valid = match Verifier::new_without_digest(&public_key) {
+        Ok(mut verifier) => verifier.verify_oneshot(signature, data).unwrap_or(false),
+        Err(_) => false,
+    };
+    is_valid
+}
+
+/// Node ID of the public key's owner.
+#[must_use]
+pub fn public_key_to_node_id(public_key: &[u8]) -> Vec<u8> {
+    let hash = hash(MessageDigest::sha3_512(), public_key).expect("Failed to hash a public key");
+    hash.to_vec()
+}
+
+/// Returns true if the bytes are a raw Ed25519 public key.
+pub fn is_valid_public_key(public_key: &[u8]) -> bool {
+    public_key.len() == PUBLIC_KEY_SIZE
+        && !has_small_order(public_key)
+        && PKey::public_key_from_raw_bytes(public_key, Id::ED25519).is_ok()
+}
+
+fn has_small_order(public_key: &[u8]) -> bool {
+    debug_assert_eq!(public_key.len(), PUBLIC_KEY_SIZE);
+    SMALL_ORDER_POINTS.iter().any(|point| {
+        point[..PUBLIC_KEY_SIZE - 1] == public_key[..PUBLIC_KEY_SIZE - 1]
+            && point[PUBLIC_KEY_SIZE - 1] == public_key[PUBLIC_KEY_SIZE - 1] & 0x7f
+    })
+}
+
+#[cfg(unix)]
+fn write_private_file(path: &Path, bytes: &[u8]) -> Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(bytes)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn write_private_file(path: &Path, bytes: &[u8]) -> Result<()> {
+    std::fs::write(path, bytes)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_valid_public_key, verify, Identity, PUBLIC_KEY_SIZE};
+
+    #[test]
+    fn load_or_generate() -> anyhow::Result<()> {
+        let mut path = std::env::temp_dir();
+        path.push(format!("cocoon_identity_test_{}.pem", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let generated = Identity::load_or_generate(&path)?;
+        assert_eq!(generated.public_key().len(), PUBLIC_KEY_SIZE);
+        assert!(is_valid_public_key(generated.public_key()));
+
+        //same identity after reload
+        let loaded = Identity::load_or_generate(&path)?;
+        assert_eq!(generated.public_key(), loaded.public_key());
+        assert_eq!(generated.node_id(), loaded.node_id());
+        assert_eq!(loaded.node_id().len(), 64);
+
+        assert_ne!(Identity::generate()?.node_id(), loaded.node_id());
+        assert!(!is_valid_public_key(&[0; 31]));
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn sign_and_verify() -> anyhow::Result<()> {
+        let identity = Identity::generate()?;
+        let other = Identity::generate()?;
+        let data = b"cocoon";
+
+        let signature = identity.sign(data);
+        assert!(verify(identity.public_key(), data, &signature));
+        //other data, other key
+        assert!(!verify(identity.public_key(), b"cocoom", &signature));
+        assert!(!verify(other.public_key(), data, &signature));
+        //malformed
+        assert!(!verify(identity.public_key(), data, &signature[1..]));
+        assert!(!verify(&[0; 31], data, &signature));
+        //anyone can sign for a key of small order
+        assert!(!verify(&[0; 32], data, &[0; 64]));
+        let mut identity_point = [0; 32];
+        identity_point[0] = 1;
+        identity_point[31] = 0x80;
+        assert!(!is_valid_public_key(&identity_point));
+        Ok(())
+    }
+}
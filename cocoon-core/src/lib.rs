@@ -6,9 +6,27 @@ extern crate serde_derive;
 mod cocoon_config;
 mod constant;
 mod dht_manager;
+mod fragment;
+mod identity;
 mod message;
+mod record_store;
 mod route_table;
+mod session;
+mod transport;
 mod utility;
+mod validation;
 
-pub use cocoon_config::{DaemonConfig, KVDatabaseConfig, SqliteConfig};
-pub use dht_manager::DHTManager;
+pub use cocoon_config::{
+    DaemonConfig, KVDatabaseConfig, MaintenanceConfig, NetworkManagerConfig, SqliteConfig,
+};
+pub use dht_manager::{BootstrapStatus, DHTManager, MaintenanceStatus};
+pub use identity::Identity;
+pub use message::PeerInfo;
+pub use record_store::StorageUsage;
+pub use transport::{DatagramTransport, MemoryNetwork, MemoryTransport, UdpTransport};
+pub use validation::{
+    content_hash, BlockValidator, ContentHashValidator, ReplyEvaluation, StoreError,
+};
+
+#[cfg(feature = "fuzzing")]
+pub use message::{decode_message, verify_message, Message, MessageHeader};
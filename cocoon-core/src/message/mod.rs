@@ -1,16 +1,19 @@
 use crate::constant;
+use crate::identity::{self, Identity, PUBLIC_KEY_SIZE, SIGNATURE_SIZE};
+use anyhow::{anyhow, Result};
 use bytecheck::CheckBytes;
 use rkyv::{
     ser::{serializers::AllocSerializer, Serializer},
     Archive, Deserialize, Infallible, Serialize,
 };
+use std::fmt;
 use std::net::SocketAddr;
 use tracing::{event, Level};
 
 /// Network messages.
 
 /// Network message types.
-#[derive(Debug, PartialEq, Eq, FromPrimitive)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, FromPrimitive)]
 pub enum MessageType {
     PingRequest = 1,
     FindNodeRequest = 2,
@@ -19,29 +22,42 @@ pub enum MessageType {
     PingResponse = 5,
     FindNodeResponse = 6,
     FindValueResponse = 7,
+    /// A piece of a message larger than the MTU, see fragment::split.
+    Fragment = 8,
+    /// First message of a session handshake, see session::SessionSocket.
+    HandshakeInit = 9,
+    HandshakeResponse = 10,
+    /// A signed message encrypted with a session key.
+    Encrypted = 11,
+    /// Sent by the nodes a store request reached, passed back along its hops to the publisher.
+    StoreValueResponse = 12,
 }
 
 /// Network message header.
-/// For now, only contains a message_type.
+/// A response carries the transaction ID of the request it answers.
 #[derive(Archive, Deserialize, Serialize, Debug, PartialEq)]
 #[archive_attr(derive(CheckBytes, Debug))]
 pub struct MessageHeader {
     pub message_type: u32,
+    pub transaction_id: u32,
 }
 
 impl MessageHeader {
-    pub fn new(message_type: MessageType) -> Self {
+    pub fn new(message_type: MessageType, transaction_id: u32) -> Self {
         MessageHeader {
             message_type: message_type as u32,
+            transaction_id: transaction_id,
         }
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> Self {
-        assert!(bytes.len() >= constant::MESSAGE_HEADER_SIZE);
-        let archived =
-            rkyv::check_archived_root::<Self>(&bytes[0..constant::MESSAGE_HEADER_SIZE]).unwrap();
-        let header: Self = archived.deserialize(&mut Infallible).unwrap();
-        header
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < constant::MESSAGE_HEADER_SIZE {
+            return Err(anyhow!("Message is too short ({} bytes)", bytes.len()));
+        }
+        let archived = rkyv::check_archived_root::<Self>(&bytes[0..constant::MESSAGE_HEADER_SIZE])
+            .map_err(|e| anyhow!("Malformed message header: {}", e))?;
+        let header: Self = archived.deserialize(&mut Infallible)?;
+        Ok(header)
     }
 
     pub fn to_bytes(&self) -> Vec<u8> {
@@ -55,10 +71,143 @@ impl MessageHeader {
         av.to_vec()
     }
 }
+
+/// Endpoint and public key of a node.
+#[derive(Archive, Deserialize, Serialize, Debug, PartialEq, Clone)]
+#[archive_attr(derive(CheckBytes, Debug))]
+pub struct PeerInfo {
+    pub endpoint: SocketAddr,
+    pub public_key: Vec<u8>,
+}
+
+impl PeerInfo {
+    pub fn new(endpoint: &SocketAddr, public_key: &[u8]) -> Self {
+        PeerInfo {
+            endpoint: *endpoint,
+            public_key: public_key.to_vec(),
+        }
+    }
+}
+
+impl fmt::Display for PeerInfo {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            formatter,
+            "endpoint: {} public key: {}",
+            self.endpoint,
+            hex::encode(&self.public_key)
+        )
+    }
+}
+
 //TODO: maybe it is possible to refactor these with traits or enum
 
+/// Size of the trailer which authenticates a datagram.
+pub const SIGNATURE_TRAILER_SIZE: usize = PUBLIC_KEY_SIZE + SIGNATURE_SIZE;
+
+/// Append the sender's public key and a signature over the message (header and body).
+/// Every datagram on the network is signed.
+pub fn sign_message(identity: &Identity, message: &[u8]) -> Vec<u8> {
+    let signature = identity.sign(message);
+    let mut datagram = Vec::with_capacity(message.len() + SIGNATURE_TRAILER_SIZE);
+    datagram.extend_from_slice(message);
+    datagram.extend_from_slice(identity.public_key());
+    datagram.extend_from_slice(&signature);
+    datagram
+}
+
+/// Verify the trailer appended by sign_message.
+/// Returns the sender's public key and the message (header and body).
+pub fn verify_message(datagram: &[u8]) -> Result<(&[u8], &[u8])> {
+    if datagram.len() < constant::MESSAGE_HEADER_SIZE + SIGNATURE_TRAILER_SIZE {
+        return Err(anyhow!("Datagram is too short to be signed"));
+    }
+    let (message, trailer) = datagram.split_at(datagram.len() - SIGNATURE_TRAILER_SIZE);
+    let (public_key, signature) = trailer.split_at(PUBLIC_KEY_SIZE);
+    if !identity::verify(public_key, message, signature) {
+        return Err(anyhow!("Bad signature"));
+    }
+    Ok((public_key, message))
+}
+
+/// A decoded message body.
+#[derive(Debug, PartialEq)]
+pub enum Message {
+    PingRequest(PingRequestMessage),
+    FindNodeRequest(FindNodeRequestMessage),
+    FindValueRequest(FindValueRequestMessage),
+    StoreValueRequest(StoreValueRequestMessage),
+    PingResponse(PingResponseMessage),
+    FindNodeResponse(FindNodeResponseMessage),
+    FindValueResponse(FindValueResponseMessage),
+    StoreValueResponse(StoreValueResponseMessage),
+}
+
+/// Decode a message (header and body) received from the network.
+/// Returns Err for unknown types, malformed bodies and keys of the wrong size,
+/// never panics whatever the bytes are.
+pub fn decode_message(bytes: &[u8]) -> Result<(MessageHeader, Message)> {
+    let header = MessageHeader::from_bytes(bytes)?;
+    let message_type: MessageType = match num::FromPrimitive::from_u32(header.message_type) {
+        Some(message_type) => message_type,
+        None => return Err(anyhow!("Unknown message type {}", header.message_type)),
+    };
+    let (key, message) = match message_type {
+        MessageType::PingRequest => (
+            None,
+            Message::PingRequest(PingRequestMessage::from_bytes(bytes)?.1),
+        ),
+        MessageType::FindNodeRequest => {
+            let (_, msg) = FindNodeRequestMessage::from_bytes(bytes)?;
+            (Some(msg.key.len()), Message::FindNodeRequest(msg))
+        }
+        MessageType::FindValueRequest => {
+            let (_, msg) = FindValueRequestMessage::from_bytes(bytes)?;
+            (Some(msg.key.len()), Message::FindValueRequest(msg))
+        }
+        MessageType::StoreValueRequest => {
+            let (_, msg) = StoreValueRequestMessage::from_bytes(bytes)?;
+            (Some(msg.key.len()), Message::StoreValueRequest(msg))
+        }
+        MessageType::PingResponse => (
+            None,
+            Message::PingResponse(PingResponseMessage::from_bytes(bytes)?.1),
+        ),
+        MessageType::FindNodeResponse => {
+            let (_, msg) = FindNodeResponseMessage::from_bytes(bytes)?;
+            (Some(msg.key.len()), Message::FindNodeResponse(msg))
+        }
+        MessageType::FindValueResponse => {
+            let (_, msg) = FindValueResponseMessage::from_bytes(bytes)?;
+            if msg.data.is_some() && msg.nodes.len() != 0 {
+                return Err(anyhow!("Find value response with both data and nodes"));
+            }
+            (Some(msg.key.len()), Message::FindValueResponse(msg))
+        }
+        MessageType::StoreValueResponse => {
+            let (_, msg) = StoreValueResponseMessage::from_bytes(bytes)?;
+            (Some(msg.key.len()), Message::StoreValueResponse(msg))
+        }
+        MessageType::Fragment => {
+            //fragments are reassembled before, never signed as a whole
+            return Err(anyhow!("Fragment is not a message"));
+        }
+        MessageType::HandshakeInit | MessageType::HandshakeResponse | MessageType::Encrypted => {
+            //handled by the session layer before
+            return Err(anyhow!("{:?} is not a DHT message", message_type));
+        }
+    };
+    if let Some(key_size) = key {
+        if key_size != constant::KEY_SIZE {
+            return Err(anyhow!("Invalid key size {}", key_size));
+        }
+    }
+    Ok((header, message))
+}
+
 /// Ping request message.
 /// Peers which received this will reply with PingResponseMessage.
+/// The sender's public key is in the signature trailer.
 #[derive(Archive, Deserialize, Serialize, Debug, PartialEq)]
 #[archive_attr(derive(CheckBytes, Debug))]
 pub struct PingRequestMessage {}
@@ -68,16 +217,16 @@ impl PingRequestMessage {
         PingRequestMessage {}
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> (MessageHeader, Self) {
-        let header = MessageHeader::from_bytes(bytes);
-        let archived =
-            rkyv::check_archived_root::<Self>(&bytes[constant::MESSAGE_HEADER_SIZE..]).unwrap();
-        let msg: Self = archived.deserialize(&mut Infallible).unwrap();
-        (header, msg)
+    pub fn from_bytes(bytes: &[u8]) -> Result<(MessageHeader, Self)> {
+        let header = MessageHeader::from_bytes(bytes)?;
+        let archived = rkyv::check_archived_root::<Self>(&bytes[constant::MESSAGE_HEADER_SIZE..])
+            .map_err(|e| anyhow!("Malformed message body: {}", e))?;
+        let msg: Self = archived.deserialize(&mut Infallible)?;
+        Ok((header, msg))
     }
 
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let header = MessageHeader::new(MessageType::PingRequest);
+    pub fn to_bytes(&self, transaction_id: u32) -> Vec<u8> {
+        let header = MessageHeader::new(MessageType::PingRequest, transaction_id);
         let mut bytes = header.to_bytes();
 
         let mut serializer = AllocSerializer::<32>::default(); //todo bench
@@ -101,17 +250,16 @@ impl FindNodeRequestMessage {
         FindNodeRequestMessage { key: key.to_vec() }
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> (MessageHeader, Self) {
-        let header = MessageHeader::from_bytes(bytes);
-        let archived =
-            rkyv::check_archived_root::<Self>(&bytes[constant::MESSAGE_HEADER_SIZE..]).unwrap();
-        let msg: Self = archived.deserialize(&mut Infallible).unwrap();
-
-        (header, msg)
+    pub fn from_bytes(bytes: &[u8]) -> Result<(MessageHeader, Self)> {
+        let header = MessageHeader::from_bytes(bytes)?;
+        let archived = rkyv::check_archived_root::<Self>(&bytes[constant::MESSAGE_HEADER_SIZE..])
+            .map_err(|e| anyhow!("Malformed message body: {}", e))?;
+        let msg: Self = archived.deserialize(&mut Infallible)?;
+        Ok((header, msg))
     }
 
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let header = MessageHeader::new(MessageType::FindNodeRequest);
+    pub fn to_bytes(&self, transaction_id: u32) -> Vec<u8> {
+        let header = MessageHeader::new(MessageType::FindNodeRequest, transaction_id);
         let mut bytes = header.to_bytes();
         let mut serializer = AllocSerializer::<512>::default(); //todo bench
         serializer
@@ -126,36 +274,40 @@ impl FindNodeRequestMessage {
 #[archive_attr(derive(CheckBytes, Debug))]
 pub struct FindValueRequestMessage {
     pub key: Vec<u8>,
+    pub replication_level: u32,
+    /// Number of hops the request has taken.
+    pub hop_count: u32,
+    /// Decides how the returned value is validated.
+    pub block_type: u32,
 }
 
 impl FindValueRequestMessage {
-    pub fn new(key: &[u8]) -> Self {
+    pub fn new(key: &[u8], replication_level: u32, hop_count: u32, block_type: u32) -> Self {
         debug_assert!(key.len() != 0);
         FindValueRequestMessage {
             key: key.to_owned(),
+            replication_level: replication_level,
+            hop_count: hop_count,
+            block_type: block_type,
         }
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> (MessageHeader, Self) {
-        let header = MessageHeader::from_bytes(bytes);
-        let archived =
-            rkyv::check_archived_root::<Self>(&bytes[constant::MESSAGE_HEADER_SIZE..]).unwrap();
-        let msg: Self = archived.deserialize(&mut Infallible).unwrap();
-        (header, msg)
+    pub fn from_bytes(bytes: &[u8]) -> Result<(MessageHeader, Self)> {
+        let header = MessageHeader::from_bytes(bytes)?;
+        let archived = rkyv::check_archived_root::<Self>(&bytes[constant::MESSAGE_HEADER_SIZE..])
+            .map_err(|e| anyhow!("Malformed message body: {}", e))?;
+        let msg: Self = archived.deserialize(&mut Infallible)?;
+        Ok((header, msg))
     }
 
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let header = MessageHeader::new(MessageType::FindValueRequest);
+    pub fn to_bytes(&self, transaction_id: u32) -> Vec<u8> {
+        let header = MessageHeader::new(MessageType::FindValueRequest, transaction_id);
         let mut bytes = header.to_bytes();
-        println!("find val header {} bytes", bytes.len());
         let mut serializer = AllocSerializer::<512>::default(); //todo bench
         serializer
             .serialize_value(self)
             .expect("Failed to serialize a message");
         let body_bytes = serializer.into_serializer().into_inner();
-
-        println!("find val body {} bytes", body_bytes.len());
-
         bytes.extend_from_slice(&body_bytes);
         bytes
     }
@@ -167,28 +319,47 @@ pub struct StoreValueRequestMessage {
     pub key: Vec<u8>,
     pub data: Vec<u8>,
     pub replication_level: u32,
-    //TODO expire date
+    /// Number of hops the request has taken.
+    pub hop_count: u32,
+    /// Seconds to keep the value.
+    pub ttl: u64,
+    /// Decides how the value is validated.
+    pub block_type: u32,
+    /// Chosen by the publisher and kept along the hops.
+    /// The replicas sign it in their responses, which are passed back hop by hop.
+    pub nonce: u64,
 }
 
 impl StoreValueRequestMessage {
-    pub fn new(key: &[u8], data: &[u8], replication_level: u32) -> Self {
+    pub fn new(
+        key: &[u8],
+        data: &[u8],
+        replication_level: u32,
+        hop_count: u32,
+        ttl: u64,
+        block_type: u32,
+    ) -> Self {
         StoreValueRequestMessage {
             key: key.to_vec(),
             data: data.to_vec(),
             replication_level: replication_level,
+            hop_count: hop_count,
+            ttl: ttl,
+            block_type: block_type,
+            nonce: 0,
         }
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> (MessageHeader, Self) {
-        let header = MessageHeader::from_bytes(bytes);
-        let archived =
-            rkyv::check_archived_root::<Self>(&bytes[constant::MESSAGE_HEADER_SIZE..]).unwrap();
-        let msg: Self = archived.deserialize(&mut Infallible).unwrap();
-        (header, msg)
+    pub fn from_bytes(bytes: &[u8]) -> Result<(MessageHeader, Self)> {
+        let header = MessageHeader::from_bytes(bytes)?;
+        let archived = rkyv::check_archived_root::<Self>(&bytes[constant::MESSAGE_HEADER_SIZE..])
+            .map_err(|e| anyhow!("Malformed message body: {}", e))?;
+        let msg: Self = archived.deserialize(&mut Infallible)?;
+        Ok((header, msg))
     }
 
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let header = MessageHeader::new(MessageType::StoreValueRequest);
+    pub fn to_bytes(&self, transaction_id: u32) -> Vec<u8> {
+        let header = MessageHeader::new(MessageType::StoreValueRequest, transaction_id);
         let mut bytes = header.to_bytes();
         let mut serializer = AllocSerializer::<512>::default(); //todo bench
         serializer
@@ -199,25 +370,32 @@ impl StoreValueRequestMessage {
     }
 }
 
+/// Reply to PingRequestMessage.
+/// Tells the pinger which endpoint the request came from, so nodes behind a NAT
+/// learn their public endpoint.
 #[derive(Archive, Deserialize, Serialize, Debug, PartialEq)]
 #[archive_attr(derive(CheckBytes, Debug))]
-pub struct PingResponseMessage {}
+pub struct PingResponseMessage {
+    pub observed_endpoint: SocketAddr,
+}
 
 impl PingResponseMessage {
-    pub fn new() -> Self {
-        PingResponseMessage {}
+    pub fn new(observed_endpoint: &SocketAddr) -> Self {
+        PingResponseMessage {
+            observed_endpoint: *observed_endpoint,
+        }
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> (MessageHeader, Self) {
-        let header = MessageHeader::from_bytes(bytes);
-        let archived =
-            rkyv::check_archived_root::<Self>(&bytes[constant::MESSAGE_HEADER_SIZE..]).unwrap();
-        let msg: Self = archived.deserialize(&mut Infallible).unwrap();
-        (header, msg)
+    pub fn from_bytes(bytes: &[u8]) -> Result<(MessageHeader, Self)> {
+        let header = MessageHeader::from_bytes(bytes)?;
+        let archived = rkyv::check_archived_root::<Self>(&bytes[constant::MESSAGE_HEADER_SIZE..])
+            .map_err(|e| anyhow!("Malformed message body: {}", e))?;
+        let msg: Self = archived.deserialize(&mut Infallible)?;
+        Ok((header, msg))
     }
 
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let header = MessageHeader::new(MessageType::PingResponse);
+    pub fn to_bytes(&self, transaction_id: u32) -> Vec<u8> {
+        let header = MessageHeader::new(MessageType::PingResponse, transaction_id);
         let mut bytes = header.to_bytes();
         let mut serializer = AllocSerializer::<512>::default(); //todo bench
         serializer
@@ -228,29 +406,33 @@ impl PingResponseMessage {
     }
 }
 
+/// Reply to FindNodeRequestMessage.
+/// Contains the key so the reply can be matched to a lookup.
 #[derive(Archive, Deserialize, Serialize, Debug, PartialEq)]
 #[archive_attr(derive(CheckBytes, Debug))]
 pub struct FindNodeResponseMessage {
-    pub nodes: Vec<SocketAddr>,
+    pub key: Vec<u8>,
+    pub nodes: Vec<PeerInfo>,
 }
 
 impl FindNodeResponseMessage {
-    pub fn new(addrs: &[SocketAddr]) -> Self {
+    pub fn new(key: &[u8], nodes: &[PeerInfo]) -> Self {
         FindNodeResponseMessage {
-            nodes: addrs.to_vec(),
+            key: key.to_vec(),
+            nodes: nodes.to_vec(),
         }
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> (MessageHeader, Self) {
-        let header = MessageHeader::from_bytes(bytes);
-        let archived =
-            rkyv::check_archived_root::<Self>(&bytes[constant::MESSAGE_HEADER_SIZE..]).unwrap();
-        let msg: Self = archived.deserialize(&mut Infallible).unwrap();
-        (header, msg)
+    pub fn from_bytes(bytes: &[u8]) -> Result<(MessageHeader, Self)> {
+        let header = MessageHeader::from_bytes(bytes)?;
+        let archived = rkyv::check_archived_root::<Self>(&bytes[constant::MESSAGE_HEADER_SIZE..])
+            .map_err(|e| anyhow!("Malformed message body: {}", e))?;
+        let msg: Self = archived.deserialize(&mut Infallible)?;
+        Ok((header, msg))
     }
 
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let header = MessageHeader::new(MessageType::FindNodeResponse);
+    pub fn to_bytes(&self, transaction_id: u32) -> Vec<u8> {
+        let header = MessageHeader::new(MessageType::FindNodeResponse, transaction_id);
         let mut bytes = header.to_bytes();
         let mut serializer = AllocSerializer::<512>::default(); //todo bench
         serializer
@@ -261,43 +443,36 @@ impl FindNodeResponseMessage {
     }
 }
 
+/// Reply to FindValueRequestMessage.
+/// Contains either the data or nodes closer to the key (possibly none).
 #[derive(Archive, Deserialize, Serialize, Debug, PartialEq)]
 #[archive_attr(derive(CheckBytes, Debug))]
 pub struct FindValueResponseMessage {
     pub key: Vec<u8>,
-    pub node: Option<SocketAddr>,
+    pub nodes: Vec<PeerInfo>,
     pub data: Option<Vec<u8>>,
 }
 
 impl FindValueResponseMessage {
-    pub fn new(key: &[u8], node: Option<&SocketAddr>, data: Option<&[u8]>) -> Self {
-        assert!(!(node.is_none() && data.is_none()));
-        assert!(!(node.is_some() && data.is_some()));
-        if node.is_some() && data.is_none() {
-            FindValueResponseMessage {
-                key: key.to_vec(),
-                node: Some(*node.unwrap()),
-                data: None,
-            }
-        } else {
-            FindValueResponseMessage {
-                key: key.to_vec(),
-                node: None,
-                data: Some(data.unwrap().to_vec()),
-            }
+    pub fn new(key: &[u8], nodes: &[PeerInfo], data: Option<&[u8]>) -> Self {
+        assert!(!(nodes.len() != 0 && data.is_some()));
+        FindValueResponseMessage {
+            key: key.to_vec(),
+            nodes: nodes.to_vec(),
+            data: data.map(|d| d.to_vec()),
         }
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> (MessageHeader, Self) {
-        let header = MessageHeader::from_bytes(bytes);
-        let archived =
-            rkyv::check_archived_root::<Self>(&bytes[constant::MESSAGE_HEADER_SIZE..]).unwrap();
-        let msg: Self = archived.deserialize(&mut Infallible).unwrap();
-        (header, msg)
+    pub fn from_bytes(bytes: &[u8]) -> Result<(MessageHeader, Self)> {
+        let header = MessageHeader::from_bytes(bytes)?;
+        let archived = rkyv::check_archived_root::<Self>(&bytes[constant::MESSAGE_HEADER_SIZE..])
+            .map_err(|e| anyhow!("Malformed message body: {}", e))?;
+        let msg: Self = archived.deserialize(&mut Infallible)?;
+        Ok((header, msg))
     }
 
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let header = MessageHeader::new(MessageType::FindValueResponse);
+    pub fn to_bytes(&self, transaction_id: u32) -> Vec<u8> {
+        let header = MessageHeader::new(MessageType::FindValueResponse, transaction_id);
         let mut bytes = header.to_bytes();
         let mut serializer = AllocSerializer::<512>::default(); //todo bench
         serializer
@@ -308,24 +483,200 @@ impl FindValueResponseMessage {
     }
 }
 
+/// Reply to StoreValueRequestMessage, from a node which stored the value (accepted)
+/// or refused it. Not sent by the nodes which only forward the request,
+/// they pass the replies back to the node the request came from as they are.
+#[derive(Archive, Deserialize, Serialize, Debug, PartialEq)]
+#[archive_attr(derive(CheckBytes, Debug))]
+pub struct StoreValueResponseMessage {
+    pub key: Vec<u8>,
+    pub accepted: bool,
+    /// Why the value was refused, empty if accepted.
+    pub reason: String,
+    /// Public key of the node which answered, not of the forwarders.
+    pub replica: Vec<u8>,
+    /// Signature of the key, the nonce of the request and the answer, see sign.
+    pub signature: Vec<u8>,
+}
+
+impl StoreValueResponseMessage {
+    pub fn accept(key: &[u8]) -> Self {
+        StoreValueResponseMessage {
+            key: key.to_vec(),
+            accepted: true,
+            reason: String::new(),
+            replica: Vec::new(),
+            signature: Vec::new(),
+        }
+    }
+
+    pub fn reject(key: &[u8], reason: &str) -> Self {
+        StoreValueResponseMessage {
+            key: key.to_vec(),
+            accepted: false,
+            reason: reason.to_owned(),
+            replica: Vec::new(),
+            signature: Vec::new(),
+        }
+    }
+
+    fn signed_bytes(&self, nonce: u64) -> Vec<u8> {
+        let mut bytes = self.key.clone();
+        bytes.extend_from_slice(&nonce.to_le_bytes());
+        bytes.push(self.accepted as u8);
+        bytes
+    }
+
+    /// Sign the answer to the request with the nonce,
+    /// the forwarders can not make it up nor replay an answer to another request.
+    pub fn sign(&mut self, identity: &Identity, nonce: u64) {
+        self.replica = identity.public_key().to_vec();
+        self.signature = identity.sign(&self.signed_bytes(nonce));
+    }
+
+    /// Returns true if the replica signed the answer to the request with the nonce.
+    pub fn verify(&self, nonce: u64) -> bool {
+        identity::verify(&self.replica, &self.signed_bytes(nonce), &self.signature)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<(MessageHeader, Self)> {
+        let header = MessageHeader::from_bytes(bytes)?;
+        let archived = rkyv::check_archived_root::<Self>(&bytes[constant::MESSAGE_HEADER_SIZE..])
+            .map_err(|e| anyhow!("Malformed message body: {}", e))?;
+        let msg: Self = archived.deserialize(&mut Infallible)?;
+        Ok((header, msg))
+    }
+
+    pub fn to_bytes(&self, transaction_id: u32) -> Vec<u8> {
+        let header = MessageHeader::new(MessageType::StoreValueResponse, transaction_id);
+        let mut bytes = header.to_bytes();
+        let mut serializer = AllocSerializer::<256>::default();
+        serializer
+            .serialize_value(self)
+            .expect("Failed to serialize a message");
+        bytes.extend_from_slice(&serializer.into_serializer().into_inner());
+        bytes
+    }
+}
+
+/// Starts a session handshake.
+/// Not signed, the initiator proves who it is inside the first encrypted datagram,
+/// so its public key is not seen on the way.
+#[derive(Archive, Deserialize, Serialize, Debug, PartialEq)]
+#[archive_attr(derive(CheckBytes, Debug))]
+pub struct HandshakeInitMessage {
+    /// Index of the session on the initiator, put in the encrypted datagrams it receives.
+    pub sender_index: u32,
+    /// X25519 public key generated for this handshake.
+    pub ephemeral_public_key: Vec<u8>,
+    /// Seconds since UNIX_EPOCH when the handshake was started, so a replayed init gets stale.
+    pub timestamp: u64,
+}
+
+impl HandshakeInitMessage {
+    pub fn new(sender_index: u32, ephemeral_public_key: &[u8], timestamp: u64) -> Self {
+        HandshakeInitMessage {
+            sender_index: sender_index,
+            ephemeral_public_key: ephemeral_public_key.to_vec(),
+            timestamp: timestamp,
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<(MessageHeader, Self)> {
+        let header = MessageHeader::from_bytes(bytes)?;
+        let archived = rkyv::check_archived_root::<Self>(&bytes[constant::MESSAGE_HEADER_SIZE..])
+            .map_err(|e| anyhow!("Malformed message body: {}", e))?;
+        let msg: Self = archived.deserialize(&mut Infallible)?;
+        Ok((header, msg))
+    }
+
+    pub fn to_bytes(&self, transaction_id: u32) -> Vec<u8> {
+        let header = MessageHeader::new(MessageType::HandshakeInit, transaction_id);
+        let mut bytes = header.to_bytes();
+        let mut serializer = AllocSerializer::<128>::default();
+        serializer
+            .serialize_value(self)
+            .expect("Failed to serialize a message");
+        bytes.extend_from_slice(&serializer.into_serializer().into_inner());
+        bytes
+    }
+}
+
+/// Reply to HandshakeInitMessage, completes the handshake.
+#[derive(Archive, Deserialize, Serialize, Debug, PartialEq)]
+#[archive_attr(derive(CheckBytes, Debug))]
+pub struct HandshakeResponseMessage {
+    /// Index of the session on the responder.
+    pub sender_index: u32,
+    /// sender_index of the init message.
+    pub receiver_index: u32,
+    pub ephemeral_public_key: Vec<u8>,
+    /// Public key of the responder and its signature of the handshake,
+    /// encrypted with a key of the ephemeral keys.
+    pub sealed_identity: Vec<u8>,
+}
+
+impl HandshakeResponseMessage {
+    pub fn new(
+        sender_index: u32,
+        receiver_index: u32,
+        ephemeral_public_key: &[u8],
+        sealed_identity: &[u8],
+    ) -> Self {
+        HandshakeResponseMessage {
+            sender_index: sender_index,
+            receiver_index: receiver_index,
+            ephemeral_public_key: ephemeral_public_key.to_vec(),
+            sealed_identity: sealed_identity.to_vec(),
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<(MessageHeader, Self)> {
+        let header = MessageHeader::from_bytes(bytes)?;
+        let archived = rkyv::check_archived_root::<Self>(&bytes[constant::MESSAGE_HEADER_SIZE..])
+            .map_err(|e| anyhow!("Malformed message body: {}", e))?;
+        let msg: Self = archived.deserialize(&mut Infallible)?;
+        Ok((header, msg))
+    }
+
+    pub fn to_bytes(&self, transaction_id: u32) -> Vec<u8> {
+        let header = MessageHeader::new(MessageType::HandshakeResponse, transaction_id);
+        let mut bytes = header.to_bytes();
+        let mut serializer = AllocSerializer::<128>::default();
+        serializer
+            .serialize_value(self)
+            .expect("Failed to serialize a message");
+        bytes.extend_from_slice(&serializer.into_serializer().into_inner());
+        bytes
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::constant::MESSAGE_HEADER_SIZE;
-    use super::{FindNodeRequestMessage, MessageHeader, MessageType, PingRequestMessage};
-    use crate::message::{FindValueRequestMessage, PingResponseMessage, StoreValueRequestMessage};
+    use super::{
+        decode_message, sign_message, verify_message, FindNodeRequestMessage, Message,
+        MessageHeader, MessageType, PingRequestMessage, SIGNATURE_TRAILER_SIZE,
+    };
+    use crate::identity::Identity;
+    use crate::message::{
+        FindNodeResponseMessage, FindValueRequestMessage, FindValueResponseMessage, PeerInfo,
+        PingResponseMessage, StoreValueRequestMessage, StoreValueResponseMessage,
+    };
     use openssl::rand::rand_bytes;
 
     #[test]
     pub fn header() -> anyhow::Result<()> {
-        let h = MessageHeader::new(MessageType::PingRequest);
+        let h = MessageHeader::new(MessageType::PingRequest, 42);
         assert_eq!(h.message_type, MessageType::PingRequest as u32);
+        assert_eq!(h.transaction_id, 42);
 
         //serialize
         let bytes = h.to_bytes();
         assert_eq!(bytes.len(), MESSAGE_HEADER_SIZE);
 
         //deserialize
-        let hh = MessageHeader::from_bytes(&bytes);
+        let hh = MessageHeader::from_bytes(&bytes)?;
 
         assert_eq!(h, hh);
         Ok(())
@@ -334,12 +685,12 @@ mod tests {
     #[test]
     pub fn ping_request() -> anyhow::Result<()> {
         //header
-        let header = MessageHeader::new(MessageType::PingRequest);
+        let header = MessageHeader::new(MessageType::PingRequest, 7);
 
         let req = PingRequestMessage::new();
 
-        let bytes = req.to_bytes();
-        let (h, r) = PingRequestMessage::from_bytes(&bytes);
+        let bytes = req.to_bytes(7);
+        let (h, r) = PingRequestMessage::from_bytes(&bytes)?;
         assert_eq!(h, header);
         assert_eq!(r, req);
         Ok(())
@@ -348,7 +699,7 @@ mod tests {
     #[test]
     pub fn find_node_request() -> anyhow::Result<()> {
         //header
-        let header = MessageHeader::new(MessageType::FindNodeRequest);
+        let header = MessageHeader::new(MessageType::FindNodeRequest, 7);
 
         let mut key = vec![0; 64];
         rand_bytes(&mut key)?;
@@ -356,8 +707,8 @@ mod tests {
 
         assert_eq!(key, req.key);
 
-        let bytes = req.to_bytes();
-        let (h, r) = FindNodeRequestMessage::from_bytes(&bytes);
+        let bytes = req.to_bytes(7);
+        let (h, r) = FindNodeRequestMessage::from_bytes(&bytes)?;
         assert_eq!(h, header);
         assert_eq!(r, req);
 
@@ -367,16 +718,19 @@ mod tests {
     #[test]
     pub fn find_value_request() -> anyhow::Result<()> {
         //header
-        let header = MessageHeader::new(MessageType::FindValueRequest);
+        let header = MessageHeader::new(MessageType::FindValueRequest, 7);
 
         let mut key = vec![0; 64];
         rand_bytes(&mut key)?;
 
-        let req = FindValueRequestMessage::new(&key);
+        let req = FindValueRequestMessage::new(&key, 5, 3, 1);
         assert_eq!(key, req.key);
+        assert_eq!(5, req.replication_level);
+        assert_eq!(3, req.hop_count);
+        assert_eq!(1, req.block_type);
 
-        let bytes = req.to_bytes();
-        let (h, r) = FindValueRequestMessage::from_bytes(&bytes);
+        let bytes = req.to_bytes(7);
+        let (h, r) = FindValueRequestMessage::from_bytes(&bytes)?;
         assert_eq!(h, header);
         assert_eq!(r, req);
 
@@ -386,39 +740,193 @@ mod tests {
     #[test]
     pub fn store_value_request() -> anyhow::Result<()> {
         //header
-        let header = MessageHeader::new(MessageType::StoreValueRequest);
+        let header = MessageHeader::new(MessageType::StoreValueRequest, 7);
 
         let mut key = vec![0; 64];
         let mut data = vec![0; 64];
         rand_bytes(&mut key)?;
         rand_bytes(&mut data)?;
         let rep_level = 99;
-        let req = StoreValueRequestMessage::new(&key, &data, rep_level);
+        let req = StoreValueRequestMessage::new(&key, &data, rep_level, 3, 3600, 1);
         assert_eq!(key, req.key);
         assert_eq!(data, req.data);
         assert_eq!(rep_level, req.replication_level);
+        assert_eq!(3, req.hop_count);
+        assert_eq!(3600, req.ttl);
+        assert_eq!(1, req.block_type);
+        assert_eq!(0, req.nonce);
 
-        let bytes = req.to_bytes();
-        let (h, r) = StoreValueRequestMessage::from_bytes(&bytes);
+        let bytes = req.to_bytes(7);
+        let (h, r) = StoreValueRequestMessage::from_bytes(&bytes)?;
         assert_eq!(h, header);
         assert_eq!(r, req);
 
+        let mut req = req;
+        req.nonce = u64::MAX;
+        let (_, r) = StoreValueRequestMessage::from_bytes(&req.to_bytes(7))?;
+        assert_eq!(r, req);
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn store_value_response() -> anyhow::Result<()> {
+        let header = MessageHeader::new(MessageType::StoreValueResponse, 7);
+        let key = vec![3; 64];
+
+        let res = StoreValueResponseMessage::accept(&key);
+        assert!(res.accepted);
+        let (h, r) = StoreValueResponseMessage::from_bytes(&res.to_bytes(7))?;
+        assert_eq!(h, header);
+        assert_eq!(r, res);
+
+        let res = StoreValueResponseMessage::reject(&key, "kvdb is full");
+        assert!(!res.accepted);
+        assert_eq!(res.reason, "kvdb is full");
+        let (_, r) = StoreValueResponseMessage::from_bytes(&res.to_bytes(7))?;
+        assert_eq!(r, res);
+
+        //signed by the replica for the request with the nonce
+        let replica = Identity::generate()?;
+        let mut res = StoreValueResponseMessage::accept(&key);
+        assert!(!res.verify(1));
+        res.sign(&replica, 1);
+        assert_eq!(res.replica, replica.public_key());
+        let (_, r) = StoreValueResponseMessage::from_bytes(&res.to_bytes(7))?;
+        assert!(r.verify(1));
+        assert!(!r.verify(2));
+        //a forwarder turns it into a rejection
+        let mut forged = r;
+        forged.accepted = false;
+        assert!(!forged.verify(1));
         Ok(())
     }
 
     #[test]
     pub fn ping_response() -> anyhow::Result<()> {
         //header
-        let header = MessageHeader::new(MessageType::PingResponse);
+        let header = MessageHeader::new(MessageType::PingResponse, 7);
 
-        let req = PingResponseMessage::new();
+        let req = PingResponseMessage::new(&"[2001:db8::1]:4870".parse()?);
 
-        let bytes = req.to_bytes();
-        let (h, r) = PingResponseMessage::from_bytes(&bytes);
+        let bytes = req.to_bytes(7);
+        let (h, r) = PingResponseMessage::from_bytes(&bytes)?;
         assert_eq!(h, header);
         assert_eq!(r, req);
         Ok(())
     }
 
-    //todo other response message
+    #[test]
+    pub fn find_node_response() -> anyhow::Result<()> {
+        //header
+        let header = MessageHeader::new(MessageType::FindNodeResponse, 7);
+
+        let mut key = vec![0; 64];
+        rand_bytes(&mut key)?;
+        let nodes = vec![
+            PeerInfo::new(&"127.0.0.1:4000".parse()?, &[1; 32]),
+            PeerInfo::new(&"[::1]:4001".parse()?, &[2; 32]),
+        ];
+        let res = FindNodeResponseMessage::new(&key, &nodes);
+        assert_eq!(key, res.key);
+        assert_eq!(nodes, res.nodes);
+
+        let bytes = res.to_bytes(7);
+        let (h, r) = FindNodeResponseMessage::from_bytes(&bytes)?;
+        assert_eq!(h, header);
+        assert_eq!(r, res);
+        Ok(())
+    }
+
+    #[test]
+    pub fn find_value_response() -> anyhow::Result<()> {
+        //header
+        let header = MessageHeader::new(MessageType::FindValueResponse, 7);
+
+        let mut key = vec![0; 64];
+        let mut data = vec![0; 64];
+        rand_bytes(&mut key)?;
+        rand_bytes(&mut data)?;
+
+        //with data
+        let res = FindValueResponseMessage::new(&key, &[], Some(&data));
+        let bytes = res.to_bytes(7);
+        let (h, r) = FindValueResponseMessage::from_bytes(&bytes)?;
+        assert_eq!(h, header);
+        assert_eq!(r, res);
+        assert_eq!(r.data, Some(data));
+
+        //with nodes
+        let nodes = vec![PeerInfo::new(&"127.0.0.1:4000".parse()?, &[1; 32])];
+        let res = FindValueResponseMessage::new(&key, &nodes, None);
+        let bytes = res.to_bytes(7);
+        let (_, r) = FindValueResponseMessage::from_bytes(&bytes)?;
+        assert_eq!(r, res);
+        assert!(r.data.is_none());
+        Ok(())
+    }
+
+    #[test]
+    pub fn decode() -> anyhow::Result<()> {
+        let key = vec![7; 64];
+        let bytes = FindNodeRequestMessage::new(&key).to_bytes(9);
+        let (header, message) = decode_message(&bytes)?;
+        assert_eq!(header, MessageHeader::new(MessageType::FindNodeRequest, 9));
+        assert_eq!(
+            message,
+            Message::FindNodeRequest(FindNodeRequestMessage::new(&key))
+        );
+
+        //short key
+        assert!(decode_message(&FindNodeRequestMessage::new(&[7; 3]).to_bytes(9)).is_err());
+        //truncated
+        assert!(decode_message(&bytes[..bytes.len() - 1]).is_err());
+        assert!(decode_message(&bytes[..3]).is_err());
+        //unknown type
+        let mut unknown = bytes.clone();
+        unknown[0] = 0xee;
+        assert!(decode_message(&unknown).is_err());
+        //body of another type
+        let mut other = bytes.clone();
+        other[0] = MessageType::StoreValueRequest as u8;
+        assert!(decode_message(&other).is_err());
+        let mut fragment = bytes.clone();
+        fragment[0] = MessageType::Fragment as u8;
+        assert!(decode_message(&fragment).is_err());
+
+        //random bytes
+        let mut random = vec![0; 256];
+        for _ in 0..1000 {
+            rand_bytes(&mut random)?;
+            random[0] = random[0] % 13;
+            random[1..4].copy_from_slice(&[0; 3]);
+            let _ = decode_message(&random);
+        }
+        Ok(())
+    }
+
+    #[test]
+    pub fn signed_message() -> anyhow::Result<()> {
+        let identity = Identity::generate()?;
+        let message = PingRequestMessage::new().to_bytes(7);
+        let datagram = sign_message(&identity, &message);
+        assert_eq!(datagram.len(), message.len() + SIGNATURE_TRAILER_SIZE);
+
+        let (public_key, m) = verify_message(&datagram)?;
+        assert_eq!(public_key, identity.public_key());
+        assert_eq!(m, message.as_slice());
+
+        //tampered message
+        let mut tampered = datagram.clone();
+        tampered[MESSAGE_HEADER_SIZE - 1] ^= 1;
+        assert!(verify_message(&tampered).is_err());
+        //someone else's key
+        let mut forged = datagram.clone();
+        let start = message.len();
+        forged[start..start + 32].copy_from_slice(Identity::generate()?.public_key());
+        assert!(verify_message(&forged).is_err());
+        //unsigned
+        assert!(verify_message(&message).is_err());
+        Ok(())
+    }
 }
@@ -0,0 +1,455 @@
+use crate::route_table::{distance_to_fraction, node_id_distance};
+use anyhow::{anyhow, Result};
+use rocksdb::{IteratorMode, Options, DB};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::{event, Level};
+
+const DATA_COLUMN_FAMILY: &str = "dht-data-cf";
+const METADATA_COLUMN_FAMILY: &str = "dht-metadata-cf";
+const METADATA_SIZE: usize = 21;
+
+/// Key, value and block type of a record.
+pub type Record = (Vec<u8>, Vec<u8>, u32);
+
+/// RecordMetadata
+/// Kept next to each value in the kvdb.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct RecordMetadata {
+    /// Seconds since UNIX_EPOCH.
+    pub expiration: u64,
+    /// Seconds since UNIX_EPOCH of the last store.
+    pub stored_at: u64,
+    /// True if we published the record.
+    /// Local records are republished instead of expiring.
+    pub is_local: bool,
+    /// Decides how the value is validated.
+    pub block_type: u32,
+}
+
+impl RecordMetadata {
+    pub fn is_expired(&self, now: u64) -> bool {
+        !self.is_local && self.expiration <= now
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(METADATA_SIZE);
+        bytes.extend_from_slice(&self.expiration.to_le_bytes());
+        bytes.extend_from_slice(&self.stored_at.to_le_bytes());
+        bytes.push(self.is_local as u8);
+        bytes.extend_from_slice(&self.block_type.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != METADATA_SIZE {
+            return Err(anyhow!("Invalid record metadata size {}", bytes.len()));
+        }
+        Ok(RecordMetadata {
+            expiration: u64::from_le_bytes(bytes[0..8].try_into()?),
+            stored_at: u64::from_le_bytes(bytes[8..16].try_into()?),
+            is_local: bytes[16] != 0,
+            block_type: u32::from_le_bytes(bytes[17..21].try_into()?),
+        })
+    }
+}
+
+/// Bytes used by the values in the kvdb.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct StorageUsage {
+    /// Number of values.
+    pub records: u64,
+    /// Bytes of all values.
+    pub used_bytes: u64,
+    /// Bytes of the values we published, which are never evicted.
+    pub local_bytes: u64,
+    pub quota_bytes: u64,
+}
+
+/// RecordStore
+/// Values of the DHT and their expiration, stored in the kvdb.
+/// Values without metadata (stored by older versions) are treated as expired.
+/// Once the quota is reached, values stored for other nodes are evicted,
+/// expired ones first, then the ones far from our node ID and close to expiration.
+pub struct RecordStore {
+    kvdb: DB,
+    /// Our node ID.
+    own_id: Vec<u8>,
+    /// Also serializes the writes, so the usage matches the kvdb.
+    usage: Mutex<StorageUsage>,
+}
+
+impl RecordStore {
+    pub fn open(path: &Path, own_id: &[u8], quota: u64) -> Result<Self> {
+        let mut db_options = Options::default();
+        db_options.create_if_missing(true);
+        db_options.create_missing_column_families(true);
+        let kvdb = DB::open_cf(
+            &db_options,
+            path,
+            [DATA_COLUMN_FAMILY, METADATA_COLUMN_FAMILY],
+        )
+        .map_err(|e| anyhow!("Failed to open the kvdb {:?}: {}", path, e))?;
+
+        let mut store = RecordStore {
+            kvdb: kvdb,
+            own_id: own_id.to_vec(),
+            usage: Mutex::new(StorageUsage::default()),
+        };
+        let mut usage = StorageUsage {
+            quota_bytes: quota,
+            ..StorageUsage::default()
+        };
+        let data_cfh = store.kvdb.cf_handle(DATA_COLUMN_FAMILY).unwrap();
+        for (key, data) in store.kvdb.iterator_cf(data_cfh, IteratorMode::Start) {
+            usage.records += 1;
+            usage.used_bytes += data.len() as u64;
+            if let Ok(Some(metadata)) = store.metadata(&key) {
+                if metadata.is_local {
+                    usage.local_bytes += data.len() as u64;
+                }
+            }
+        }
+        *store.usage.get_mut().unwrap() = usage;
+        Ok(store)
+    }
+
+    pub fn usage(&self) -> StorageUsage {
+        *self.usage.lock().unwrap()
+    }
+
+    /// Store a value which expires after ttl.
+    /// Storing a known value again keeps the later expiration, and a local record stays local.
+    /// Local records are always stored. Others evict less valuable values if the quota
+    /// is reached, and Ok(false) is returned if there are not enough of them.
+    pub fn put(
+        &self,
+        key: &[u8],
+        data: &[u8],
+        ttl: Duration,
+        is_local: bool,
+        block_type: u32,
+        now: u64,
+    ) -> Result<bool> {
+        let mut usage = self.usage.lock().unwrap();
+        let mut metadata = RecordMetadata {
+            expiration: now.saturating_add(ttl.as_secs()),
+            stored_at: now,
+            is_local: is_local,
+            block_type: block_type,
+        };
+        if let Some(old) = self.metadata(key).unwrap_or(None) {
+            if !old.is_expired(now) {
+                metadata.expiration = std::cmp::max(metadata.expiration, old.expiration);
+                metadata.is_local |= old.is_local;
+                if old.is_local {
+                    //republished with the type it was published with
+                    metadata.block_type = old.block_type;
+                }
+            }
+        }
+
+        let data_cfh = self.kvdb.cf_handle(DATA_COLUMN_FAMILY).unwrap();
+        let old_size = match self.kvdb.get_cf(data_cfh, key)? {
+            Some(old_data) => old_data.len() as u64,
+            None => 0,
+        };
+        let needed = (usage.used_bytes - old_size).saturating_add(data.len() as u64);
+        if !metadata.is_local && needed > usage.quota_bytes {
+            //free a tenth of the quota at once, so we do not scan on every store
+            let min_to_free = needed - usage.quota_bytes;
+            let to_free = needed.saturating_sub(usage.quota_bytes / 10 * 9);
+            let score = self.eviction_score(key, &metadata, now);
+            let mut victims = Vec::new();
+            let mut freed = 0;
+            for (victim_key, victim_score) in self.eviction_candidates(now)? {
+                if freed >= to_free || victim_score <= score {
+                    break;
+                }
+                if victim_key == key {
+                    continue;
+                }
+                if let Some(victim_data) = self.kvdb.get_cf(data_cfh, &victim_key)? {
+                    freed += victim_data.len() as u64;
+                }
+                victims.push(victim_key);
+            }
+            if freed < min_to_free {
+                return Ok(false);
+            }
+            for victim_key in &victims {
+                self.remove_impl(&mut usage, victim_key)?;
+            }
+            event!(Level::DEBUG, "Evicted {} values", victims.len());
+        }
+
+        self.remove_impl(&mut usage, key)?;
+        let metadata_cfh = self.kvdb.cf_handle(METADATA_COLUMN_FAMILY).unwrap();
+        self.kvdb.put_cf(data_cfh, key, data)?;
+        self.kvdb.put_cf(metadata_cfh, key, metadata.to_bytes())?;
+        usage.records += 1;
+        usage.used_bytes += data.len() as u64;
+        if metadata.is_local {
+            usage.local_bytes += data.len() as u64;
+        }
+        Ok(true)
+    }
+
+    /// Get the value with the given key.
+    /// Returns Ok(None) if not found or expired.
+    pub fn get(&self, key: &[u8], now: u64) -> Result<Option<Vec<u8>>> {
+        match self.metadata(key)? {
+            Some(metadata) if !metadata.is_expired(now) => {
+                let data_cfh = self.kvdb.cf_handle(DATA_COLUMN_FAMILY).unwrap();
+                Ok(self.kvdb.get_cf(data_cfh, key)?)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    pub fn metadata(&self, key: &[u8]) -> Result<Option<RecordMetadata>> {
+        let metadata_cfh = self.kvdb.cf_handle(METADATA_COLUMN_FAMILY).unwrap();
+        match self.kvdb.get_cf(metadata_cfh, key)? {
+            Some(bytes) => Ok(Some(RecordMetadata::from_bytes(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Remove the value with the given key.
+    pub fn remove(&self, key: &[u8]) -> Result<()> {
+        let mut usage = self.usage.lock().unwrap();
+        self.remove_impl(&mut usage, key)
+    }
+
+    fn remove_impl(&self, usage: &mut StorageUsage, key: &[u8]) -> Result<()> {
+        let data_cfh = self.kvdb.cf_handle(DATA_COLUMN_FAMILY).unwrap();
+        let metadata_cfh = self.kvdb.cf_handle(METADATA_COLUMN_FAMILY).unwrap();
+        let data = match self.kvdb.get_cf(data_cfh, key)? {
+            Some(data) => data,
+            None => {
+                self.kvdb.delete_cf(metadata_cfh, key)?;
+                return Ok(());
+            }
+        };
+        if let Ok(Some(metadata)) = self.metadata(key) {
+            if metadata.is_local {
+                usage.local_bytes -= data.len() as u64;
+            }
+        }
+        self.kvdb.delete_cf(metadata_cfh, key)?;
+        self.kvdb.delete_cf(data_cfh, key)?;
+        usage.records -= 1;
+        usage.used_bytes -= data.len() as u64;
+        Ok(())
+    }
+
+    /// Higher is evicted first.
+    /// Expired values first, then by the distance from our node ID
+    /// plus the elapsed fraction of the lifetime.
+    fn eviction_score(&self, key: &[u8], metadata: &RecordMetadata, now: u64) -> f64 {
+        if metadata.is_expired(now) {
+            return f64::INFINITY;
+        }
+        let distance = if key.len() == self.own_id.len() {
+            distance_to_fraction(&node_id_distance(key, &self.own_id))
+        } else {
+            1.0
+        };
+        let lifetime = metadata
+            .expiration
+            .saturating_sub(metadata.stored_at)
+            .max(1);
+        let age = now.saturating_sub(metadata.stored_at).min(lifetime);
+        distance + age as f64 / lifetime as f64
+    }
+
+    /// Keys of the values which may be evicted (all but local ones),
+    /// with the eviction score in descending order.
+    fn eviction_candidates(&self, now: u64) -> Result<Vec<(Vec<u8>, f64)>> {
+        let data_cfh = self.kvdb.cf_handle(DATA_COLUMN_FAMILY).unwrap();
+        let mut candidates = Vec::new();
+        for (key, _) in self.kvdb.iterator_cf(data_cfh, IteratorMode::Start) {
+            let score = match self.metadata(&key) {
+                Ok(Some(metadata)) if metadata.is_local => continue,
+                Ok(Some(metadata)) => self.eviction_score(&key, &metadata, now),
+                _ => f64::INFINITY,
+            };
+            candidates.push((key.to_vec(), score));
+        }
+        candidates.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+        Ok(candidates)
+    }
+
+    /// Remove all expired values.
+    /// Returns the number of removed values.
+    pub fn remove_expired(&self, now: u64) -> Result<usize> {
+        let mut usage = self.usage.lock().unwrap();
+        let data_cfh = self.kvdb.cf_handle(DATA_COLUMN_FAMILY).unwrap();
+        let mut expired_keys = Vec::new();
+        for (key, _) in self.kvdb.iterator_cf(data_cfh, IteratorMode::Start) {
+            let is_expired = match self.metadata(&key) {
+                Ok(Some(metadata)) => metadata.is_expired(now),
+                Ok(None) => true,
+                Err(e) => {
+                    event!(
+                        Level::WARN,
+                        "Broken metadata of {}: {}",
+                        hex::encode(&key),
+                        e
+                    );
+                    true
+                }
+            };
+            if is_expired {
+                expired_keys.push(key);
+            }
+        }
+        for key in &expired_keys {
+            self.remove_impl(&mut usage, key)?;
+        }
+        Ok(expired_keys.len())
+    }
+
+    /// Keys, values and block types of the local records.
+    pub fn local_records(&self) -> Result<Vec<Record>> {
+        let metadata_cfh = self.kvdb.cf_handle(METADATA_COLUMN_FAMILY).unwrap();
+        let data_cfh = self.kvdb.cf_handle(DATA_COLUMN_FAMILY).unwrap();
+        let mut records = Vec::new();
+        for (key, metadata) in self.kvdb.iterator_cf(metadata_cfh, IteratorMode::Start) {
+            let metadata = RecordMetadata::from_bytes(&metadata)?;
+            if !metadata.is_local {
+                continue;
+            }
+            if let Some(data) = self.kvdb.get_cf(data_cfh, &key)? {
+                records.push((key.to_vec(), data, metadata.block_type));
+            }
+        }
+        Ok(records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RecordMetadata, RecordStore};
+    use std::time::Duration;
+
+    const OWN_ID: [u8; 64] = [0; 64];
+
+    fn open(name: &str, quota: u64) -> RecordStore {
+        let mut path = std::env::temp_dir();
+        path.push(format!(
+            "cocoon_record_store_{}_{}",
+            name,
+            std::process::id()
+        ));
+        RecordStore::open(&path, &OWN_ID, quota).unwrap()
+    }
+
+    /// Key at the distance of the given first byte from OWN_ID.
+    fn key(distance: u8) -> Vec<u8> {
+        let mut key = vec![0; 64];
+        key[0] = distance;
+        key
+    }
+
+    #[test]
+    fn metadata() {
+        let metadata = RecordMetadata {
+            expiration: 200,
+            stored_at: 100,
+            is_local: true,
+            block_type: 1,
+        };
+        assert_eq!(
+            RecordMetadata::from_bytes(&metadata.to_bytes()).unwrap(),
+            metadata
+        );
+        assert!(RecordMetadata::from_bytes(&[0; 3]).is_err());
+    }
+
+    #[test]
+    fn expiration() -> anyhow::Result<()> {
+        let store = open("expiration", 1 << 20);
+        let ttl = Duration::from_secs(100);
+        store.put(b"remote", b"r", ttl, false, 1, 1000)?;
+        store.put(b"local", b"l", ttl, true, 1, 1000)?;
+        assert_eq!(store.get(b"remote", 1099)?, Some(b"r".to_vec()));
+        assert_eq!(store.get(b"remote", 1100)?, None);
+        //local records do not expire
+        assert_eq!(store.get(b"local", 5000)?, Some(b"l".to_vec()));
+
+        //the later expiration is kept
+        store.put(b"remote", b"r", Duration::from_secs(10), false, 1, 1050)?;
+        assert_eq!(store.metadata(b"remote")?.unwrap().expiration, 1100);
+
+        assert_eq!(store.remove_expired(1099)?, 0);
+        assert_eq!(store.remove_expired(1100)?, 1);
+        assert!(store.metadata(b"remote")?.is_none());
+        assert_eq!(
+            store.local_records()?,
+            vec![(b"local".to_vec(), b"l".to_vec(), 1)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn local_stays_local() -> anyhow::Result<()> {
+        let store = open("local_stays_local", 1 << 20);
+        let ttl = Duration::from_secs(100);
+        store.put(b"key", b"value", ttl, true, 1, 1000)?;
+        //cached again from the network
+        store.put(b"key", b"value", ttl, false, 1, 1010)?;
+        let metadata = store.metadata(b"key")?.unwrap();
+        assert!(metadata.is_local);
+        assert_eq!(metadata.stored_at, 1010);
+
+        store.remove(b"key")?;
+        assert_eq!(store.get(b"key", 1010)?, None);
+        assert!(store.local_records()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn quota() -> anyhow::Result<()> {
+        let store = open("quota", 100);
+        let ttl = Duration::from_secs(100);
+        let value = [0; 40];
+        assert!(store.put(&key(0x80), &value, ttl, false, 1, 1000)?);
+        assert!(store.put(&key(0x01), &value, ttl, false, 1, 1000)?);
+        //over quota, evict the far one
+        assert!(store.put(&key(0x02), &value, ttl, false, 1, 1000)?);
+        assert!(store.get(&key(0x80), 1000)?.is_none());
+        assert!(store.get(&key(0x01), 1000)?.is_some());
+        assert_eq!(store.usage().used_bytes, 80);
+        assert_eq!(store.usage().records, 2);
+
+        //farther than everything we have
+        assert!(!store.put(&key(0xff), &value, ttl, false, 1, 1000)?);
+        assert!(store.get(&key(0xff), 1000)?.is_none());
+
+        //local records are always stored and never evicted
+        assert!(store.put(&key(0xf0), &value, ttl, true, 1, 1000)?);
+        assert!(store.put(&key(0xf1), &value, ttl, true, 1, 1000)?);
+        assert_eq!(store.usage().local_bytes, 80);
+        assert!(!store.put(&key(0x03), &value, ttl, false, 1, 1000)?);
+        store.remove_expired(5000)?;
+        assert_eq!(store.usage().used_bytes, 80);
+        assert_eq!(store.usage().local_bytes, 80);
+        Ok(())
+    }
+
+    #[test]
+    fn evict_old_value() -> anyhow::Result<()> {
+        let store = open("evict_old_value", 100);
+        let ttl = Duration::from_secs(100);
+        let value = [0; 40];
+        store.put(&key(0x01), &value, ttl, false, 1, 1000)?;
+        store.put(&key(0x02), &value, ttl, false, 1, 1050)?;
+        //about the same distance, the one close to expiration goes
+        assert!(store.put(&key(0x03), &value, ttl, false, 1, 1090)?);
+        assert!(store.get(&key(0x01), 1090)?.is_none());
+        assert!(store.get(&key(0x02), 1090)?.is_some());
+        Ok(())
+    }
+}
@@ -1,12 +1,23 @@
+use crate::message::PeerInfo;
 use crate::route_table::node;
 use node::Node;
 use std::collections::VecDeque;
 use std::sync::{Arc, Mutex};
+use tokio::time::Instant;
 use tracing::{event, span, Level};
 
 /// Represent Bucket.
+/// Nodes are ordered from least recently seen (front) to most recently seen (back).
 pub struct Bucket {
     pub nodes: VecDeque<Arc<Mutex<Node>>>,
+    /// Nodes which did not fit in the bucket, most recently seen at the back.
+    /// Used to replace dead nodes.
+    pub replacement_cache: VecDeque<PeerInfo>,
+    /// ID of the node which is being pinged to decide whether it should be evicted.
+    pub probing: Option<Vec<u8>>,
+    /// When a lookup of a key in the bucket's range was last made.
+    /// Buckets without lookups for a while are refreshed.
+    pub last_lookup: Instant,
     /// constant 'K'
     k: u16,
 }
@@ -15,6 +26,9 @@ impl Bucket {
     pub fn new(k: u16) -> Self {
         Bucket {
             nodes: VecDeque::new(),
+            replacement_cache: VecDeque::new(),
+            probing: None,
+            last_lookup: Instant::now(),
             k: k,
         }
     }
@@ -29,6 +43,40 @@ impl Bucket {
         self.nodes.push_back(node.clone());
     }
 
+    /// Remove a node from the bucket.
+    pub fn remove_node(&mut self, id: &[u8]) -> Option<Arc<Mutex<Node>>> {
+        let index = self.index_of(id)?;
+        self.nodes.remove(index)
+    }
+
+    /// Move a node to the back (most recently seen).
+    pub fn touch(&mut self, id: &[u8]) {
+        if let Some(index) = self.index_of(id) {
+            let node = self.nodes.remove(index).unwrap();
+            self.nodes.push_back(node);
+        }
+    }
+
+    pub fn least_recently_seen(&self) -> Option<Arc<Mutex<Node>>> {
+        self.nodes.front().cloned()
+    }
+
+    /// Remember a node which did not fit in the bucket.
+    /// The oldest entry is dropped if the cache is full.
+    pub fn add_replacement(&mut self, peer: &PeerInfo) {
+        self.replacement_cache
+            .retain(|p| p.public_key != peer.public_key);
+        if self.replacement_cache.len() >= self.k as usize {
+            self.replacement_cache.pop_front();
+        }
+        self.replacement_cache.push_back(peer.clone());
+    }
+
+    /// Take the most recently seen replacement.
+    pub fn pop_replacement(&mut self) -> Option<PeerInfo> {
+        self.replacement_cache.pop_back()
+    }
+
     pub fn size(&self) -> usize {
         self.nodes.len()
     }
@@ -46,12 +94,59 @@ impl Bucket {
         }
         nodes
     }
+
+    fn index_of(&self, id: &[u8]) -> Option<usize> {
+        self.nodes.iter().position(|n| n.lock().unwrap().id == id)
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    /*
-        #[test]
-        fn bucket_test() {}
-    */
+    use super::Bucket;
+    use crate::message::PeerInfo;
+    use crate::route_table::node::Node;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+
+    fn peer(n: u8) -> PeerInfo {
+        PeerInfo::new(&SocketAddr::from(([127, 0, 0, 1], n as u16)), &[n; 32])
+    }
+
+    #[test]
+    fn bucket_lru_order() {
+        let mut bucket = Bucket::new(3);
+        let mut ids = Vec::new();
+        for n in 1..=3 {
+            let p = peer(n);
+            let node = Node::new(&p.public_key, &p.endpoint, 64);
+            ids.push(node.id.clone());
+            bucket.add_node(&Arc::new(Mutex::new(node)));
+        }
+        assert!(bucket.is_full());
+        let lrs = bucket.least_recently_seen().unwrap();
+        assert_eq!(lrs.lock().unwrap().id, ids[0]);
+
+        bucket.touch(&ids[0]);
+        let lrs = bucket.least_recently_seen().unwrap();
+        assert_eq!(lrs.lock().unwrap().id, ids[1]);
+
+        assert!(bucket.remove_node(&ids[1]).is_some());
+        assert!(bucket.remove_node(&ids[1]).is_none());
+        assert_eq!(bucket.size(), 2);
+    }
+
+    #[test]
+    fn bucket_replacement_cache() {
+        let mut bucket = Bucket::new(2);
+        bucket.add_replacement(&peer(1));
+        bucket.add_replacement(&peer(2));
+        //refresh
+        bucket.add_replacement(&peer(1));
+        //drops the oldest (2)
+        bucket.add_replacement(&peer(3));
+        assert_eq!(bucket.replacement_cache.len(), 2);
+        assert_eq!(bucket.pop_replacement(), Some(peer(3)));
+        assert_eq!(bucket.pop_replacement(), Some(peer(1)));
+        assert_eq!(bucket.pop_replacement(), None);
+    }
 }
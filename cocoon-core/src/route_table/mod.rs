@@ -1,21 +1,43 @@
 mod bucket;
 mod node;
+mod rtt;
+use crate::constant::{INITIAL_RTO, KEY_SIZE};
+use crate::identity::public_key_to_node_id;
+use crate::message::PeerInfo;
+use crate::utility;
+use anyhow::anyhow;
 use bucket::Bucket;
-pub use node::{calculate_bucket_index, endpoint_to_node_id, node_id_cmp, node_id_distance, Node};
+pub use node::{
+    calculate_bucket_index, distance_to_fraction, node_id_cmp, node_id_distance, Node, NodeInfo,
+};
+use openssl::rand::rand_bytes;
+pub use rtt::backoff;
+use rusqlite::{params, Connection};
 use std::collections::HashMap;
-use std::collections::HashSet;
 use std::sync::Mutex;
+use std::time::Duration;
 use std::{net::SocketAddr, sync::Arc};
+use tokio::time::Instant;
 use tracing::{event, span, Level};
 
 /// RouteTable
 /// RouteTable for DHTManager.
+/// The buckets are the leaves of a prefix tree of the ID space. Only the bucket covering
+/// our own ID is split, so the tree is a path along our ID and is kept as a vector:
+/// bucket i holds the nodes sharing exactly i leading bits with us,
+/// and the last one the nodes sharing at least as many.
+/// It starts with a single bucket, and the last bucket is split when it is full.
 pub struct RouteTable {
     k: u16,
+    /// Size of the node IDs in bytes, the first bytes of the hash of the public key.
+    id_size: usize,
     own_node: Node,
-    buckets: Vec<Bucket>, //TODO: maybe this isn't necessarily vector
+    buckets: Vec<Bucket>,
     /// Useful for checking whether a node is in the buckets or not.
-    node_map: HashMap<SocketAddr, Arc<Mutex<Node>>>,
+    /// Keyed by node ID.
+    node_map: HashMap<Vec<u8>, Arc<Mutex<Node>>>,
+    /// Node ID of the node at each endpoint, to find a node by the endpoint of a request.
+    endpoint_map: HashMap<SocketAddr, Vec<u8>>,
 }
 
 impl Drop for RouteTable {
@@ -25,141 +47,1039 @@ impl Drop for RouteTable {
 }
 
 impl RouteTable {
+    /// k is the bucket size, id_size the size of the node IDs in bytes (at most KEY_SIZE).
     #[must_use]
-    pub fn new(own_endpoint: &SocketAddr, k: u16, buckets_capacity: usize) -> Self {
-        //create buckets
-        let mut buckets = Vec::with_capacity(buckets_capacity);
-        for i in 0..buckets_capacity {
-            buckets.push(Bucket::new(k));
-        }
-
+    pub fn new(own_public_key: &[u8], own_endpoint: &SocketAddr, k: u16, id_size: usize) -> Self {
+        assert!(k > 0);
+        assert!(0 < id_size && id_size <= KEY_SIZE);
         RouteTable {
             k: k,
-            own_node: Node::new(own_endpoint),
-            buckets: buckets,
+            id_size: id_size,
+            own_node: Node::new(own_public_key, own_endpoint, id_size),
+            buckets: vec![Bucket::new(k)],
             node_map: HashMap::new(),
+            endpoint_map: HashMap::new(),
         }
     }
 
+    /// constant 'K'
+    #[must_use]
+    pub fn k(&self) -> u16 {
+        self.k
+    }
+
+    #[must_use]
+    pub fn own_endpoint(&self) -> SocketAddr {
+        self.own_node.endpoint
+    }
+
+    /// Change our endpoint, e.g. to the one other nodes see.
+    pub fn set_own_endpoint(&mut self, endpoint: &SocketAddr) {
+        self.own_node.endpoint = *endpoint;
+    }
+
+    #[must_use]
+    pub fn own_id(&self) -> &[u8] {
+        &self.own_node.id
+    }
+
+    /// Size of the node IDs in bytes.
     #[must_use]
-    pub fn contains(&self, endpoint: &SocketAddr) -> bool {
-        self.node_map.contains_key(endpoint)
+    pub fn id_size(&self) -> usize {
+        self.id_size
     }
 
+    /// Number of buckets, grows as the bucket covering our ID is split.
     #[must_use]
-    pub fn get_node_by_endpoint(&self, endpoint: &SocketAddr) -> Arc<Mutex<Node>> {
-        assert!(self.contains(endpoint));
-        let opt = self.node_map.get(&endpoint);
+    pub fn bucket_count(&self) -> usize {
+        self.buckets.len()
+    }
+
+    /// Node ID of the public key's owner in this route table.
+    #[must_use]
+    pub fn node_id(&self, public_key: &[u8]) -> Vec<u8> {
+        let mut id = public_key_to_node_id(public_key);
+        id.truncate(self.id_size);
+        id
+    }
+
+    /// Cut a full length ID (e.g. from public_key_to_node_id) to the ID size.
+    fn truncate<'a>(&self, id: &'a [u8]) -> &'a [u8] {
+        &id[..std::cmp::min(id.len(), self.id_size)]
+    }
+
+    /// Number of nodes in the buckets.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.node_map.len()
+    }
+
+    #[must_use]
+    pub fn contains(&self, id: &[u8]) -> bool {
+        self.node_map.contains_key(self.truncate(id))
+    }
+
+    #[must_use]
+    pub fn get_node(&self, id: &[u8]) -> Arc<Mutex<Node>> {
+        assert!(self.contains(id));
+        let opt = self.node_map.get(self.truncate(id));
         assert!(opt.is_some());
         opt.unwrap().clone()
     }
 
-    //
-    pub fn add_node(&mut self, node_endpoint: &SocketAddr) -> anyhow::Result<bool> {
+    /// Add a node, or update it if it is already in the route table.
+    /// The endpoint of a known node is replaced, since peers may move between networks.
+    /// Only for a node a signed datagram has just arrived from, see add_hearsay for the others.
+    /// Returns false if the bucket is full, Err if the public key is ours.
+    pub fn add_node(
+        &mut self,
+        public_key: &[u8],
+        node_endpoint: &SocketAddr,
+    ) -> anyhow::Result<bool> {
         event!(Level::DEBUG, "add node");
-        let new_node = Node::new(node_endpoint);
+        let new_node = Node::new(public_key, node_endpoint, self.id_size);
 
-        assert!(self.own_node != new_node);
+        if self.own_node == new_node {
+            return Err(anyhow!("{} uses our public key", node_endpoint));
+        }
 
-        if self.contains(node_endpoint) {
+        if self.contains(&new_node.id) {
             //already in route table
             //update status
-            event!(Level::ERROR, "{} is already in route table", node_endpoint);
-            let node = self.get_node_by_endpoint(node_endpoint);
+            let node = self.get_node(&new_node.id);
             {
                 //update node status
                 let mut node = node.lock().unwrap();
+                if node.endpoint != *node_endpoint {
+                    event!(
+                        Level::DEBUG,
+                        "{} moved from {} to {}",
+                        hex::encode(&node.id),
+                        node.endpoint,
+                        node_endpoint
+                    );
+                    if self.endpoint_map.get(&node.endpoint) == Some(&node.id) {
+                        self.endpoint_map.remove(&node.endpoint);
+                    }
+                    node.endpoint = *node_endpoint;
+                    self.endpoint_map.insert(*node_endpoint, node.id.clone());
+                }
                 node.update_alive();
             }
+            //most recently seen
+            self.find_bucket_mut_ref(&new_node.id).touch(&new_node.id);
             event!(Level::DEBUG, "Updated the status of {}", node_endpoint);
             return Ok(true);
         }
 
-        //find bucket for node
-        let bucket = self.find_bucket_mut_ref(&new_node.id);
+        Ok(self.insert_node(new_node))
+    }
 
-        let new_node = Arc::new(Mutex::new(new_node));
+    /// Add a node we heard of from another node (e.g. in a find node response).
+    /// Only the node itself can change its entry, so a known node is left as it is,
+    /// and an unknown one is added as never seen, to be pinged by the maintenance.
+    /// Returns false if the node is unknown and its bucket is full, Err if the public key is ours.
+    pub fn add_hearsay(&mut self, peer: &PeerInfo) -> anyhow::Result<bool> {
+        let new_node = Node::unverified(&peer.public_key, &peer.endpoint, self.id_size);
+        if self.own_node == new_node {
+            return Err(anyhow!("{} uses our public key", peer.endpoint));
+        }
+        if self.contains(&new_node.id) {
+            return Ok(true);
+        }
+        Ok(self.insert_node(new_node))
+    }
 
-        if bucket.is_full() {
-            return Ok(false);
+    /// Insert a node which is not in the route table yet.
+    /// The bucket covering our ID is split until the node fits or lands in another bucket.
+    /// Returns false if the bucket is full.
+    fn insert_node(&mut self, new_node: Node) -> bool {
+        let id = new_node.id.clone();
+        let endpoint = new_node.endpoint;
+        loop {
+            let index = self.bucket_index(&id);
+            if !self.buckets[index].is_full() {
+                break;
+            }
+            if index + 1 != self.buckets.len() || !self.split_last_bucket() {
+                return false;
+            }
         }
 
+        let new_node = Arc::new(Mutex::new(new_node));
         //add to bucket
-        bucket.add_node(&new_node);
+        self.find_bucket_mut_ref(&id).add_node(&new_node);
         //add to node map
-        self.node_map.insert(*node_endpoint, new_node);
-        Ok(true)
+        self.endpoint_map.insert(endpoint, id.clone());
+        self.node_map.insert(id, new_node);
+        true
+    }
+
+    /// Split the last bucket, which covers our ID, in two.
+    /// The nodes sharing one more bit with us move to the new last bucket.
+    /// Returns false if the bucket covers only our ID.
+    fn split_last_bucket(&mut self) -> bool {
+        let depth = self.buckets.len() - 1;
+        if depth + 1 >= self.id_size * 8 {
+            return false;
+        }
+        let own_id = self.own_node.id.clone();
+        let id_size = self.id_size;
+        let is_deeper = |id: &[u8]| calculate_bucket_index(&own_id, id) > depth;
+
+        let old = self.buckets.last_mut().unwrap();
+        let mut new = Bucket::new(self.k);
+        new.last_lookup = old.last_lookup;
+        //keep the least recently seen order
+        let (deeper, shallower): (Vec<_>, Vec<_>) = old
+            .nodes
+            .drain(..)
+            .partition(|node| is_deeper(&node.lock().unwrap().id));
+        old.nodes.extend(shallower);
+        new.nodes.extend(deeper);
+        let (deeper, shallower): (Vec<_>, Vec<_>) =
+            old.replacement_cache.drain(..).partition(|peer| {
+                let mut id = public_key_to_node_id(&peer.public_key);
+                id.truncate(id_size);
+                is_deeper(&id)
+            });
+        old.replacement_cache.extend(shallower);
+        new.replacement_cache.extend(deeper);
+        if let Some(probing) = &old.probing {
+            if is_deeper(probing) {
+                new.probing = old.probing.take();
+            }
+        }
+        self.buckets.push(new);
+        event!(
+            Level::DEBUG,
+            "Split the bucket {}, {} buckets",
+            depth,
+            self.buckets.len()
+        );
+        true
+    }
+
+    /// Record a request which the node did not answer.
+    pub fn record_failure(&mut self, id: &[u8]) {
+        if let Some(node) = self.node_map.get(self.truncate(id)) {
+            node.lock().unwrap().record_failure();
+        }
     }
 
+    /// Record the round trip time of a request which the node answered.
+    pub fn record_rtt(&mut self, id: &[u8], rtt: Duration) {
+        if let Some(node) = self.node_map.get(self.truncate(id)) {
+            node.lock().unwrap().rtt.update(rtt);
+        }
+    }
+
+    /// Retransmission timeout of the requests to the endpoint,
+    /// from the round trip time of the node there (INITIAL_RTO for an unknown node).
     #[must_use]
-    pub fn find_bucket(&self, id: &[u8]) -> &Bucket {
-        event!(Level::DEBUG, "Find bucket");
+    pub fn request_timeout(&self, endpoint: &SocketAddr) -> Duration {
+        match self
+            .endpoint_map
+            .get(endpoint)
+            .and_then(|id| self.node_map.get(id))
+        {
+            Some(node) => node.lock().unwrap().rtt.rto(),
+            None => INITIAL_RTO,
+        }
+    }
+
+    /// Remove a node from the buckets and the node map.
+    /// Returns false if the node is not in the route table.
+    pub fn remove_node(&mut self, id: &[u8]) -> bool {
+        let id = self.truncate(id);
+        let node = match self.node_map.remove(id) {
+            Some(node) => node,
+            None => return false,
+        };
+        let endpoint = node.lock().unwrap().endpoint;
+        if self.endpoint_map.get(&endpoint).map(|i| i.as_slice()) == Some(id) {
+            self.endpoint_map.remove(&endpoint);
+        }
+        let bucket = self.find_bucket_mut_ref(id);
+        let removed = bucket.remove_node(id);
+        debug_assert!(removed.is_some());
+        event!(
+            Level::DEBUG,
+            "Removed {} from the route table",
+            hex::encode(id)
+        );
+        true
+    }
 
-        let index = calculate_bucket_index(&self.own_node.id, &id);
+    /// Put a node, which did not fit in its bucket, to the bucket's replacement cache.
+    /// Returns the least recently seen node of the bucket if it should be pinged
+    /// to decide whether to evict it, None if the bucket is already probing one.
+    pub fn add_replacement(&mut self, peer: &PeerInfo) -> Option<PeerInfo> {
+        let id = self.node_id(&peer.public_key);
+        let bucket = self.find_bucket_mut_ref(&id);
+        bucket.add_replacement(peer);
+        if bucket.probing.is_some() {
+            return None;
+        }
+        let lrs = bucket.least_recently_seen()?;
+        let lrs = lrs.lock().unwrap();
+        bucket.probing = Some(lrs.id.clone());
+        Some(lrs.peer_info())
+    }
 
-        event!(Level::DEBUG, "bucket index {}", index);
+    /// Finish probing the least recently seen node of a bucket.
+    /// If the node did not respond, it is evicted and replaced with the most recently seen
+    /// replacement. Returns the inserted node.
+    pub fn finish_probe(&mut self, probed_id: &[u8], is_alive: bool) -> Option<PeerInfo> {
+        let probed_id = self.truncate(probed_id);
+        {
+            let bucket = self.find_bucket_mut_ref(probed_id);
+            if bucket.probing.as_deref() == Some(probed_id) {
+                bucket.probing = None;
+            }
+        }
+        if is_alive {
+            return None;
+        }
+        event!(
+            Level::DEBUG,
+            "{} did not respond, evict",
+            hex::encode(probed_id)
+        );
+        self.replace_node(probed_id)
+    }
 
-        if self.buckets.len() <= index {
-            panic!("Logic error, need bigger bucket vector\ncalculated bucket index {}\ncurrent bucket vector capacity {}", index,self.buckets.len());
+    /// Remove a node and fill its place with the most recently seen replacement
+    /// of the bucket. Returns the inserted node.
+    fn replace_node(&mut self, id: &[u8]) -> Option<PeerInfo> {
+        self.remove_node(id);
+        loop {
+            let replacement = self.find_bucket_mut_ref(id).pop_replacement()?;
+            if self.contains(&self.node_id(&replacement.public_key)) {
+                continue;
+            }
+            //the replacements may be hearsay
+            if self.add_hearsay(&replacement).unwrap_or(false) {
+                return Some(replacement);
+            }
         }
-        assert!(0 as usize <= index && index < self.buckets.len());
+    }
 
-        &self.buckets[index]
+    /// Remove the nodes which failed max_failures requests in a row,
+    /// replacing them from the replacement caches. Returns the removed nodes.
+    pub fn remove_failed_nodes(&mut self, max_failures: u32) -> Vec<PeerInfo> {
+        let failed: Vec<PeerInfo> = self
+            .node_map
+            .values()
+            .map(|node| node.lock().unwrap())
+            .filter(|node| node.failure_count >= max_failures)
+            .map(|node| node.peer_info())
+            .collect();
+        for peer in &failed {
+            event!(
+                Level::DEBUG,
+                "{} failed {} requests, remove",
+                peer.endpoint,
+                max_failures
+            );
+            let id = self.node_id(&peer.public_key);
+            self.replace_node(&id);
+        }
+        failed
     }
 
+    /// Nodes which have not been seen for the timeout.
     #[must_use]
-    pub fn find_bucket_mut_ref(&mut self, id: &[u8]) -> &mut Bucket {
-        event!(Level::DEBUG, "Find bucket");
-        let index = calculate_bucket_index(&self.own_node.id, &id);
-        event!(Level::DEBUG, "bucket index {}", index);
-        if self.buckets.len() <= index {
-            panic!("Logic error, need bigger bucket vector\ncalculated bucket index {}\ncurrent bucket vector capacity {}", index,self.buckets.len());
+    pub fn quiet_nodes(&self, quiet_timeout: Duration) -> Vec<PeerInfo> {
+        self.node_map
+            .values()
+            .map(|node| node.lock().unwrap())
+            .filter(|node| node.is_quiet(quiet_timeout))
+            .map(|node| node.peer_info())
+            .collect()
+    }
+
+    /// Index of the bucket covering the ID.
+    /// Never out of range, the last bucket covers our own ID and the IDs close to it.
+    fn bucket_index(&self, id: &[u8]) -> usize {
+        let index = calculate_bucket_index(&self.own_node.id, self.truncate(id));
+        std::cmp::min(index, self.buckets.len() - 1)
+    }
+
+    /// Record a lookup of the key, the bucket of the key does not need a refresh.
+    pub fn mark_lookup(&mut self, key: &[u8], now: Instant) {
+        let index = self.bucket_index(key);
+        self.buckets[index].last_lookup = now;
+    }
+
+    /// Indices of the buckets without a lookup for the interval.
+    /// Nothing to refresh before we know a node to ask.
+    #[must_use]
+    pub fn buckets_to_refresh(&self, now: Instant, interval: Duration) -> Vec<usize> {
+        if self.node_map.is_empty() {
+            return Vec::new();
+        }
+        (0..self.buckets.len())
+            .filter(|i| now.duration_since(self.buckets[*i].last_lookup) >= interval)
+            .collect()
+    }
+
+    /// Random key (of KEY_SIZE) in the range of the bucket.
+    #[must_use]
+    pub fn random_id_in_bucket(&self, index: usize) -> Vec<u8> {
+        let own_id = &self.own_node.id;
+        assert!(index < self.buckets.len());
+        //the distance has exactly 'index' leading zeros
+        let mut distance = vec![0; KEY_SIZE];
+        rand_bytes(&mut distance).expect("Failed to generate a random number");
+        for bit in 0..=index {
+            let mask = 0x80 >> (bit % 8);
+            if bit == index {
+                distance[bit / 8] |= mask;
+            } else {
+                distance[bit / 8] &= !mask;
+            }
         }
-        assert!(0 as usize <= index && index < self.buckets.len());
+        node_id_distance(own_id, &distance)
+    }
+
+    #[must_use]
+    pub fn find_bucket(&self, id: &[u8]) -> &Bucket {
+        &self.buckets[self.bucket_index(id)]
+    }
 
+    #[must_use]
+    pub fn find_bucket_mut_ref(&mut self, id: &[u8]) -> &mut Bucket {
+        let index = self.bucket_index(id);
         &mut self.buckets[index]
     }
 
+    /// Find the desired_count closest nodes to the ID, ordered by XOR distance.
+    /// The nodes in the ID's bucket are closer than the nodes in the deeper buckets,
+    /// which are closer than the nodes in the shallower buckets, nearest first.
+    /// Buckets are gathered in that order until there are enough candidates.
     #[must_use]
     pub fn find_nodes(&self, id: &[u8], desired_count: usize) -> Vec<Arc<Mutex<Node>>> {
-        debug_assert!(id.len() != 0);
-        let bucket = self.find_bucket(&id);
-        bucket.select_nodes(desired_count)
-        //todo if nodes.len() < desired_count
-        //maybe gather from other buckets
+        let index = self.bucket_index(id);
+        let shallower = (0..index).rev();
+        let mut candidates = Vec::new();
+        for i in (index..self.buckets.len()).chain(shallower) {
+            if candidates.len() >= desired_count && i < index {
+                break;
+            }
+            let bucket = &self.buckets[i];
+            candidates.extend(bucket.select_nodes(bucket.size()));
+        }
+
+        let mut candidates: Vec<(Vec<u8>, Arc<Mutex<Node>>)> = candidates
+            .into_iter()
+            .map(|node| {
+                let distance = node_id_distance(&node.lock().unwrap().id, id);
+                (distance, node)
+            })
+            .collect();
+        //big endian, the lexicographic order is the numeric order
+        candidates.sort_by(|(lhs, _), (rhs, _)| lhs.cmp(rhs));
+        candidates.truncate(desired_count);
+        candidates.into_iter().map(|(_, node)| node).collect()
+    }
+
+    /// Select up to n nodes at random from all buckets.
+    /// Used for the random walk phase of R5N routing.
+    #[must_use]
+    pub fn random_nodes(&self, n: usize) -> Vec<Arc<Mutex<Node>>> {
+        let mut nodes: Vec<Arc<Mutex<Node>>> = self.node_map.values().cloned().collect();
+        let n = std::cmp::min(n, nodes.len());
+        //partial fisher-yates shuffle
+        for i in 0..n {
+            let j = i + utility::random_index(nodes.len() - i);
+            nodes.swap(i, j);
+        }
+        nodes.truncate(n);
+        nodes
     }
 
-    //todo write test
+    /// Returns true if no known node is closer to the ID than we are.
     #[must_use]
     pub fn is_closest_to(&self, id: &[u8]) -> bool {
-        let bucket = self.find_bucket(&id);
-        for node in bucket.select_nodes(bucket.size()) {
+        let closest = match self.find_nodes(id, 1).pop() {
+            Some(closest) => closest,
+            None => return true,
+        };
+        let closest = closest.lock().unwrap();
+        let d1 = node_id_distance(&self.own_node.id, id);
+        let d2 = node_id_distance(&closest.id, id);
+        !node_id_cmp(&d2, &d1) //false if d2 < d1
+    }
+
+    #[must_use]
+    pub fn is_space_available_for(&self, id: &[u8]) -> bool {
+        let bucket = self.find_bucket(id);
+        !bucket.is_full()
+    }
+
+    /// Estimate the number of nodes in the network (including ourselves).
+    /// With N nodes spread uniformly over the keyspace, the i-th closest node to us
+    /// is expected at i/N of the keyspace. The K closest known nodes are fitted to
+    /// that by least squares, which gives N = sum(i^2) / sum(i * d_i).
+    /// Only the verified nodes count, anyone can make up the nodes they tell us of.
+    /// Never less than the number of verified nodes.
+    #[must_use]
+    pub fn estimate_network_size(&self) -> usize {
+        let mut distances: Vec<f64> = self
+            .node_map
+            .iter()
+            .filter(|(_, node)| node.lock().unwrap().is_verified())
+            .map(|(id, _)| distance_to_fraction(&node_id_distance(&self.own_node.id, id)))
+            .collect();
+        let known = distances.len() + 1;
+        distances.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        distances.truncate(self.k as usize);
+
+        let mut numerator = 0.0;
+        let mut denominator = 0.0;
+        for (i, distance) in distances.iter().enumerate() {
+            let rank = (i + 1) as f64;
+            numerator += rank * rank;
+            denominator += rank * distance;
+        }
+        if denominator == 0.0 {
+            return known;
+        }
+        let estimate = (numerator / denominator).round();
+        if estimate >= usize::MAX as f64 {
+            return usize::MAX;
+        }
+        std::cmp::max(estimate as usize, known)
+    }
+
+    /// Save all nodes in the buckets to the sqlite database.
+    /// Previously saved nodes are replaced.
+    pub fn save(&self, db: &mut Connection) -> anyhow::Result<usize> {
+        create_nodes_table(db)?;
+        let tx = db.transaction()?;
+        tx.execute("DELETE FROM route_table_nodes", [])?;
+        let mut count = 0;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT INTO route_table_nodes (node_id, public_key, endpoint, last_seen, failure_count) VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            for bucket in &self.buckets {
+                for node in &bucket.nodes {
+                    let node_info = node.lock().unwrap().info();
+                    stmt.execute(params![
+                        node_info.id,
+                        node_info.public_key,
+                        node_info.endpoint_string,
+                        node_info.last_seen as i64,
+                        node_info.failure_count
+                    ])?;
+                    count += 1;
+                }
+            }
+        }
+        tx.commit()?;
+        event!(Level::DEBUG, "Saved {} nodes", count);
+        Ok(count)
+    }
+
+    /// Load the nodes saved by save().
+    /// Nodes which are already known or do not fit in their bucket are skipped.
+    /// Returns the number of loaded nodes.
+    pub fn load(&mut self, db: &Connection) -> anyhow::Result<usize> {
+        create_nodes_table(db)?;
+        let mut stmt = db.prepare(
+            "SELECT node_id, public_key, endpoint, last_seen, failure_count FROM route_table_nodes ORDER BY last_seen ASC",
+        )?;
+        let infos = stmt
+            .query_map([], |row| {
+                Ok(NodeInfo {
+                    id: row.get(0)?,
+                    public_key: row.get(1)?,
+                    endpoint_string: row.get(2)?,
+                    last_seen: row.get::<_, i64>(3)? as u64,
+                    failure_count: row.get(4)?,
+                })
+            })?
+            .collect::<Result<Vec<NodeInfo>, _>>()?;
+
+        let mut count = 0;
+        for info in &infos {
+            let node = match Node::from_info(info) {
+                Ok(node) => node,
+                Err(e) => {
+                    event!(
+                        Level::WARN,
+                        "Skip malformed saved node {}: {}",
+                        info.endpoint_string,
+                        e
+                    );
+                    continue;
+                }
+            };
+            if node.id != self.node_id(&node.public_key) {
+                event!(
+                    Level::WARN,
+                    "Skip saved node {} with wrong id",
+                    node.endpoint
+                );
+                continue;
+            }
+            if node == self.own_node || self.contains(&node.id) {
+                continue;
+            }
+            //oldest first, so the buckets keep the least recently seen order
+            if self.insert_node(node) {
+                count += 1;
+            }
+        }
+        event!(Level::DEBUG, "Loaded {} nodes", count);
+        Ok(count)
+    }
+}
+
+fn create_nodes_table(db: &Connection) -> anyhow::Result<()> {
+    db.execute(
+        "CREATE TABLE IF NOT EXISTS route_table_nodes (
+            node_id BLOB PRIMARY KEY,
+            public_key BLOB NOT NULL,
+            endpoint TEXT NOT NULL,
+            last_seen INTEGER NOT NULL,
+            failure_count INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{calculate_bucket_index, node_id_distance, RouteTable};
+    use crate::constant::{INITIAL_RTO, KEY_SIZE};
+    use crate::identity::public_key_to_node_id;
+    use crate::message::PeerInfo;
+    use openssl::hash::{hash, MessageDigest};
+    use openssl::rand::rand_bytes;
+    use rusqlite::Connection;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    const OWN_PUBLIC_KEY: [u8; 32] = [0x42; 32];
+
+    fn own_endpoint() -> SocketAddr {
+        "127.0.0.1:9999".parse().unwrap()
+    }
+
+    /// Generate n peers which fall into the same bucket.
+    fn same_bucket_peers(n: usize) -> Vec<PeerInfo> {
+        let own_id = public_key_to_node_id(&OWN_PUBLIC_KEY);
+        let mut peers = Vec::new();
+        let mut port = 10000;
+        while peers.len() < n {
+            let mut public_key = vec![0; 32];
+            rand_bytes(&mut public_key).unwrap();
+            if calculate_bucket_index(&own_id, &public_key_to_node_id(&public_key)) == 0 {
+                peers.push(PeerInfo::new(
+                    &SocketAddr::from(([127, 0, 0, 1], port)),
+                    &public_key,
+                ));
+                port += 1;
+            }
+        }
+        peers
+    }
+
+    fn id(peer: &PeerInfo) -> Vec<u8> {
+        public_key_to_node_id(&peer.public_key)
+    }
+
+    fn add(rt: &mut RouteTable, peer: &PeerInfo) -> bool {
+        rt.add_node(&peer.public_key, &peer.endpoint).unwrap()
+    }
+
+    #[test]
+    fn evict_dead_node() {
+        let peers = same_bucket_peers(4);
+        let mut rt = RouteTable::new(&OWN_PUBLIC_KEY, &own_endpoint(), 2, 64);
+        assert!(add(&mut rt, &peers[0]));
+        assert!(add(&mut rt, &peers[1]));
+        //full
+        assert!(!add(&mut rt, &peers[2]));
+
+        //probe the least recently seen one
+        assert_eq!(rt.add_replacement(&peers[2]), Some(peers[0].clone()));
+        //already probing
+        assert_eq!(rt.add_replacement(&peers[3]), None);
+
+        //peers[0] did not respond, replaced with the most recent replacement
+        assert_eq!(
+            rt.finish_probe(&id(&peers[0]), false),
+            Some(peers[3].clone())
+        );
+        assert!(!rt.contains(&id(&peers[0])));
+        assert!(rt.contains(&id(&peers[1])));
+        assert!(rt.contains(&id(&peers[3])));
+        assert!(!rt.contains(&id(&peers[2])));
+    }
+
+    #[test]
+    fn keep_alive_node() {
+        let peers = same_bucket_peers(3);
+        let mut rt = RouteTable::new(&OWN_PUBLIC_KEY, &own_endpoint(), 2, 64);
+        add(&mut rt, &peers[0]);
+        add(&mut rt, &peers[1]);
+        //seen again, peers[1] becomes the least recently seen
+        add(&mut rt, &peers[0]);
+
+        assert_eq!(rt.add_replacement(&peers[2]), Some(peers[1].clone()));
+        assert_eq!(rt.finish_probe(&id(&peers[1]), true), None);
+        assert!(rt.contains(&id(&peers[0])));
+        assert!(rt.contains(&id(&peers[1])));
+        assert!(!rt.contains(&id(&peers[2])));
+    }
+
+    #[test]
+    fn identity_keyed_node() {
+        let peers = same_bucket_peers(1);
+        let mut rt = RouteTable::new(&OWN_PUBLIC_KEY, &own_endpoint(), 20, 64);
+        assert!(add(&mut rt, &peers[0]));
+
+        //same key from another endpoint is the same node
+        let moved: SocketAddr = "192.168.0.2:4000".parse().unwrap();
+        assert!(rt.add_node(&peers[0].public_key, &moved).unwrap());
+        assert_eq!(rt.len(), 1);
+        let node = rt.get_node(&id(&peers[0]));
+        assert_eq!(node.lock().unwrap().endpoint, moved);
+
+        //our own key is rejected
+        assert!(rt.add_node(&OWN_PUBLIC_KEY, &moved).is_err());
+    }
+
+    #[test]
+    fn hearsay_node() {
+        let peers = same_bucket_peers(2);
+        let mut rt = RouteTable::new(&OWN_PUBLIC_KEY, &own_endpoint(), 20, 64);
+        assert!(add(&mut rt, &peers[0]));
+        rt.record_failure(&id(&peers[0]));
+
+        //someone else claims the known node is elsewhere
+        let hijacked = PeerInfo::new(&"192.168.0.2:4000".parse().unwrap(), &peers[0].public_key);
+        assert!(rt.add_hearsay(&hijacked).unwrap());
+        {
+            let node = rt.get_node(&id(&peers[0]));
             let node = node.lock().unwrap();
-            let d1 = node_id_distance(&self.own_node.id, &id);
-            let d2 = node_id_distance(&node.id, &id);
-            let not_closest = node_id_cmp(&d2, &d1); //true if d2 < d1
-            if not_closest {
-                return false;
+            assert_eq!(node.endpoint, peers[0].endpoint);
+            assert_eq!(node.failure_count, 1);
+        }
+
+        //an unknown node is added, to be pinged
+        assert!(rt.add_hearsay(&peers[1]).unwrap());
+        assert!(rt.contains(&id(&peers[1])));
+        assert_eq!(
+            rt.quiet_nodes(Duration::from_secs(60)),
+            vec![peers[1].clone()]
+        );
+        assert!(rt
+            .add_hearsay(&PeerInfo::new(&own_endpoint(), &OWN_PUBLIC_KEY))
+            .is_err());
+    }
+
+    #[test]
+    fn save_and_load() {
+        let peers = same_bucket_peers(3);
+        let mut db = Connection::open_in_memory().unwrap();
+
+        let mut rt = RouteTable::new(&OWN_PUBLIC_KEY, &own_endpoint(), 20, 64);
+        for peer in &peers {
+            add(&mut rt, peer);
+        }
+        rt.record_failure(&id(&peers[1]));
+        assert_eq!(rt.save(&mut db).unwrap(), 3);
+        //saving again replaces the old rows
+        rt.remove_node(&id(&peers[2]));
+        assert_eq!(rt.save(&mut db).unwrap(), 2);
+
+        let mut loaded = RouteTable::new(&OWN_PUBLIC_KEY, &own_endpoint(), 20, 64);
+        assert_eq!(loaded.load(&db).unwrap(), 2);
+        assert!(loaded.contains(&id(&peers[0])));
+        assert!(loaded.contains(&id(&peers[1])));
+        assert!(!loaded.contains(&id(&peers[2])));
+        let node = loaded.get_node(&id(&peers[1]));
+        assert_eq!(node.lock().unwrap().failure_count, 1);
+        assert_eq!(node.lock().unwrap().public_key, peers[1].public_key);
+
+        //empty database
+        let mut empty = RouteTable::new(&OWN_PUBLIC_KEY, &own_endpoint(), 20, 64);
+        assert_eq!(
+            empty.load(&Connection::open_in_memory().unwrap()).unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn estimate_network_size() {
+        let mut rt = RouteTable::new(&OWN_PUBLIC_KEY, &own_endpoint(), 20, 64);
+        //only ourselves
+        assert_eq!(rt.estimate_network_size(), 1);
+
+        //deterministic keys, spread uniformly over the keyspace
+        let network_size = 2000;
+        for i in 0..network_size {
+            let public_key = hash(MessageDigest::sha3_256(), &(i as u32).to_le_bytes()).unwrap();
+            add(&mut rt, &PeerInfo::new(&own_endpoint(), &public_key));
+        }
+        //far buckets are full, most of the network is unknown
+        assert!(rt.len() < network_size);
+        let estimate = rt.estimate_network_size();
+        assert!(
+            network_size / 2 <= estimate && estimate <= network_size * 2,
+            "estimate {}",
+            estimate
+        );
+
+        //the nodes we have only heard of fill the close buckets, but do not count
+        let len = rt.len();
+        for i in network_size..network_size * 20 {
+            let public_key = hash(MessageDigest::sha3_256(), &(i as u32).to_le_bytes()).unwrap();
+            rt.add_hearsay(&PeerInfo::new(&own_endpoint(), &public_key))
+                .unwrap();
+        }
+        assert!(rt.len() > len);
+        assert_eq!(rt.estimate_network_size(), estimate);
+    }
+
+    #[test]
+    fn find_closest_nodes() {
+        let mut rt = RouteTable::new(&OWN_PUBLIC_KEY, &own_endpoint(), 20, 64);
+        assert!(rt.find_nodes(rt.own_id(), 20).is_empty());
+        let mut ids = Vec::new();
+        for i in 0..300 {
+            let public_key = hash(MessageDigest::sha3_256(), &(i as u32).to_le_bytes()).unwrap();
+            if add(&mut rt, &PeerInfo::new(&own_endpoint(), &public_key)) {
+                ids.push(public_key_to_node_id(&public_key));
             }
         }
-        return true;
+        assert_eq!(ids.len(), rt.len());
+
+        let own_id = rt.own_id().to_vec();
+        let mut keys = vec![own_id.clone()];
+        for n in [0, 1, 5, 9] {
+            //shares n bits with us
+            let mut key = own_id.clone();
+            key[n / 8] ^= 0x80 >> (n % 8);
+            keys.push(key);
+        }
+        for key in &keys {
+            //every node sorted by the distance
+            let mut expected = ids.clone();
+            expected.sort_by_key(|id| node_id_distance(id, key));
+            expected.truncate(20);
+            let found: Vec<Vec<u8>> = rt
+                .find_nodes(key, 20)
+                .iter()
+                .map(|node| node.lock().unwrap().id.clone())
+                .collect();
+            assert_eq!(found, expected);
+
+            let own_distance = node_id_distance(&own_id, key);
+            let is_closest = node_id_distance(&expected[0], key) > own_distance;
+            assert_eq!(rt.is_closest_to(key), is_closest);
+        }
+        assert!(rt.is_closest_to(&own_id));
+        //the key of a node is closest to the node
+        assert!(!rt.is_closest_to(&ids[0]));
+        assert_eq!(rt.find_nodes(&own_id, 1000).len(), ids.len());
     }
 
-    #[must_use]
-    pub fn is_space_available_for(&self, endpoint: &SocketAddr) -> bool {
-        let id = endpoint_to_node_id(&endpoint);
-        let bucket = self.find_bucket(&id);
-        !bucket.is_full()
+    #[test]
+    fn refresh_buckets() {
+        let mut rt = RouteTable::new(&OWN_PUBLIC_KEY, &own_endpoint(), 1, 64);
+        let now = Instant::now();
+        //nothing to refresh without nodes
+        assert!(rt.buckets_to_refresh(now, Duration::ZERO).is_empty());
+
+        let peers = same_bucket_peers(1);
+        add(&mut rt, &peers[0]);
+        assert_eq!(rt.buckets_to_refresh(now, Duration::ZERO), vec![0]);
+        assert!(rt
+            .buckets_to_refresh(now, Duration::from_secs(60))
+            .is_empty());
+
+        //a node in bucket 3 splits the only bucket
+        let own_id = rt.own_id().to_vec();
+        let mut public_key = vec![0; 32];
+        loop {
+            rand_bytes(&mut public_key).unwrap();
+            if calculate_bucket_index(&own_id, &public_key_to_node_id(&public_key)) == 3 {
+                break;
+            }
+        }
+        assert!(add(&mut rt, &PeerInfo::new(&own_endpoint(), &public_key)));
+        assert_eq!(rt.bucket_count(), 2);
+        let id = rt.random_id_in_bucket(1);
+        assert_eq!(calculate_bucket_index(&own_id, &id), 1);
+
+        //a lookup in bucket 1
+        let later = now + Duration::from_secs(60);
+        rt.mark_lookup(&id, later);
+        assert_eq!(
+            rt.buckets_to_refresh(later, Duration::from_secs(60)),
+            vec![0]
+        );
+    }
+
+    /// Add the nodes of n deterministic keys, returns the IDs of the added ones.
+    fn add_small_ids(rt: &mut RouteTable, n: u32) -> Vec<Vec<u8>> {
+        let mut ids = Vec::new();
+        for i in 0..n {
+            let public_key = hash(MessageDigest::sha3_256(), &i.to_le_bytes()).unwrap();
+            let id = rt.node_id(&public_key);
+            //small IDs collide, with ours too
+            if ids.contains(&id) {
+                continue;
+            }
+            if rt.add_node(&public_key, &own_endpoint()).unwrap_or(false) {
+                ids.push(id);
+            }
+        }
+        ids
     }
 
-    pub fn save(&self) {
-        for bucket in &self.buckets {
+    #[test]
+    fn split_buckets() {
+        let mut rt = RouteTable::new(&OWN_PUBLIC_KEY, &own_endpoint(), 2, 1);
+        assert_eq!(rt.bucket_count(), 1);
+        let ids = add_small_ids(&mut rt, 1000);
+        assert_eq!(ids.len(), rt.len());
+        //the bucket covering our ID was split, up to one bucket per bit
+        assert!(rt.bucket_count() > 1);
+        assert!(rt.bucket_count() <= 8);
+        let own_id = rt.own_id().to_vec();
+        let last = rt.bucket_count() - 1;
+        for (i, bucket) in rt.buckets.iter().enumerate() {
+            assert!(bucket.nodes.len() <= 2);
             for node in &bucket.nodes {
-                let node = node.lock().unwrap();
-                let node_info = node.info();
-                //TODO
-                event!(Level::WARN, "TODO code route tabhle save");
+                let shared = calculate_bucket_index(&own_id, &node.lock().unwrap().id);
+                if i == last {
+                    assert!(shared >= i);
+                } else {
+                    assert_eq!(shared, i);
+                }
             }
         }
+        //every bucket but the last is full with that many keys
+        assert!(rt.buckets[..last].iter().all(|bucket| bucket.is_full()));
+    }
+
+    #[test]
+    fn small_ids() {
+        let mut rt = RouteTable::new(&OWN_PUBLIC_KEY, &own_endpoint(), 3, 2);
+        let ids = add_small_ids(&mut rt, 2000);
+        for id in &ids {
+            assert_eq!(id.len(), 2);
+        }
+        for key in [
+            vec![0, 0],
+            vec![0xff, 0xff],
+            vec![0x12, 0x34],
+            rt.own_id().to_vec(),
+        ] {
+            let mut expected = ids.clone();
+            expected.sort_by_key(|id| node_id_distance(id, &key));
+            expected.truncate(3);
+            let found: Vec<Vec<u8>> = rt
+                .find_nodes(&key, 3)
+                .iter()
+                .map(|node| node.lock().unwrap().id.clone())
+                .collect();
+            assert_eq!(found, expected);
+        }
+
+        //lookup keys are full length
+        let last = rt.bucket_count() - 1;
+        assert_eq!(rt.random_id_in_bucket(last).len(), KEY_SIZE);
+
+        //keys of other sizes do not panic
+        let full_key = public_key_to_node_id(&[7; 32]);
+        assert_eq!(rt.find_nodes(&full_key, 3).len(), 3);
+        assert_eq!(rt.find_nodes(&[], 3).len(), 3);
+        let _ = rt.is_closest_to(&[]);
+        let _ = rt.is_closest_to(&full_key);
+        assert!(!rt.contains(&[]));
+        assert!(!rt.remove_node(&[1]));
+        rt.record_failure(&[]);
+        rt.mark_lookup(&[], Instant::now());
+        //full length IDs are cut to the ID size
+        let public_key = rt.get_node(&ids[0]).lock().unwrap().public_key.clone();
+        assert!(rt.contains(&public_key_to_node_id(&public_key)));
+    }
+
+    #[test]
+    fn remove_failed_nodes() {
+        let peers = same_bucket_peers(4);
+        let mut rt = RouteTable::new(&OWN_PUBLIC_KEY, &own_endpoint(), 2, 64);
+        add(&mut rt, &peers[0]);
+        add(&mut rt, &peers[1]);
+        rt.add_replacement(&peers[2]);
+        for _ in 0..3 {
+            rt.record_failure(&id(&peers[0]));
+        }
+        rt.record_failure(&id(&peers[1]));
+        assert_eq!(rt.quiet_nodes(Duration::ZERO).len(), 2);
+        assert!(rt.quiet_nodes(Duration::from_secs(60)).is_empty());
+
+        assert_eq!(rt.remove_failed_nodes(3), vec![peers[0].clone()]);
+        assert!(!rt.contains(&id(&peers[0])));
+        assert!(rt.contains(&id(&peers[1])));
+        //replaced
+        assert!(rt.contains(&id(&peers[2])));
+        assert!(rt.remove_failed_nodes(3).is_empty());
+    }
+
+    #[test]
+    fn request_timeout() {
+        let peers = same_bucket_peers(1);
+        let mut rt = RouteTable::new(&OWN_PUBLIC_KEY, &own_endpoint(), 20, 64);
+        add(&mut rt, &peers[0]);
+        //not measured yet
+        assert_eq!(rt.request_timeout(&peers[0].endpoint), INITIAL_RTO);
+
+        rt.record_rtt(&id(&peers[0]), Duration::from_millis(100));
+        assert_eq!(
+            rt.request_timeout(&peers[0].endpoint),
+            Duration::from_millis(300)
+        );
+        //unknown endpoint
+        assert_eq!(rt.request_timeout(&own_endpoint()), INITIAL_RTO);
+
+        //the node moved
+        let moved: SocketAddr = "192.168.0.2:4000".parse().unwrap();
+        rt.add_node(&peers[0].public_key, &moved).unwrap();
+        assert_eq!(rt.request_timeout(&peers[0].endpoint), INITIAL_RTO);
+        assert_eq!(rt.request_timeout(&moved), Duration::from_millis(300));
+        rt.remove_node(&id(&peers[0]));
+        assert_eq!(rt.request_timeout(&moved), INITIAL_RTO);
+    }
+
+    #[test]
+    fn random_nodes() {
+        let peers = same_bucket_peers(5);
+        let mut rt = RouteTable::new(&OWN_PUBLIC_KEY, &own_endpoint(), 20, 64);
+        assert!(rt.random_nodes(3).is_empty());
+        for peer in &peers {
+            add(&mut rt, peer);
+        }
+        let mut ids: Vec<Vec<u8>> = rt
+            .random_nodes(3)
+            .iter()
+            .map(|node| node.lock().unwrap().id.clone())
+            .collect();
+        assert_eq!(ids.len(), 3);
+        ids.dedup();
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), 3);
+        assert_eq!(rt.random_nodes(10).len(), 5);
     }
 }
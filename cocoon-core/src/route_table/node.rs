@@ -1,57 +1,123 @@
-use openssl::hash::{hash, MessageDigest};
+use crate::identity::public_key_to_node_id;
+use crate::message::PeerInfo;
+use crate::route_table::rtt::RttEstimator;
 use std::cmp::max;
 use std::fmt;
 use std::mem::size_of;
 use std::net::SocketAddr;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tracing::{event, span, Level};
+
+/// Persistent part of a Node.
 pub struct NodeInfo {
-    endpoint_string: String,
+    pub endpoint_string: String,
+    pub id: Vec<u8>,
+    pub public_key: Vec<u8>,
+    /// Seconds since UNIX_EPOCH.
+    pub last_seen: u64,
+    pub failure_count: u32,
 }
 
 /// Node
 /// Represents a node on p2p network.
 pub struct Node {
     /// Node's ID.
-    /// Currently it's generated by hashing IP address.
+    /// Hash of the public key.
     pub id: Vec<u8>,
+    pub public_key: Vec<u8>,
     /// Node's endpoint(IPAddress).
+    /// May change, the node is identified by the ID.
     pub endpoint: SocketAddr,
     last_ping: SystemTime,
+    /// Number of requests the node failed to answer since it was last seen.
+    pub failure_count: u32,
+    /// Round trip time of the requests, not saved.
+    pub rtt: RttEstimator,
 }
 
 impl Node {
-    pub fn new(sock_addr: &SocketAddr) -> Self {
-        let node_id = endpoint_to_node_id(sock_addr);
+    /// The ID is the first id_size bytes of the hash of the public key.
+    pub fn new(public_key: &[u8], sock_addr: &SocketAddr, id_size: usize) -> Self {
+        let mut node_id = public_key_to_node_id(public_key);
+        node_id.truncate(id_size);
         event!(
             Level::DEBUG,
-            "SockAddr {}, hash {}",
+            "SockAddr {}, id {}",
             sock_addr,
             hex::encode(&node_id)
         );
         Node {
             id: node_id,
+            public_key: public_key.to_vec(),
             endpoint: sock_addr.to_owned(),
             last_ping: SystemTime::now(),
+            failure_count: 0,
+            rtt: RttEstimator::new(),
         }
     }
 
+    /// A node we have only heard of from other nodes.
+    /// It has never been seen, so it is quiet until it answers a ping.
+    pub fn unverified(public_key: &[u8], sock_addr: &SocketAddr, id_size: usize) -> Self {
+        let mut node = Node::new(public_key, sock_addr, id_size);
+        node.last_ping = UNIX_EPOCH;
+        node
+    }
+
+    /// Restore a node from the saved info.
+    pub fn from_info(info: &NodeInfo) -> anyhow::Result<Self> {
+        let endpoint: SocketAddr = info.endpoint_string.parse()?;
+        Ok(Node {
+            id: info.id.clone(),
+            public_key: info.public_key.clone(),
+            endpoint: endpoint,
+            last_ping: UNIX_EPOCH + Duration::from_secs(info.last_seen),
+            failure_count: info.failure_count,
+            rtt: RttEstimator::new(),
+        })
+    }
+
     pub fn update_alive(&mut self) {
         self.last_ping = SystemTime::now();
+        self.failure_count = 0;
     }
 
-    pub fn is_alive(&self) -> bool {
-        let one_min = Duration::from_secs(60); //TODO: for now 1 min, examine and change
-        if self.last_ping.elapsed().unwrap() > one_min {
-            //dead
-            return false;
-        }
-        true
+    /// Record a request which the node did not answer.
+    pub fn record_failure(&mut self) {
+        self.failure_count = self.failure_count.saturating_add(1);
+    }
+
+    pub fn last_seen(&self) -> SystemTime {
+        self.last_ping
+    }
+
+    /// Returns true if the node has ever been seen,
+    /// false for a node we have only heard of.
+    pub fn is_verified(&self) -> bool {
+        self.last_ping > UNIX_EPOCH
+    }
+
+    /// Returns true if the node has not been seen for the timeout,
+    /// it should be pinged to tell whether it is still alive.
+    pub fn is_quiet(&self, quiet_timeout: Duration) -> bool {
+        self.last_ping.elapsed().unwrap_or_default() >= quiet_timeout
+    }
+
+    pub fn peer_info(&self) -> PeerInfo {
+        PeerInfo::new(&self.endpoint, &self.public_key)
     }
 
     pub fn info(&self) -> NodeInfo {
         NodeInfo {
             endpoint_string: self.endpoint.to_string(),
+            id: self.id.clone(),
+            public_key: self.public_key.clone(),
+            last_seen: self
+                .last_ping
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            failure_count: self.failure_count,
         }
     }
 }
@@ -60,10 +126,11 @@ impl fmt::Display for Node {
     fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
         write!(
             formatter,
-            "endpoint: {} id: {} status: {}",
+            "endpoint: {} id: {} last seen: {}s ago rtt: {:?}",
             self.endpoint,
             &hex::encode(&self.id),
-            if self.is_alive() { "alive" } else { "dead" }
+            self.last_ping.elapsed().unwrap_or_default().as_secs(),
+            self.rtt.srtt()
         )
     }
 }
@@ -74,27 +141,13 @@ impl PartialEq for Node {
     }
 }
 
-#[must_use]
-pub fn endpoint_to_node_id(endpoint: &SocketAddr) -> Vec<u8> {
-    let s = endpoint.to_string();
-    let sock_bytes = s.as_bytes();
-    let hash = hash(MessageDigest::sha3_512(), &sock_bytes).expect("Failed to hash socket bytes");
-    hash.to_vec()
-}
-
 //return true if lhs < rhs
+//a shorter distance is compared as if padded with zeros
 pub fn node_id_cmp(lhs: &[u8], rhs: &[u8]) -> bool {
-    assert_eq!(lhs.len(), rhs.len());
-    for i in 0..lhs.len() {
-        let mut l = lhs[i];
-        let mut r = rhs[i];
-        if cfg!(target_endian = "little") {
-            l = l.to_le();
-            r = r.to_le();
-        } else {
-            l = l.to_be();
-            r = r.to_be();
-        }
+    let len = max(lhs.len(), rhs.len());
+    for i in 0..len {
+        let l = lhs.get(i).copied().unwrap_or(0);
+        let r = rhs.get(i).copied().unwrap_or(0);
         if l < r {
             return true;
         }
@@ -105,45 +158,42 @@ pub fn node_id_cmp(lhs: &[u8], rhs: &[u8]) -> bool {
     return false;
 }
 
+/// XOR distance of the IDs.
+/// The IDs may have different sizes (e.g. a key and a shorter node ID),
+/// the shorter one is padded with zeros.
 pub fn node_id_distance(lhs: &[u8], rhs: &[u8]) -> Vec<u8> {
-    assert!(lhs.len() > 0);
-    assert!(rhs.len() > 0);
-    assert_eq!(lhs.len(), rhs.len());
-
-    let mut ret = vec![0; lhs.len()];
-    debug_assert_eq!(lhs.len(), ret.len());
-    for i in 0..lhs.len() {
-        ret[i] = lhs[i] ^ rhs[i];
+    let len = max(lhs.len(), rhs.len());
+    let mut ret = vec![0; len];
+    for (i, byte) in ret.iter_mut().enumerate() {
+        *byte = lhs.get(i).copied().unwrap_or(0) ^ rhs.get(i).copied().unwrap_or(0);
     }
     ret
 }
 
+/// Fraction of the keyspace covered by the distance, in [0, 1).
+pub fn distance_to_fraction(distance: &[u8]) -> f64 {
+    let mut head = [0; 8];
+    let len = std::cmp::min(distance.len(), head.len());
+    head[..len].copy_from_slice(&distance[..len]);
+    u64::from_be_bytes(head) as f64 / 2f64.powi(64)
+}
+
+/// Number of leading bits the IDs share.
 pub fn calculate_bucket_index(lhs: &[u8], rhs: &[u8]) -> usize {
-    assert!(lhs.len() > 0);
-    assert!(rhs.len() > 0);
-    assert_eq!(lhs.len(), rhs.len());
-
-    let distance = node_id_distance(lhs, rhs);
-    let leading_zeros = u8_slice_clz(&distance);
-    //calculate 2^n <= x < 2^(n+1)
-    let node_id_bits = lhs.len() * 8;
-    assert!(node_id_bits >= leading_zeros);
-    let exp = max(node_id_bits - leading_zeros, 0);
-    node_id_bits - exp
+    u8_slice_clz(&node_id_distance(lhs, rhs))
 }
 
 pub fn u8_slice_clz(v: &[u8]) -> usize {
-    for i in 0..v.len() {
-        if v[i] == 0 {
+    for (i, byte) in v.iter().enumerate() {
+        if *byte == 0 {
             //all zero
             continue;
         }
-        let tmp;
-        if cfg!(target_endian = "little") {
-            tmp = v[i].to_le();
+        let tmp = if cfg!(target_endian = "little") {
+            byte.to_le()
         } else {
-            tmp = v[i].to_be();
-        }
+            byte.to_be()
+        };
         return i * 8 + tmp.leading_zeros() as usize;
     }
     return v.len() * 8;
@@ -151,7 +201,7 @@ pub fn u8_slice_clz(v: &[u8]) -> usize {
 
 #[cfg(test)]
 mod tests {
-    use super::{calculate_bucket_index, u8_slice_clz};
+    use super::{calculate_bucket_index, node_id_cmp, node_id_distance, u8_slice_clz};
     use openssl::rand::rand_bytes;
 
     #[test]
@@ -173,6 +223,19 @@ mod tests {
         rand_bytes(&mut rb).unwrap();
 
         assert_eq!(calculate_bucket_index(&rb, &rb), 512);
-        //todo cover more (but how?)
+        let mut other = rb.clone();
+        other[1] ^= 0x10;
+        assert_eq!(calculate_bucket_index(&rb, &other), 11);
+    }
+
+    #[test]
+    fn mismatched_sizes() {
+        assert_eq!(node_id_distance(&[0xf0, 0x0f], &[0xff]), vec![0x0f, 0x0f]);
+        assert!(node_id_distance(&[], &[]).is_empty());
+        assert_eq!(calculate_bucket_index(&[], &[]), 0);
+        assert_eq!(calculate_bucket_index(&[0x80], &[0x80, 0x01]), 15);
+        assert!(node_id_cmp(&[1], &[1, 1]));
+        assert!(!node_id_cmp(&[1, 0], &[1]));
+        assert!(!node_id_cmp(&[], &[]));
     }
 }
@@ -0,0 +1,102 @@
+use crate::constant::{INITIAL_RTO, MAX_RTO, MIN_RTO};
+use std::cmp::{max, min};
+use std::time::Duration;
+
+/// Clock granularity of the RTO, the variance never counts for less.
+const GRANULARITY: Duration = Duration::from_millis(10);
+
+/// RttEstimator
+/// Smoothed round trip time of the requests to a node (RFC 6298),
+/// which gives the retransmission timeout of the requests.
+pub struct RttEstimator {
+    srtt: Option<Duration>,
+    rttvar: Duration,
+}
+
+impl RttEstimator {
+    pub fn new() -> Self {
+        RttEstimator {
+            srtt: None,
+            rttvar: Duration::ZERO,
+        }
+    }
+
+    /// Add the round trip time of a request which was not retransmitted.
+    pub fn update(&mut self, sample: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(sample);
+                self.rttvar = sample / 2;
+            }
+            Some(srtt) => {
+                let deviation = max(srtt, sample) - min(srtt, sample);
+                self.rttvar = self.rttvar * 3 / 4 + deviation / 4;
+                self.srtt = Some(srtt * 7 / 8 + sample / 8);
+            }
+        }
+    }
+
+    /// Smoothed round trip time, None before the first sample.
+    pub fn srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    /// How long to wait for a response before sending the request again.
+    pub fn rto(&self) -> Duration {
+        match self.srtt {
+            None => INITIAL_RTO,
+            Some(srtt) => (srtt + max(self.rttvar * 4, GRANULARITY)).clamp(MIN_RTO, MAX_RTO),
+        }
+    }
+}
+
+/// Timeout of the nth retransmission (0 for the first transmission),
+/// doubled each time up to MAX_RTO.
+pub fn backoff(rto: Duration, attempt: u32) -> Duration {
+    min(rto.saturating_mul(1 << min(attempt, 16)), MAX_RTO)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{backoff, RttEstimator};
+    use crate::constant::{INITIAL_RTO, MAX_RTO, MIN_RTO};
+    use std::time::Duration;
+
+    #[test]
+    fn estimate_rto() {
+        let mut rtt = RttEstimator::new();
+        assert_eq!(rtt.srtt(), None);
+        assert_eq!(rtt.rto(), INITIAL_RTO);
+
+        //srtt + 4 * rttvar = 3 * sample
+        rtt.update(Duration::from_millis(200));
+        assert_eq!(rtt.srtt(), Some(Duration::from_millis(200)));
+        assert_eq!(rtt.rto(), Duration::from_millis(600));
+
+        //converges to a stable rtt
+        for _ in 0..100 {
+            rtt.update(Duration::from_millis(300));
+        }
+        let srtt = rtt.srtt().unwrap();
+        assert!(Duration::from_millis(295) < srtt && srtt <= Duration::from_millis(300));
+        assert!(rtt.rto() < Duration::from_millis(350));
+
+        //a fast node is not retried too early
+        let mut fast = RttEstimator::new();
+        fast.update(Duration::from_micros(100));
+        assert_eq!(fast.rto(), MIN_RTO);
+        //a slow one is retried before too long
+        let mut slow = RttEstimator::new();
+        slow.update(Duration::from_secs(60));
+        assert_eq!(slow.rto(), MAX_RTO);
+    }
+
+    #[test]
+    fn exponential_backoff() {
+        let rto = Duration::from_millis(300);
+        assert_eq!(backoff(rto, 0), rto);
+        assert_eq!(backoff(rto, 1), rto * 2);
+        assert_eq!(backoff(rto, 2), rto * 4);
+        assert_eq!(backoff(rto, 100), MAX_RTO);
+    }
+}
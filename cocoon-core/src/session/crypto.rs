@@ -0,0 +1,161 @@
+use anyhow::{anyhow, Result};
+use openssl::derive::Deriver;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{Id, PKey, Private};
+use openssl::sign::Signer;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+
+/// Size of a raw X25519 public key.
+pub const EPHEMERAL_KEY_SIZE: usize = 32;
+/// Size of a ChaCha20-Poly1305 key.
+pub const SESSION_KEY_SIZE: usize = 32;
+/// Size of the Poly1305 tag appended to the ciphertext.
+pub const TAG_SIZE: usize = 16;
+/// Salt of the key derivation, so the keys are not used by other protocols.
+const KDF_SALT: &[u8] = b"cocoon session";
+
+/// EphemeralKey
+/// X25519 keypair generated for one handshake.
+pub struct EphemeralKey {
+    private_key: PKey<Private>,
+    public_key: Vec<u8>,
+}
+
+impl EphemeralKey {
+    pub fn generate() -> Result<Self> {
+        let private_key = PKey::generate_x25519()?;
+        let public_key = private_key.raw_public_key()?;
+        Ok(EphemeralKey {
+            private_key: private_key,
+            public_key: public_key,
+        })
+    }
+
+    /// Raw public key.
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    /// Shared secret with the owner of the peer's ephemeral key.
+    /// Fails for the keys of small order, which give an all zero secret.
+    pub fn diffie_hellman(&self, peer_public_key: &[u8]) -> Result<Vec<u8>> {
+        if peer_public_key.len() != EPHEMERAL_KEY_SIZE {
+            return Err(anyhow!(
+                "Invalid ephemeral key size {}",
+                peer_public_key.len()
+            ));
+        }
+        let peer_public_key = PKey::public_key_from_raw_bytes(peer_public_key, Id::X25519)?;
+        let mut deriver = Deriver::new(&self.private_key)?;
+        deriver.set_peer(&peer_public_key)?;
+        let shared = deriver.derive_to_vec()?;
+        if shared.iter().all(|b| *b == 0) {
+            return Err(anyhow!("Ephemeral key of small order"));
+        }
+        Ok(shared)
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[&[u8]]) -> Result<Vec<u8>> {
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    for d in data {
+        signer.update(d)?;
+    }
+    Ok(signer.sign_to_vec()?)
+}
+
+/// Derive the session keys from the shared secret with HKDF-SHA256.
+/// The transcript (both ephemeral keys) binds the keys to the handshake.
+/// Returns the keys of initiator to responder and responder to initiator.
+pub fn derive_keys(shared: &[u8], transcript: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+    //extract
+    let prk = hmac_sha256(KDF_SALT, &[shared])?;
+    //expand, two blocks of 32 bytes
+    let initiator_key = hmac_sha256(&prk, &[transcript, &[1]])?;
+    let responder_key = hmac_sha256(&prk, &[&initiator_key, transcript, &[2]])?;
+    debug_assert_eq!(initiator_key.len(), SESSION_KEY_SIZE);
+    Ok((initiator_key, responder_key))
+}
+
+/// Derive the key which encrypts the identity of the responder in the handshake response.
+/// Used for a single message, independent of the session keys.
+pub fn derive_identity_key(shared: &[u8], transcript: &[u8]) -> Result<Vec<u8>> {
+    let prk = hmac_sha256(KDF_SALT, &[shared])?;
+    hmac_sha256(&prk, &[transcript, &[0]])
+}
+
+/// Nonce of the counter, a counter is never used twice with a key.
+fn nonce(counter: u64) -> [u8; 12] {
+    let mut nonce = [0; 12];
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+    nonce
+}
+
+/// Encrypt and authenticate the plaintext and the additional data.
+/// Returns the ciphertext followed by the tag.
+pub fn seal(key: &[u8], counter: u64, aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+    let mut tag = [0; TAG_SIZE];
+    let mut sealed = encrypt_aead(
+        Cipher::chacha20_poly1305(),
+        key,
+        Some(&nonce(counter)),
+        aad,
+        plaintext,
+        &mut tag,
+    )?;
+    sealed.extend_from_slice(&tag);
+    Ok(sealed)
+}
+
+/// Reverse of seal, fails if anything was modified.
+pub fn open(key: &[u8], counter: u64, aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>> {
+    if sealed.len() < TAG_SIZE {
+        return Err(anyhow!("Ciphertext is too short"));
+    }
+    let (ciphertext, tag) = sealed.split_at(sealed.len() - TAG_SIZE);
+    decrypt_aead(
+        Cipher::chacha20_poly1305(),
+        key,
+        Some(&nonce(counter)),
+        aad,
+        ciphertext,
+        tag,
+    )
+    .map_err(|_| anyhow!("Failed to decrypt"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{derive_identity_key, derive_keys, open, seal, EphemeralKey};
+
+    #[test]
+    pub fn key_exchange() -> anyhow::Result<()> {
+        let a = EphemeralKey::generate()?;
+        let b = EphemeralKey::generate()?;
+        let shared = a.diffie_hellman(b.public_key())?;
+        assert_eq!(shared, b.diffie_hellman(a.public_key())?);
+        assert!(a.diffie_hellman(&[0; 32]).is_err());
+        assert!(a.diffie_hellman(&[1; 16]).is_err());
+
+        let (k1, k2) = derive_keys(&shared, b"transcript")?;
+        assert_ne!(k1, k2);
+        assert_eq!(
+            (k1.clone(), k2.clone()),
+            derive_keys(&shared, b"transcript")?
+        );
+        assert_ne!(k1, derive_keys(&shared, b"another")?.0);
+        let identity_key = derive_identity_key(&shared, b"transcript")?;
+        assert_ne!(identity_key, k1);
+        assert_ne!(identity_key, k2);
+
+        let sealed = seal(&k1, 7, b"header", b"hello")?;
+        assert_eq!(open(&k1, 7, b"header", &sealed)?, b"hello");
+        assert!(open(&k1, 8, b"header", &sealed).is_err());
+        assert!(open(&k1, 7, b"header!", &sealed).is_err());
+        let mut tampered = sealed.clone();
+        tampered[0] ^= 1;
+        assert!(open(&k1, 7, b"header", &tampered).is_err());
+        Ok(())
+    }
+}
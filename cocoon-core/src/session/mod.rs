@@ -0,0 +1,317 @@
+use crate::fragment::FragmentSocket;
+use crate::identity::Identity;
+use crate::message::{
+    verify_message, HandshakeInitMessage, HandshakeResponseMessage, MessageHeader, MessageType,
+};
+use crate::utility;
+use anyhow::{anyhow, Result};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use table::{is_encrypted, Queued, SessionTable};
+use tokio::time::Instant;
+use tracing::{event, Level};
+
+mod crypto;
+mod table;
+
+/// A message received from a peer, with the signature trailer stripped.
+pub struct ReceivedMessage {
+    /// Header and body.
+    pub message: Vec<u8>,
+    pub sender: SocketAddr,
+    /// Public key of the node which signed the message.
+    pub public_key: Vec<u8>,
+}
+
+/// SessionSocket
+/// Encrypts the signed datagrams with a key shared with the peer,
+/// so the keys and values we exchange can not be read on the way.
+/// The key is agreed in a handshake of ephemeral X25519 keys, the public keys of the nodes
+/// are only sent encrypted, so who talks to whom is not seen on the way.
+/// The first datagrams to a peer wait in a queue until it completes.
+/// Only the handshake is accepted in clear text, a signed datagram could be replayed.
+pub struct SessionSocket {
+    socket: FragmentSocket,
+    sessions: Mutex<SessionTable>,
+    /// Number of dropped datagrams which failed the verification or decryption.
+    rejected_datagrams: AtomicU64,
+}
+
+impl SessionSocket {
+    pub fn new(socket: FragmentSocket, identity: Arc<Identity>) -> Self {
+        SessionSocket {
+            socket: socket,
+            sessions: Mutex::new(SessionTable::new(identity)),
+            rejected_datagrams: AtomicU64::new(0),
+        }
+    }
+
+    /// Addresses of the transport, the first one is the main one.
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.socket.local_addrs()
+    }
+
+    /// Returns true if the transport can send to the endpoint.
+    pub fn can_reach(&self, endpoint: &SocketAddr) -> bool {
+        self.socket.can_reach(endpoint)
+    }
+
+    pub fn rejected_count(&self) -> u64 {
+        self.rejected_datagrams.load(Ordering::Relaxed)
+    }
+
+    /// Number of sessions with other nodes.
+    pub fn session_count(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    /// Forget the sessions with the endpoint, e.g. when the node is removed from the route table.
+    pub fn close_session(&self, endpoint: &SocketAddr) {
+        self.sessions.lock().unwrap().close(endpoint);
+    }
+
+    /// Send the signed datagram encrypted.
+    /// Without a session, the datagram is queued and a handshake is started,
+    /// so this returns before the datagram is sent.
+    pub async fn send_to(&self, datagram: &[u8], endpoint: &SocketAddr) -> Result<()> {
+        let now = Instant::now();
+        let sealed = self
+            .sessions
+            .lock()
+            .unwrap()
+            .seal(endpoint, datagram, now)?;
+        if let Some(sealed) = sealed {
+            self.socket.send_to(&sealed, endpoint).await?;
+            return Ok(());
+        }
+        let queued = self
+            .sessions
+            .lock()
+            .unwrap()
+            .queue(endpoint, datagram.to_vec(), now);
+        if let Queued::SendInit(init) = queued {
+            let bytes = init.to_bytes(utility::new_transaction_id());
+            self.socket.send_to(&bytes, endpoint).await?;
+        }
+        Ok(())
+    }
+
+    /// Receive the next message.
+    /// Handshakes are answered here, and datagrams which fail the verification are dropped.
+    pub async fn recv_from(&self) -> std::io::Result<ReceivedMessage> {
+        loop {
+            let (datagram, sender) = self.socket.recv_from().await?;
+            match self.handle(&datagram, &sender).await {
+                Ok(Some(received)) => return Ok(received),
+                Ok(None) => {}
+                Err(e) => {
+                    self.rejected_datagrams.fetch_add(1, Ordering::Relaxed);
+                    event!(Level::DEBUG, "Dropped a datagram from {}: {}", sender, e);
+                }
+            }
+        }
+    }
+
+    async fn handle(
+        &self,
+        datagram: &[u8],
+        sender: &SocketAddr,
+    ) -> Result<Option<ReceivedMessage>> {
+        if is_encrypted(datagram) {
+            let (datagram, peer_public_key) =
+                self.sessions
+                    .lock()
+                    .unwrap()
+                    .open(datagram, sender, Instant::now())?;
+            let (public_key, message) = verify_message(&datagram)?;
+            //the session authenticates the peer, the signature is of the same node
+            if public_key != peer_public_key {
+                return Err(anyhow!("Message is not signed by the session peer"));
+            }
+            return Ok(Some(ReceivedMessage {
+                message: message.to_vec(),
+                sender: *sender,
+                public_key: peer_public_key,
+            }));
+        }
+
+        //the handshake is not signed, the identities are inside the encrypted part
+        let header = MessageHeader::from_bytes(datagram)?;
+        if header.message_type == MessageType::HandshakeInit as u32 {
+            let (_, init) = HandshakeInitMessage::from_bytes(datagram)?;
+            let response = self.sessions.lock().unwrap().accept_init(
+                sender,
+                &init,
+                Instant::now(),
+                utility::unix_time_now(),
+            )?;
+            let bytes = response.to_bytes(header.transaction_id);
+            self.send_handshake(&bytes, sender).await;
+            return Ok(None);
+        }
+        if header.message_type == MessageType::HandshakeResponse as u32 {
+            let (_, response) = HandshakeResponseMessage::from_bytes(datagram)?;
+            let queued =
+                self.sessions
+                    .lock()
+                    .unwrap()
+                    .accept_response(sender, &response, Instant::now())?;
+            event!(
+                Level::DEBUG,
+                "Established a session with {}, {} datagrams queued",
+                sender,
+                queued.len()
+            );
+            for sealed in &queued {
+                if let Err(e) = self.socket.send_to(sealed, sender).await {
+                    event!(Level::DEBUG, "Failed to send to {}: {}", sender, e);
+                }
+            }
+            return Ok(None);
+        }
+        Err(anyhow!("Message is not encrypted"))
+    }
+
+    async fn send_handshake(&self, message: &[u8], endpoint: &SocketAddr) {
+        if let Err(e) = self.socket.send_to(message, endpoint).await {
+            event!(
+                Level::DEBUG,
+                "Failed to send a handshake to {}: {}",
+                endpoint,
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::table::is_encrypted;
+    use super::{ReceivedMessage, SessionSocket};
+    use crate::constant::DEFAULT_MTU;
+    use crate::fragment::FragmentSocket;
+    use crate::identity::Identity;
+    use crate::message::{sign_message, PingRequestMessage, StoreValueRequestMessage};
+    use crate::transport::{DatagramTransport, MemoryNetwork, MemoryTransport};
+    use async_trait::async_trait;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    /// Keeps the datagrams as they were on the network.
+    struct RecordingTransport {
+        transport: MemoryTransport,
+        received: Arc<Mutex<Vec<Vec<u8>>>>,
+    }
+
+    #[async_trait]
+    impl DatagramTransport for RecordingTransport {
+        async fn send_to(&self, buf: &[u8], target: &SocketAddr) -> std::io::Result<usize> {
+            self.transport.send_to(buf, target).await
+        }
+
+        async fn recv_from(&self, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+            let (size, sender) = self.transport.recv_from(buf).await?;
+            self.received.lock().unwrap().push(buf[..size].to_vec());
+            Ok((size, sender))
+        }
+
+        fn local_addr(&self) -> std::io::Result<SocketAddr> {
+            self.transport.local_addr()
+        }
+    }
+
+    fn session_socket(
+        transport: Arc<dyn DatagramTransport>,
+    ) -> anyhow::Result<(SessionSocket, Arc<Identity>)> {
+        let socket = FragmentSocket::new(transport, DEFAULT_MTU)?;
+        let identity = Arc::new(Identity::generate()?);
+        Ok((SessionSocket::new(socket, identity.clone()), identity))
+    }
+
+    fn contains(datagram: &[u8], bytes: &[u8]) -> bool {
+        datagram.windows(bytes.len()).any(|window| window == bytes)
+    }
+
+    async fn receive(socket: &SessionSocket) -> anyhow::Result<ReceivedMessage> {
+        Ok(timeout(Duration::from_millis(500), socket.recv_from()).await??)
+    }
+
+    #[tokio::test]
+    pub async fn encrypted_exchange() -> anyhow::Result<()> {
+        let network = MemoryNetwork::new(1);
+        let a_endpoint: SocketAddr = "10.0.0.1:4870".parse()?;
+        let b_endpoint: SocketAddr = "10.0.0.2:4870".parse()?;
+        let a_received = Arc::new(Mutex::new(Vec::new()));
+        let b_received = Arc::new(Mutex::new(Vec::new()));
+        let (a, a_identity) = session_socket(Arc::new(RecordingTransport {
+            transport: network.bind(&a_endpoint)?,
+            received: a_received.clone(),
+        }))?;
+        let (b, b_identity) = session_socket(Arc::new(RecordingTransport {
+            transport: network.bind(&b_endpoint)?,
+            received: b_received.clone(),
+        }))?;
+        let a = Arc::new(a);
+        let b = Arc::new(b);
+
+        //b answers the handshake while a waits for the response
+        let cloned_b = b.clone();
+        let receiver = tokio::spawn(async move { receive(&cloned_b).await });
+        let secret = b"a secret block of the file".to_vec();
+        let store = StoreValueRequestMessage::new(&[7; 64], &secret, 1, 1, 60, 0).to_bytes(1);
+        a.send_to(&sign_message(&a_identity, &store), &b_endpoint)
+            .await?;
+        //no message, the response is handled inside
+        assert!(receive(&a).await.is_err());
+
+        let received = receiver.await??;
+        assert_eq!(received.message, store);
+        assert_eq!(received.sender, a_endpoint);
+        assert_eq!(received.public_key, a_identity.public_key());
+        assert_eq!(a.session_count(), 1);
+        assert_eq!(b.session_count(), 1);
+        {
+            //the init and the store, which can not be read on the way
+            let b_received = b_received.lock().unwrap();
+            assert_eq!(b_received.len(), 2);
+            assert!(is_encrypted(&b_received[1]));
+            assert!(!contains(&b_received[1], &secret));
+            //who is talking to whom can not be seen either
+            let a_received = a_received.lock().unwrap();
+            assert_eq!(a_received.len(), 1);
+            for datagram in [&b_received[0], &a_received[0], &b_received[1]] {
+                assert!(!contains(datagram, a_identity.public_key()));
+                assert!(!contains(datagram, b_identity.public_key()));
+            }
+        }
+
+        //b replies with the session
+        let ping = PingRequestMessage::new().to_bytes(2);
+        b.send_to(&sign_message(&b_identity, &ping), &a_endpoint)
+            .await?;
+        let received = receive(&a).await?;
+        assert_eq!(received.message, ping);
+        assert_eq!(received.public_key, b_identity.public_key());
+        assert_eq!(a.rejected_count(), 0);
+        assert_eq!(b.rejected_count(), 0);
+
+        //signed but in clear text, it could be a replay
+        a.socket
+            .send_to(&sign_message(&a_identity, &ping), &b_endpoint)
+            .await?;
+        assert!(receive(&b).await.is_err());
+        assert_eq!(b.rejected_count(), 1);
+
+        //replayed by someone at the endpoint of a
+        drop(a);
+        let replayer = network.bind(&a_endpoint)?;
+        let replayed = b_received.lock().unwrap()[1].clone();
+        replayer.send_to(&replayed, &b_endpoint).await?;
+        assert!(receive(&b).await.is_err());
+        assert_eq!(b.rejected_count(), 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,682 @@
+use super::crypto::{self, EphemeralKey};
+use crate::constant::{
+    HANDSHAKE_MAX_AGE, HANDSHAKE_RETRY_INTERVAL, HANDSHAKE_TIMEOUT, MAX_HANDSHAKES,
+    MAX_QUEUED_DATAGRAMS, MAX_SESSIONS, SESSION_LIFETIME, SESSION_REKEY_INTERVAL,
+};
+use crate::identity::{self, Identity, PUBLIC_KEY_SIZE, SIGNATURE_SIZE};
+use crate::message::{HandshakeInitMessage, HandshakeResponseMessage, MessageType};
+use crate::utility;
+use anyhow::{anyhow, Result};
+use openssl::rand::rand_bytes;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::time::Instant;
+
+/// Size of the header of an encrypted datagram:
+/// message type (Encrypted), receiver index and counter.
+/// The header is authenticated as the additional data.
+pub const ENCRYPTED_HEADER_SIZE: usize = 16;
+/// Public key followed by the signature of the handshake.
+const IDENTITY_PROOF_SIZE: usize = PUBLIC_KEY_SIZE + SIGNATURE_SIZE;
+/// Signed with the transcript, so a proof of one side can not be used as the other.
+const INITIATOR_LABEL: &[u8] = b"cocoon handshake initiator";
+const RESPONDER_LABEL: &[u8] = b"cocoon handshake responder";
+/// First byte of the plaintext of an encrypted datagram,
+/// tells if the identity proof of the initiator comes before the signed datagram.
+const WITHOUT_IDENTITY: u8 = 0;
+const WITH_IDENTITY: u8 = 1;
+
+/// Returns true if the datagram is encrypted with a session key.
+pub fn is_encrypted(datagram: &[u8]) -> bool {
+    datagram.len() >= 4 && datagram[0..4] == (MessageType::Encrypted as u32).to_le_bytes()
+}
+
+/// Counters received recently, so a datagram replayed by someone else is dropped.
+struct ReplayWindow {
+    /// The highest counter received plus one.
+    next: u64,
+    /// Bit i is set if next - 1 - i was received.
+    bitmap: u64,
+}
+
+impl ReplayWindow {
+    fn new() -> Self {
+        ReplayWindow { next: 0, bitmap: 0 }
+    }
+
+    fn is_fresh(&self, counter: u64) -> bool {
+        if counter >= self.next {
+            return true;
+        }
+        let age = self.next - 1 - counter;
+        age < 64 && self.bitmap & (1 << age) == 0
+    }
+
+    fn mark(&mut self, counter: u64) {
+        if counter >= self.next {
+            let shift = counter - self.next + 1;
+            self.bitmap = if shift >= 64 { 0 } else { self.bitmap << shift };
+            self.bitmap |= 1;
+            self.next = counter + 1;
+        } else {
+            self.bitmap |= 1 << (self.next - 1 - counter);
+        }
+    }
+}
+
+/// Keys and counters shared with a peer after a handshake.
+struct Session {
+    /// Every message inside is signed by this key.
+    /// None on the responder until the initiator proved who it is.
+    peer_public_key: Option<Vec<u8>>,
+    /// Ephemeral keys of the initiator and the responder.
+    transcript: Vec<u8>,
+    /// Our proof for the responder, sent with each datagram until it answers with the session.
+    identity_proof: Option<Vec<u8>>,
+    endpoint: SocketAddr,
+    /// Index we put in the datagrams to the peer.
+    remote_index: u32,
+    send_key: Vec<u8>,
+    receive_key: Vec<u8>,
+    send_counter: u64,
+    replay_window: ReplayWindow,
+    established: Instant,
+    /// The responder does not send with a session before the initiator used it,
+    /// as the initiator may not have received the response.
+    is_confirmed: bool,
+}
+
+/// A handshake init we answered, kept until it is stale.
+/// The same init again is a retry (our response was lost) or a replay.
+struct AcceptedInit {
+    endpoint: SocketAddr,
+    response: HandshakeResponseMessage,
+    accepted: Instant,
+}
+
+/// A handshake we started, waiting for the response.
+struct PendingHandshake {
+    ephemeral_key: EphemeralKey,
+    local_index: u32,
+    /// Time of the init, sent again the same on a retry.
+    timestamp: u64,
+    started: Instant,
+    last_sent: Instant,
+    /// Signed datagrams to send once the session is established.
+    queue: VecDeque<Vec<u8>>,
+}
+
+/// What to do with a datagram which has no session to be sent with.
+pub enum Queued {
+    /// Send the init message to start a handshake.
+    SendInit(HandshakeInitMessage),
+    /// The handshake is in progress.
+    Wait,
+}
+
+/// SessionTable
+/// Sessions keyed by our index, which the peer puts in the datagrams to us.
+/// A peer can have a few sessions at once (e.g. while rekeying),
+/// the latest established one is used to send.
+/// The handshake is of ephemeral keys only, the public keys of both nodes are sent
+/// encrypted with the keys agreed, each signing the ephemeral keys.
+pub struct SessionTable {
+    identity: Arc<Identity>,
+    sessions: HashMap<u32, Session>,
+    /// Index of the session to send to the endpoint with.
+    current: HashMap<SocketAddr, u32>,
+    handshakes: HashMap<SocketAddr, PendingHandshake>,
+    /// Answered inits keyed by the ephemeral public key of the initiator.
+    accepted_inits: HashMap<Vec<u8>, AcceptedInit>,
+    last_expired: Instant,
+}
+
+impl SessionTable {
+    pub fn new(identity: Arc<Identity>) -> Self {
+        SessionTable {
+            identity: identity,
+            sessions: HashMap::new(),
+            current: HashMap::new(),
+            handshakes: HashMap::new(),
+            accepted_inits: HashMap::new(),
+            last_expired: Instant::now(),
+        }
+    }
+
+    /// Number of sessions, including the ones being replaced.
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// Encrypt the signed datagram with the session of the endpoint.
+    /// Returns None if there is no session or it should be rekeyed.
+    pub fn seal(
+        &mut self,
+        endpoint: &SocketAddr,
+        datagram: &[u8],
+        now: Instant,
+    ) -> Result<Option<Vec<u8>>> {
+        let index = match self.current.get(endpoint) {
+            Some(index) => *index,
+            None => return Ok(None),
+        };
+        let session = match self.sessions.get_mut(&index) {
+            Some(session) => session,
+            None => return Ok(None),
+        };
+        if now.duration_since(session.established) >= SESSION_REKEY_INTERVAL {
+            return Ok(None);
+        }
+        Ok(Some(seal_with(session, datagram)?))
+    }
+
+    /// Queue the signed datagram until a handshake with the endpoint completes.
+    /// The oldest datagram is dropped if the queue is full.
+    pub fn queue(&mut self, endpoint: &SocketAddr, datagram: Vec<u8>, now: Instant) -> Queued {
+        self.expire(now);
+        if let Some(handshake) = self.handshakes.get_mut(endpoint) {
+            if handshake.queue.len() >= MAX_QUEUED_DATAGRAMS {
+                handshake.queue.pop_front();
+            }
+            handshake.queue.push_back(datagram);
+            if now.duration_since(handshake.last_sent) < HANDSHAKE_RETRY_INTERVAL {
+                return Queued::Wait;
+            }
+            //the init or the response may be lost, send the same init again
+            handshake.last_sent = now;
+            return Queued::SendInit(HandshakeInitMessage::new(
+                handshake.local_index,
+                handshake.ephemeral_key.public_key(),
+                handshake.timestamp,
+            ));
+        }
+
+        if self.handshakes.len() >= MAX_HANDSHAKES {
+            let oldest = self
+                .handshakes
+                .iter()
+                .min_by_key(|(_, handshake)| handshake.started)
+                .map(|(endpoint, _)| *endpoint)
+                .unwrap();
+            self.handshakes.remove(&oldest);
+        }
+        let ephemeral_key = EphemeralKey::generate().expect("Failed to generate a key");
+        let init = HandshakeInitMessage::new(
+            self.new_index(),
+            ephemeral_key.public_key(),
+            utility::unix_time_now(),
+        );
+        let mut queue = VecDeque::new();
+        queue.push_back(datagram);
+        self.handshakes.insert(
+            *endpoint,
+            PendingHandshake {
+                ephemeral_key: ephemeral_key,
+                local_index: init.sender_index,
+                timestamp: init.timestamp,
+                started: now,
+                last_sent: now,
+                queue: queue,
+            },
+        );
+        Queued::SendInit(init)
+    }
+
+    /// Answer the handshake started by the peer.
+    /// The session is used to send once the peer sent something with it,
+    /// its first datagrams carry the proof of who it is.
+    /// unix_time is our time in seconds since UNIX_EPOCH, an init further than
+    /// HANDSHAKE_MAX_AGE from it is rejected. An init we already answered is answered
+    /// the same without a new session if it is a retry of the peer, rejected otherwise.
+    pub fn accept_init(
+        &mut self,
+        endpoint: &SocketAddr,
+        init: &HandshakeInitMessage,
+        now: Instant,
+        unix_time: u64,
+    ) -> Result<HandshakeResponseMessage> {
+        self.expire(now);
+        if unix_time.abs_diff(init.timestamp) > HANDSHAKE_MAX_AGE.as_secs() {
+            return Err(anyhow!("Stale handshake init"));
+        }
+        if let Some(accepted) = self.accepted_inits.get(&init.ephemeral_public_key) {
+            let response = &accepted.response;
+            //the session may be gone since, e.g. closed
+            if accepted.endpoint != *endpoint || !self.sessions.contains_key(&response.sender_index)
+            {
+                return Err(anyhow!("Replayed handshake init"));
+            }
+            return Ok(HandshakeResponseMessage::new(
+                response.sender_index,
+                response.receiver_index,
+                &response.ephemeral_public_key,
+                &response.sealed_identity,
+            ));
+        }
+
+        let ephemeral_key = EphemeralKey::generate()?;
+        let shared = ephemeral_key.diffie_hellman(&init.ephemeral_public_key)?;
+        let transcript = [&init.ephemeral_public_key, ephemeral_key.public_key()].concat();
+        let (initiator_key, responder_key) = crypto::derive_keys(&shared, &transcript)?;
+        let identity_key = crypto::derive_identity_key(&shared, &transcript)?;
+        let proof = identity_proof(&self.identity, RESPONDER_LABEL, &transcript);
+        let sealed_identity = crypto::seal(&identity_key, 0, &transcript, &proof)?;
+
+        let local_index = self.new_index();
+        self.insert(
+            local_index,
+            Session {
+                peer_public_key: None,
+                transcript: transcript,
+                identity_proof: None,
+                endpoint: *endpoint,
+                remote_index: init.sender_index,
+                send_key: responder_key,
+                receive_key: initiator_key,
+                send_counter: 0,
+                replay_window: ReplayWindow::new(),
+                established: now,
+                is_confirmed: false,
+            },
+        );
+        let response = HandshakeResponseMessage::new(
+            local_index,
+            init.sender_index,
+            ephemeral_key.public_key(),
+            &sealed_identity,
+        );
+        if self.accepted_inits.len() >= MAX_SESSIONS {
+            let oldest = self
+                .accepted_inits
+                .iter()
+                .min_by_key(|(_, accepted)| accepted.accepted)
+                .map(|(key, _)| key.clone())
+                .unwrap();
+            self.accepted_inits.remove(&oldest);
+        }
+        self.accepted_inits.insert(
+            init.ephemeral_public_key.clone(),
+            AcceptedInit {
+                endpoint: *endpoint,
+                response: HandshakeResponseMessage::new(
+                    local_index,
+                    init.sender_index,
+                    ephemeral_key.public_key(),
+                    &sealed_identity,
+                ),
+                accepted: now,
+            },
+        );
+        Ok(response)
+    }
+
+    /// Complete the handshake we started.
+    /// Returns the queued datagrams, encrypted with the new session.
+    pub fn accept_response(
+        &mut self,
+        endpoint: &SocketAddr,
+        response: &HandshakeResponseMessage,
+        now: Instant,
+    ) -> Result<Vec<Vec<u8>>> {
+        let handshake = match self.handshakes.get(endpoint) {
+            Some(handshake) if handshake.local_index == response.receiver_index => handshake,
+            _ => return Err(anyhow!("Unexpected handshake response")),
+        };
+        let shared = handshake
+            .ephemeral_key
+            .diffie_hellman(&response.ephemeral_public_key)?;
+        let transcript = [
+            handshake.ephemeral_key.public_key(),
+            &response.ephemeral_public_key,
+        ]
+        .concat();
+        let identity_key = crypto::derive_identity_key(&shared, &transcript)?;
+        let proof = crypto::open(&identity_key, 0, &transcript, &response.sealed_identity)?;
+        let peer_public_key = verify_identity_proof(&proof, RESPONDER_LABEL, &transcript)?;
+        let (initiator_key, responder_key) = crypto::derive_keys(&shared, &transcript)?;
+        //only a response of the peer ends the handshake
+        let handshake = self.handshakes.remove(endpoint).unwrap();
+
+        let mut session = Session {
+            peer_public_key: Some(peer_public_key),
+            identity_proof: Some(identity_proof(&self.identity, INITIATOR_LABEL, &transcript)),
+            transcript: transcript,
+            endpoint: *endpoint,
+            remote_index: response.sender_index,
+            send_key: initiator_key,
+            receive_key: responder_key,
+            send_counter: 0,
+            replay_window: ReplayWindow::new(),
+            established: now,
+            is_confirmed: true,
+        };
+        let mut sealed = Vec::with_capacity(handshake.queue.len());
+        for datagram in &handshake.queue {
+            sealed.push(seal_with(&mut session, datagram)?);
+        }
+        self.insert(handshake.local_index, session);
+        self.current.insert(*endpoint, handshake.local_index);
+        Ok(sealed)
+    }
+
+    /// Decrypt the datagram from the sender.
+    /// Returns the signed datagram inside and the public key of the peer,
+    /// which must have signed it.
+    pub fn open(
+        &mut self,
+        datagram: &[u8],
+        sender: &SocketAddr,
+        now: Instant,
+    ) -> Result<(Vec<u8>, Vec<u8>)> {
+        if datagram.len() < ENCRYPTED_HEADER_SIZE + crypto::TAG_SIZE {
+            return Err(anyhow!("Encrypted datagram is too short"));
+        }
+        let (header, sealed) = datagram.split_at(ENCRYPTED_HEADER_SIZE);
+        let index = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        let counter = u64::from_le_bytes(header[8..16].try_into().unwrap());
+        let session = match self.sessions.get_mut(&index) {
+            Some(session) => session,
+            None => return Err(anyhow!("Unknown session {}", index)),
+        };
+        if session.endpoint != *sender {
+            return Err(anyhow!("Session {} is not of {}", index, sender));
+        }
+        if now.duration_since(session.established) >= SESSION_LIFETIME {
+            return Err(anyhow!("Session {} expired", index));
+        }
+        if !session.replay_window.is_fresh(counter) {
+            return Err(anyhow!("Replayed counter {}", counter));
+        }
+        let plaintext = crypto::open(&session.receive_key, counter, header, sealed)?;
+        let datagram = match plaintext.split_first() {
+            Some((&WITH_IDENTITY, rest)) if rest.len() >= IDENTITY_PROOF_SIZE => {
+                let (proof, datagram) = rest.split_at(IDENTITY_PROOF_SIZE);
+                let public_key =
+                    verify_identity_proof(proof, INITIATOR_LABEL, &session.transcript)?;
+                if let Some(peer_public_key) = &session.peer_public_key {
+                    if *peer_public_key != public_key {
+                        return Err(anyhow!("Session {} of another node", index));
+                    }
+                }
+                session.peer_public_key = Some(public_key);
+                datagram
+            }
+            Some((&WITHOUT_IDENTITY, datagram)) => datagram,
+            _ => return Err(anyhow!("Malformed encrypted datagram")),
+        };
+        let peer_public_key = match &session.peer_public_key {
+            Some(peer_public_key) => peer_public_key.clone(),
+            None => return Err(anyhow!("Session {} is not authenticated", index)),
+        };
+        //only authentic datagrams move the window
+        session.replay_window.mark(counter);
+        //the peer sends with the session once it knows who we are
+        session.identity_proof = None;
+
+        if !session.is_confirmed {
+            session.is_confirmed = true;
+            let established = session.established;
+            let is_newer = match self.current.get(sender).and_then(|i| self.sessions.get(i)) {
+                Some(current) => current.established <= established,
+                None => true,
+            };
+            if is_newer {
+                self.current.insert(*sender, index);
+            }
+        }
+        Ok((datagram.to_vec(), peer_public_key))
+    }
+
+    /// Drop the sessions and the handshake with the endpoint.
+    pub fn close(&mut self, endpoint: &SocketAddr) {
+        self.sessions
+            .retain(|_, session| session.endpoint != *endpoint);
+        self.current.remove(endpoint);
+        self.handshakes.remove(endpoint);
+    }
+
+    fn insert(&mut self, index: u32, session: Session) {
+        if self.sessions.len() >= MAX_SESSIONS {
+            //make room, dropping the oldest unconfirmed session first
+            let oldest = self
+                .sessions
+                .iter()
+                .min_by_key(|(_, session)| (session.is_confirmed, session.established))
+                .map(|(index, _)| *index)
+                .unwrap();
+            self.remove(oldest);
+        }
+        self.sessions.insert(index, session);
+    }
+
+    fn remove(&mut self, index: u32) {
+        if let Some(session) = self.sessions.remove(&index) {
+            if self.current.get(&session.endpoint) == Some(&index) {
+                self.current.remove(&session.endpoint);
+            }
+        }
+    }
+
+    /// Random index which is not in use.
+    fn new_index(&self) -> u32 {
+        loop {
+            let mut buf = [0; 4];
+            rand_bytes(&mut buf).expect("Failed to generate a random number");
+            let index = u32::from_le_bytes(buf);
+            if !self.sessions.contains_key(&index)
+                && !self
+                    .handshakes
+                    .values()
+                    .any(|handshake| handshake.local_index == index)
+            {
+                return index;
+            }
+        }
+    }
+
+    /// Drop the expired sessions and handshakes, at most once a second.
+    fn expire(&mut self, now: Instant) {
+        if now.duration_since(self.last_expired) < HANDSHAKE_RETRY_INTERVAL {
+            return;
+        }
+        self.last_expired = now;
+        let expired: Vec<u32> = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| now.duration_since(session.established) >= SESSION_LIFETIME)
+            .map(|(index, _)| *index)
+            .collect();
+        for index in expired {
+            self.remove(index);
+        }
+        self.handshakes
+            .retain(|_, handshake| now.duration_since(handshake.started) < HANDSHAKE_TIMEOUT);
+        //stale by then, even with a timestamp ahead of ours
+        self.accepted_inits
+            .retain(|_, accepted| now.duration_since(accepted.accepted) < HANDSHAKE_MAX_AGE * 2);
+    }
+}
+
+/// Public key followed by its signature of the transcript.
+fn identity_proof(identity: &Identity, label: &[u8], transcript: &[u8]) -> Vec<u8> {
+    let signature = identity.sign(&[label, transcript].concat());
+    [identity.public_key(), &signature].concat()
+}
+
+/// Returns the public key of the proof if it signed the transcript.
+fn verify_identity_proof(proof: &[u8], label: &[u8], transcript: &[u8]) -> Result<Vec<u8>> {
+    if proof.len() != IDENTITY_PROOF_SIZE {
+        return Err(anyhow!("Invalid identity proof size {}", proof.len()));
+    }
+    let (public_key, signature) = proof.split_at(PUBLIC_KEY_SIZE);
+    if !identity::verify(public_key, &[label, transcript].concat(), signature) {
+        return Err(anyhow!("Invalid identity proof"));
+    }
+    Ok(public_key.to_vec())
+}
+
+fn seal_with(session: &mut Session, datagram: &[u8]) -> Result<Vec<u8>> {
+    let counter = session.send_counter;
+    session.send_counter += 1;
+    let mut plaintext = Vec::with_capacity(1 + IDENTITY_PROOF_SIZE + datagram.len());
+    match &session.identity_proof {
+        Some(proof) => {
+            plaintext.push(WITH_IDENTITY);
+            plaintext.extend_from_slice(proof);
+        }
+        None => plaintext.push(WITHOUT_IDENTITY),
+    }
+    plaintext.extend_from_slice(datagram);
+    let mut sealed = Vec::with_capacity(ENCRYPTED_HEADER_SIZE + plaintext.len() + crypto::TAG_SIZE);
+    sealed.extend_from_slice(&(MessageType::Encrypted as u32).to_le_bytes());
+    sealed.extend_from_slice(&session.remote_index.to_le_bytes());
+    sealed.extend_from_slice(&counter.to_le_bytes());
+    let ciphertext = crypto::seal(&session.send_key, counter, &sealed, &plaintext)?;
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_encrypted, Queued, ReplayWindow, SessionTable};
+    use crate::constant::{
+        HANDSHAKE_MAX_AGE, HANDSHAKE_RETRY_INTERVAL, SESSION_LIFETIME, SESSION_REKEY_INTERVAL,
+    };
+    use crate::identity::Identity;
+    use crate::message::{HandshakeInitMessage, HandshakeResponseMessage};
+    use crate::utility;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use tokio::time::Instant;
+
+    #[test]
+    pub fn replay_window() {
+        let mut window = ReplayWindow::new();
+        for counter in [0, 2, 1, 70, 10] {
+            assert!(window.is_fresh(counter));
+            window.mark(counter);
+            assert!(!window.is_fresh(counter));
+        }
+        //too old to tell
+        assert!(!window.is_fresh(6));
+        assert!(window.is_fresh(69));
+        assert!(window.is_fresh(71));
+    }
+
+    #[test]
+    pub fn handshake() -> anyhow::Result<()> {
+        let a_endpoint: SocketAddr = "10.0.0.1:4870".parse()?;
+        let b_endpoint: SocketAddr = "10.0.0.2:4870".parse()?;
+        let a_identity = Arc::new(Identity::generate()?);
+        let b_identity = Arc::new(Identity::generate()?);
+        let mut a = SessionTable::new(a_identity.clone());
+        let mut b = SessionTable::new(b_identity.clone());
+        let now = Instant::now();
+
+        assert!(a.seal(&b_endpoint, b"first", now)?.is_none());
+        let init = match a.queue(&b_endpoint, b"first".to_vec(), now) {
+            Queued::SendInit(init) => init,
+            Queued::Wait => panic!(),
+        };
+        assert!(matches!(
+            a.queue(&b_endpoint, b"second".to_vec(), now),
+            Queued::Wait
+        ));
+
+        let response = b.accept_init(&a_endpoint, &init, now, init.timestamp)?;
+        //b waits for a to use the session
+        assert!(b.seal(&a_endpoint, b"reply", now)?.is_none());
+
+        //the identity of b can not be changed on the way
+        let mut tampered = HandshakeResponseMessage::new(
+            response.sender_index,
+            response.receiver_index,
+            &response.ephemeral_public_key,
+            &response.sealed_identity,
+        );
+        tampered.sealed_identity[0] ^= 1;
+        assert!(a.accept_response(&b_endpoint, &tampered, now).is_err());
+
+        let sealed = a.accept_response(&b_endpoint, &response, now)?;
+        assert_eq!(sealed.len(), 2);
+        assert!(is_encrypted(&sealed[0]));
+        assert!(a.accept_response(&b_endpoint, &response, now).is_err());
+
+        //only from the endpoint of the session
+        assert!(b.open(&sealed[0], &"10.0.0.3:4870".parse()?, now).is_err());
+        let (datagram, public_key) = b.open(&sealed[0], &a_endpoint, now)?;
+        assert_eq!(datagram, b"first");
+        assert_eq!(public_key, a_identity.public_key());
+        assert!(b.open(&sealed[0], &a_endpoint, now).is_err());
+        let mut tampered = sealed[1].clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(b.open(&tampered, &a_endpoint, now).is_err());
+        assert_eq!(b.open(&sealed[1], &a_endpoint, now)?.0, b"second");
+
+        let reply = b.seal(&a_endpoint, b"reply", now)?.unwrap();
+        let (datagram, public_key) = a.open(&reply, &b_endpoint, now)?;
+        assert_eq!(datagram, b"reply");
+        assert_eq!(public_key, b_identity.public_key());
+
+        //rekeyed after a while, the old session still receives for a bit
+        let later = now + SESSION_REKEY_INTERVAL;
+        assert!(a.seal(&b_endpoint, b"late", later)?.is_none());
+        let reply = b.seal(&a_endpoint, b"late reply", now)?.unwrap();
+        assert!(a.open(&reply, &b_endpoint, later).is_ok());
+        let reply = b.seal(&a_endpoint, b"late reply", now)?.unwrap();
+        assert!(a.open(&reply, &b_endpoint, now + SESSION_LIFETIME).is_err());
+
+        a.close(&b_endpoint);
+        assert_eq!(a.len(), 0);
+        Ok(())
+    }
+
+    #[test]
+    pub fn init_retry() -> anyhow::Result<()> {
+        let endpoint: SocketAddr = "10.0.0.2:4870".parse()?;
+        let mut a = SessionTable::new(Arc::new(Identity::generate()?));
+        let now = Instant::now();
+        let first = match a.queue(&endpoint, vec![0], now) {
+            Queued::SendInit(init) => init,
+            Queued::Wait => panic!(),
+        };
+        //the same init again, so a late response still completes the handshake
+        match a.queue(&endpoint, vec![1], now + HANDSHAKE_RETRY_INTERVAL) {
+            Queued::SendInit(init) => assert_eq!(init, first),
+            Queued::Wait => panic!(),
+        }
+        Ok(())
+    }
+
+    #[test]
+    pub fn replayed_init() -> anyhow::Result<()> {
+        let a_endpoint: SocketAddr = "10.0.0.1:4870".parse()?;
+        let mut b = SessionTable::new(Arc::new(Identity::generate()?));
+        let now = Instant::now();
+        let unix_time = utility::unix_time_now();
+        let init = HandshakeInitMessage::new(1, &[7; 32], unix_time);
+
+        let response = b.accept_init(&a_endpoint, &init, now, unix_time)?;
+        assert_eq!(b.len(), 1);
+        //a retry is answered the same, without another session
+        assert_eq!(b.accept_init(&a_endpoint, &init, now, unix_time)?, response);
+        assert_eq!(b.len(), 1);
+        //replayed from elsewhere
+        assert!(b
+            .accept_init(&"10.0.0.3:4870".parse()?, &init, now, unix_time)
+            .is_err());
+        //or after the session is gone
+        b.close(&a_endpoint);
+        assert!(b.accept_init(&a_endpoint, &init, now, unix_time).is_err());
+
+        //too old, or too far ahead
+        let max_age = HANDSHAKE_MAX_AGE.as_secs();
+        let old = HandshakeInitMessage::new(2, &[8; 32], unix_time - max_age - 1);
+        assert!(b.accept_init(&a_endpoint, &old, now, unix_time).is_err());
+        let ahead = HandshakeInitMessage::new(3, &[9; 32], unix_time + max_age + 1);
+        assert!(b.accept_init(&a_endpoint, &ahead, now, unix_time).is_err());
+        assert_eq!(b.len(), 0);
+        Ok(())
+    }
+}
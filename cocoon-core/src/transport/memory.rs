@@ -0,0 +1,269 @@
+use super::DatagramTransport;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+/// Datagrams queued for a transport at most, more are dropped as on a full socket buffer.
+const QUEUE_SIZE: usize = 1024;
+/// First port given to the transports bound on port 0.
+const FIRST_EPHEMERAL_PORT: u16 = 49152;
+
+struct Route {
+    sender: mpsc::Sender<(Vec<u8>, SocketAddr)>,
+    /// Datagram held back to be delivered after the next one.
+    held: Option<(Vec<u8>, SocketAddr)>,
+}
+
+struct NetworkState {
+    routes: HashMap<SocketAddr, Route>,
+    next_port: u16,
+    loss_rate: f64,
+    reorder_rate: f64,
+    /// State of the xorshift generator.
+    random_state: u64,
+    dropped: u64,
+}
+
+impl NetworkState {
+    /// Uniform in [0, 1).
+    fn random(&mut self) -> f64 {
+        self.random_state ^= self.random_state << 13;
+        self.random_state ^= self.random_state >> 7;
+        self.random_state ^= self.random_state << 17;
+        (self.random_state >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// MemoryNetwork
+/// Network of in-process transports, for simulations and tests.
+/// Packet loss and reordering are drawn from a seeded generator,
+/// so a run with the same seed (and order of sends) is the same.
+#[derive(Clone)]
+pub struct MemoryNetwork {
+    state: Arc<Mutex<NetworkState>>,
+}
+
+impl MemoryNetwork {
+    pub fn new(seed: u64) -> Self {
+        MemoryNetwork {
+            state: Arc::new(Mutex::new(NetworkState {
+                routes: HashMap::new(),
+                next_port: FIRST_EPHEMERAL_PORT,
+                loss_rate: 0.0,
+                reorder_rate: 0.0,
+                //xorshift never leaves 0
+                random_state: seed | 1,
+                dropped: 0,
+            })),
+        }
+    }
+
+    /// Drop each datagram with the probability.
+    pub fn set_loss_rate(&self, rate: f64) {
+        self.state.lock().unwrap().loss_rate = rate;
+    }
+
+    /// Hold back each datagram with the probability,
+    /// it is delivered after the next datagram to the same endpoint.
+    pub fn set_reorder_rate(&self, rate: f64) {
+        self.state.lock().unwrap().reorder_rate = rate;
+    }
+
+    /// Number of datagrams lost, by the loss rate, full queues or no transport at the endpoint.
+    pub fn dropped_count(&self) -> u64 {
+        self.state.lock().unwrap().dropped
+    }
+
+    /// Bind a transport on the address, a free port is chosen for port 0.
+    pub fn bind(&self, address: &SocketAddr) -> io::Result<MemoryTransport> {
+        let mut state = self.state.lock().unwrap();
+        let mut address = *address;
+        if address.port() == 0 {
+            let first = state.next_port;
+            loop {
+                address.set_port(state.next_port);
+                state.next_port = state
+                    .next_port
+                    .checked_add(1)
+                    .unwrap_or(FIRST_EPHEMERAL_PORT);
+                if !state.routes.contains_key(&address) {
+                    break;
+                }
+                if state.next_port == first {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AddrInUse,
+                        "No free port on the memory network",
+                    ));
+                }
+            }
+        }
+        if state.routes.contains_key(&address) {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} is in use", address),
+            ));
+        }
+        let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+        state.routes.insert(
+            address,
+            Route {
+                sender: sender,
+                held: None,
+            },
+        );
+        Ok(MemoryTransport {
+            network: self.clone(),
+            local_addr: address,
+            receiver: tokio::sync::Mutex::new(receiver),
+        })
+    }
+
+    fn send(&self, datagram: &[u8], from: &SocketAddr, to: &SocketAddr) {
+        let mut state = self.state.lock().unwrap();
+        let (loss_rate, reorder_rate) = (state.loss_rate, state.reorder_rate);
+        let is_lost = state.random() < loss_rate;
+        let is_held = state.random() < reorder_rate;
+        let route = match state.routes.get_mut(to) {
+            Some(route) if !is_lost => route,
+            _ => {
+                state.dropped += 1;
+                return;
+            }
+        };
+        if is_held && route.held.is_none() {
+            route.held = Some((datagram.to_vec(), *from));
+            return;
+        }
+        let mut dropped = 0;
+        let held = route.held.take();
+        for delivery in std::iter::once((datagram.to_vec(), *from)).chain(held) {
+            if route.sender.try_send(delivery).is_err() {
+                dropped += 1;
+            }
+        }
+        state.dropped += dropped;
+    }
+}
+
+/// MemoryTransport
+/// Endpoint on a MemoryNetwork, unbound when dropped.
+pub struct MemoryTransport {
+    network: MemoryNetwork,
+    local_addr: SocketAddr,
+    receiver: tokio::sync::Mutex<mpsc::Receiver<(Vec<u8>, SocketAddr)>>,
+}
+
+#[async_trait]
+impl DatagramTransport for MemoryTransport {
+    async fn send_to(&self, datagram: &[u8], endpoint: &SocketAddr) -> io::Result<usize> {
+        self.network.send(datagram, &self.local_addr, endpoint);
+        Ok(datagram.len())
+    }
+
+    async fn recv_from(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (datagram, sender) = match self.receiver.lock().await.recv().await {
+            Some(received) => received,
+            None => return Err(io::ErrorKind::NotConnected.into()),
+        };
+        //truncated as UDP does
+        let size = datagram.len().min(buffer.len());
+        buffer[..size].copy_from_slice(&datagram[..size]);
+        Ok((size, sender))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+}
+
+impl Drop for MemoryTransport {
+    fn drop(&mut self) {
+        self.network
+            .state
+            .lock()
+            .unwrap()
+            .routes
+            .remove(&self.local_addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MemoryNetwork, MemoryTransport};
+    use crate::transport::DatagramTransport;
+    use std::net::SocketAddr;
+
+    /// Datagrams received until none is waiting.
+    async fn drain(transport: &MemoryTransport) -> Vec<Vec<u8>> {
+        let mut received = Vec::new();
+        let mut buffer = [0; 16];
+        while let Ok(Ok((size, _))) = tokio::time::timeout(
+            std::time::Duration::from_millis(10),
+            transport.recv_from(&mut buffer),
+        )
+        .await
+        {
+            received.push(buffer[..size].to_vec());
+        }
+        received
+    }
+
+    #[tokio::test]
+    async fn send_and_receive() -> anyhow::Result<()> {
+        let network = MemoryNetwork::new(1);
+        let a = network.bind(&"10.0.0.1:0".parse()?)?;
+        let b = network.bind(&"10.0.0.2:4870".parse()?)?;
+        assert_eq!(a.local_addr()?.port(), 49152);
+        //in use
+        assert!(network.bind(&"10.0.0.2:4870".parse()?).is_err());
+
+        a.send_to(b"ping", &b.local_addr()?).await?;
+        let mut buffer = [0; 16];
+        let (size, sender) = b.recv_from(&mut buffer).await?;
+        assert_eq!((&buffer[..size], sender), (&b"ping"[..], a.local_addr()?));
+
+        //nobody there
+        let address: SocketAddr = "10.0.0.3:4870".parse()?;
+        a.send_to(b"ping", &address).await?;
+        assert_eq!(network.dropped_count(), 1);
+        //unbound on drop
+        let c = network.bind(&address)?;
+        drop(c);
+        assert!(network.bind(&address).is_ok());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn loss_and_reordering() -> anyhow::Result<()> {
+        //same losses with the same seed
+        let mut runs = Vec::new();
+        for _ in 0..2 {
+            let network = MemoryNetwork::new(7);
+            network.set_loss_rate(0.5);
+            let a = network.bind(&"10.0.0.1:4870".parse()?)?;
+            let b = network.bind(&"10.0.0.2:4870".parse()?)?;
+            for i in 0..100u8 {
+                a.send_to(&[i], &b.local_addr()?).await?;
+            }
+            let received = drain(&b).await;
+            assert!(received.len() > 25 && received.len() < 75);
+            assert_eq!(network.dropped_count(), 100 - received.len() as u64);
+            runs.push(received);
+        }
+        assert_eq!(runs[0], runs[1]);
+
+        //every other datagram is held back
+        let network = MemoryNetwork::new(7);
+        network.set_reorder_rate(1.0);
+        let a = network.bind(&"10.0.0.1:4870".parse()?)?;
+        let b = network.bind(&"10.0.0.2:4870".parse()?)?;
+        for i in 0..5u8 {
+            a.send_to(&[i], &b.local_addr()?).await?;
+        }
+        assert_eq!(drain(&b).await, vec![vec![1], vec![0], vec![3], vec![2]]);
+        Ok(())
+    }
+}
@@ -0,0 +1,222 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::future::poll_fn;
+use std::io;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+use tokio::io::ReadBuf;
+use tokio::net::UdpSocket;
+use tracing::{event, Level};
+
+mod memory;
+
+pub use memory::{MemoryNetwork, MemoryTransport};
+
+/// DatagramTransport
+/// Unreliable datagram delivery, UDP or a network in memory.
+/// Fragmentation and authentication are done above.
+#[async_trait]
+pub trait DatagramTransport: Send + Sync {
+    /// Send the datagram, returns the number of bytes sent.
+    async fn send_to(&self, datagram: &[u8], endpoint: &SocketAddr) -> io::Result<usize>;
+
+    /// Receive the next datagram into the buffer.
+    /// Returns the size of the datagram and the sender.
+    async fn recv_from(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+
+    /// Address we receive on.
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    /// All the addresses we receive on, the first one is local_addr.
+    fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.local_addr().into_iter().collect()
+    }
+
+    /// Returns true if we can send to the endpoint (e.g. a socket of its address family).
+    fn can_reach(&self, _endpoint: &SocketAddr) -> bool {
+        true
+    }
+}
+
+struct BoundSocket {
+    socket: UdpSocket,
+    local_addr: SocketAddr,
+    /// IPv6 socket which accepts IPv4 too.
+    is_dual_stack: bool,
+}
+
+/// UdpTransport
+/// UDP sockets, IPv4 and/or IPv6.
+/// Datagrams are sent from the socket matching the address family of the peer.
+pub struct UdpTransport {
+    sockets: Vec<BoundSocket>,
+    /// Socket to poll first, so a busy socket does not starve the others.
+    next_socket: AtomicUsize,
+}
+
+impl UdpTransport {
+    /// Bind a socket on each address.
+    /// An unspecified IPv6 address is bound dual-stack if no IPv4 address is given.
+    pub fn bind(addresses: &[SocketAddr]) -> Result<Self> {
+        if addresses.len() == 0 {
+            return Err(anyhow!("No address to bind"));
+        }
+        let has_ipv4 = addresses.iter().any(|address| address.is_ipv4());
+        let mut sockets = Vec::with_capacity(addresses.len());
+        for address in addresses {
+            let is_dual_stack = !has_ipv4 && address.ip() == IpAddr::V6(Ipv6Addr::UNSPECIFIED);
+            let socket = Socket::new(
+                Domain::for_address(*address),
+                Type::DGRAM,
+                Some(Protocol::UDP),
+            )?;
+            if address.is_ipv6() {
+                socket.set_only_v6(!is_dual_stack)?;
+            }
+            socket.set_nonblocking(true)?;
+            socket
+                .bind(&(*address).into())
+                .map_err(|e| anyhow!("Failed to bind {}: {}", address, e))?;
+            let socket = UdpSocket::from_std(socket.into())?;
+            let local_addr = socket.local_addr()?;
+            event!(Level::INFO, "Bound a UDP socket on {}", local_addr);
+            sockets.push(BoundSocket {
+                socket: socket,
+                local_addr: local_addr,
+                is_dual_stack: is_dual_stack,
+            });
+        }
+        Ok(UdpTransport {
+            sockets: sockets,
+            next_socket: AtomicUsize::new(0),
+        })
+    }
+
+    /// Socket to send to the endpoint, and the address to send to.
+    fn socket_for(&self, endpoint: &SocketAddr) -> Option<(&UdpSocket, SocketAddr)> {
+        if let Some(bound) = self
+            .sockets
+            .iter()
+            .find(|bound| bound.local_addr.is_ipv4() == endpoint.is_ipv4())
+        {
+            return Some((&bound.socket, *endpoint));
+        }
+        //IPv4 peers are reached through a dual-stack socket by the mapped address
+        match endpoint {
+            SocketAddr::V4(v4) => {
+                self.sockets
+                    .iter()
+                    .find(|bound| bound.is_dual_stack)
+                    .map(|bound| {
+                        let mapped = IpAddr::V6(v4.ip().to_ipv6_mapped());
+                        (&bound.socket, SocketAddr::new(mapped, v4.port()))
+                    })
+            }
+            SocketAddr::V6(_) => None,
+        }
+    }
+
+    fn poll_recv_from(
+        &self,
+        cx: &mut Context<'_>,
+        buffer: &mut [u8],
+    ) -> Poll<io::Result<(usize, SocketAddr)>> {
+        let first = self.next_socket.fetch_add(1, Ordering::Relaxed);
+        for i in 0..self.sockets.len() {
+            let bound = &self.sockets[(first + i) % self.sockets.len()];
+            let mut read_buffer = ReadBuf::new(buffer);
+            if let Poll::Ready(result) = bound.socket.poll_recv_from(cx, &mut read_buffer) {
+                let received_size = read_buffer.filled().len();
+                return Poll::Ready(result.map(|sender| (received_size, sender)));
+            }
+        }
+        Poll::Pending
+    }
+}
+
+#[async_trait]
+impl DatagramTransport for UdpTransport {
+    async fn send_to(&self, datagram: &[u8], endpoint: &SocketAddr) -> io::Result<usize> {
+        let (socket, endpoint) = self.socket_for(endpoint).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("No socket to reach {}", endpoint),
+            )
+        })?;
+        socket.send_to(datagram, endpoint).await
+    }
+
+    async fn recv_from(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (received_size, sender) = poll_fn(|cx| self.poll_recv_from(cx, buffer)).await?;
+        //IPv4 peers on a dual-stack socket
+        let sender = SocketAddr::new(sender.ip().to_canonical(), sender.port());
+        Ok((received_size, sender))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.sockets[0].local_addr)
+    }
+
+    /// Bound addresses, in the order of the bind addresses.
+    fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.sockets.iter().map(|bound| bound.local_addr).collect()
+    }
+
+    fn can_reach(&self, endpoint: &SocketAddr) -> bool {
+        self.socket_for(endpoint).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DatagramTransport, UdpTransport};
+    use std::net::SocketAddr;
+
+    async fn receive(transport: &UdpTransport) -> anyhow::Result<(Vec<u8>, SocketAddr)> {
+        let mut buffer = vec![0; 1024];
+        let (size, sender) = transport.recv_from(&mut buffer).await?;
+        buffer.truncate(size);
+        Ok((buffer, sender))
+    }
+
+    #[tokio::test]
+    async fn address_families() -> anyhow::Result<()> {
+        let ipv4 = UdpTransport::bind(&["127.0.0.1:0".parse()?])?;
+        let dual = UdpTransport::bind(&["127.0.0.1:0".parse()?, "[::1]:0".parse()?])?;
+        let ipv4_addr = ipv4.local_addr()?;
+        let dual_addrs = dual.local_addrs();
+        assert!(dual_addrs[0].is_ipv4() && dual_addrs[1].is_ipv6());
+
+        //no IPv6 socket
+        assert!(!ipv4.can_reach(&dual_addrs[1]));
+        assert!(ipv4.send_to(b"ping", &dual_addrs[1]).await.is_err());
+
+        //sent from the socket of the same family
+        dual.send_to(b"ping", &ipv4_addr).await?;
+        assert_eq!(receive(&ipv4).await?, (b"ping".to_vec(), dual_addrs[0]));
+        ipv4.send_to(b"pong", &dual_addrs[0]).await?;
+        assert_eq!(receive(&dual).await?, (b"pong".to_vec(), ipv4_addr));
+        dual.send_to(b"self", &dual_addrs[1]).await?;
+        assert_eq!(receive(&dual).await?, (b"self".to_vec(), dual_addrs[1]));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dual_stack() -> anyhow::Result<()> {
+        let ipv4 = UdpTransport::bind(&["127.0.0.1:0".parse()?])?;
+        let ipv6 = UdpTransport::bind(&["[::]:0".parse()?])?;
+        let ipv6_port = ipv6.local_addr()?.port();
+
+        //IPv4 peers are reached through the IPv6 socket, and seen by their IPv4 address
+        let ipv4_addr = ipv4.local_addr()?;
+        assert!(ipv6.can_reach(&ipv4_addr));
+        ipv4.send_to(b"ping", &format!("127.0.0.1:{}", ipv6_port).parse()?)
+            .await?;
+        assert_eq!(receive(&ipv6).await?, (b"ping".to_vec(), ipv4_addr));
+        ipv6.send_to(b"pong", &ipv4_addr).await?;
+        assert_eq!(receive(&ipv4).await?.0, b"pong".to_vec());
+        Ok(())
+    }
+}
@@ -1,4 +1,111 @@
+use openssl::rand::rand_bytes;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Upper bound of the replication level, as in GNUnet R5N.
+pub const MAXIMUM_REPLICATION_LEVEL: u32 = 16;
+
+/// Number of peers to forward a request to (R5N).
+/// Requests are spread to about replication_level peers during the first log2(N) hops,
+/// then forwarded to a single peer, and dropped after 4 * log2(N) hops.
 pub fn calculate_foward_count(network_size: usize, hop_count: u32, replication_level: u32) -> u16 {
-    16
-    //TODO implement
+    let target = foward_count_target(network_size, hop_count, replication_level);
+    let floor = target.floor();
+    //round up with the probability of the fractional part
+    let mut buf = [0; 4];
+    rand_bytes(&mut buf).expect("Failed to generate a random number");
+    let random = u32::from_le_bytes(buf) as f64 / u32::MAX as f64;
+    if random < target - floor {
+        floor as u16 + 1
+    } else {
+        floor as u16
+    }
+}
+
+/// Expected forward count, see calculate_foward_count.
+fn foward_count_target(network_size: usize, hop_count: u32, replication_level: u32) -> f64 {
+    let replication_level = replication_level.clamp(1, MAXIMUM_REPLICATION_LEVEL) as f64;
+    let log_network_size = (network_size.max(2) as f64).log2();
+    let hop_count = hop_count as f64;
+    if hop_count > log_network_size * 4.0 {
+        //forcefully terminate
+        return 0.0;
+    }
+    if hop_count > log_network_size * 2.0 {
+        return 1.0;
+    }
+    1.0 + (replication_level - 1.0) / (log_network_size + (replication_level - 1.0) * hop_count)
+}
+
+/// Number of hops of the random walk phase (R5N), log2(N) rounded up.
+/// Requests are routed to random peers until then, and greedily toward the key after.
+pub fn random_walk_length(network_size: usize) -> u32 {
+    (network_size.max(1) as f64).log2().ceil() as u32
+}
+
+/// Random number in [0, bound).
+pub fn random_index(bound: usize) -> usize {
+    debug_assert!(bound != 0);
+    let mut buf = [0; 8];
+    rand_bytes(&mut buf).expect("Failed to generate a random number");
+    (u64::from_le_bytes(buf) % bound as u64) as usize
+}
+
+/// Seconds since UNIX_EPOCH.
+pub fn unix_time_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Random transaction ID for messages which do not expect a response.
+pub fn new_transaction_id() -> u32 {
+    let mut buf = [0; 4];
+    rand_bytes(&mut buf).expect("Failed to generate a transaction id");
+    u32::from_le_bytes(buf)
+}
+
+/// Random nonce of a store request, which the replicas sign in their responses.
+pub fn new_nonce() -> u64 {
+    let mut buf = [0; 8];
+    rand_bytes(&mut buf).expect("Failed to generate a nonce");
+    u64::from_le_bytes(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{calculate_foward_count, foward_count_target, random_index, random_walk_length};
+
+    #[test]
+    fn foward_count() {
+        //log2(1024) = 10
+        assert_eq!(foward_count_target(1024, 41, 5), 0.0);
+        assert_eq!(foward_count_target(1024, 21, 5), 1.0);
+        assert_eq!(foward_count_target(1024, 0, 1), 1.0);
+        assert_eq!(foward_count_target(1024, 0, 11), 2.0);
+        //spreads less as the hop count grows
+        assert!(foward_count_target(1024, 1, 11) < foward_count_target(1024, 0, 11));
+        //replication level is bounded
+        assert_eq!(
+            foward_count_target(1024, 0, 1000),
+            foward_count_target(1024, 0, 16)
+        );
+
+        for _ in 0..100 {
+            let count = calculate_foward_count(1024, 1, 11);
+            assert!(count == 1 || count == 2);
+        }
+        assert_eq!(calculate_foward_count(1024, 41, 5), 0);
+    }
+
+    #[test]
+    fn random_walk() {
+        assert_eq!(random_walk_length(1), 0);
+        assert_eq!(random_walk_length(2), 1);
+        assert_eq!(random_walk_length(1000), 10);
+        assert_eq!(random_walk_length(1024), 10);
+        for _ in 0..100 {
+            assert!(random_index(3) < 3);
+        }
+    }
 }
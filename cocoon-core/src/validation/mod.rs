@@ -0,0 +1,269 @@
+use crate::constant::MAX_VALUE_SIZE;
+use anyhow::Result;
+use openssl::hash::{hash, MessageDigest};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::Arc;
+
+/// What to do with a value received for a get request.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ReplyEvaluation {
+    /// The value answers the request, no more values are needed.
+    Last,
+    /// The value answers the request, and other values may exist for the key
+    /// (e.g. several search results for a keyword).
+    More,
+    /// The value does not answer the request.
+    Invalid,
+}
+
+/// Why a value is not stored.
+#[derive(Debug, PartialEq)]
+pub enum StoreError {
+    /// The value can not be valid, e.g. the key is not the hash of the data.
+    /// The node which sent it stored or forwarded a bad value.
+    Invalid(String),
+    /// We do not take the value, other nodes may (e.g. no validator for the block type,
+    /// or a size limit which differs between versions).
+    Unsupported(String),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StoreError::Invalid(reason) => write!(formatter, "Invalid value: {}", reason),
+            StoreError::Unsupported(reason) => write!(formatter, "Unsupported value: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+/// BlockValidator
+/// Knows the format of one block type, so the DHT does not have to.
+/// The higher layer registers one for each block type it stores,
+/// values of the other types are rejected.
+pub trait BlockValidator: Send + Sync {
+    /// Check a value before storing or forwarding it.
+    fn validate_store(&self, key: &[u8], data: &[u8]) -> Result<(), StoreError>;
+
+    /// Check a value returned for the key.
+    fn evaluate_reply(&self, key: &[u8], data: &[u8]) -> ReplyEvaluation;
+
+    /// Values with the same reply hash are duplicates, only the first one is kept.
+    fn reply_hash(&self, data: &[u8]) -> Vec<u8> {
+        content_hash(data)
+    }
+}
+
+/// Validator of the values stored under the hash of the data.
+pub struct ContentHashValidator;
+
+impl BlockValidator for ContentHashValidator {
+    fn validate_store(&self, key: &[u8], data: &[u8]) -> Result<(), StoreError> {
+        if content_hash(data) != key {
+            return Err(StoreError::Invalid(
+                "The key is not the hash of the data".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn evaluate_reply(&self, key: &[u8], data: &[u8]) -> ReplyEvaluation {
+        //only one value has the hash
+        match self.validate_store(key, data) {
+            Ok(()) => ReplyEvaluation::Last,
+            Err(_) => ReplyEvaluation::Invalid,
+        }
+    }
+}
+
+/// BlockValidators
+/// Registered validators, keyed by block type.
+/// Values larger than MAX_VALUE_SIZE are rejected whatever the block type.
+pub struct BlockValidators {
+    validators: HashMap<u32, Arc<dyn BlockValidator>>,
+}
+
+impl BlockValidators {
+    pub fn new() -> Self {
+        BlockValidators {
+            validators: HashMap::new(),
+        }
+    }
+
+    /// Register the validator of the block type.
+    /// Replaces the validator registered before.
+    pub fn register(&mut self, block_type: u32, validator: Arc<dyn BlockValidator>) {
+        self.validators.insert(block_type, validator);
+    }
+
+    pub fn get(&self, block_type: u32) -> Option<Arc<dyn BlockValidator>> {
+        self.validators.get(&block_type).cloned()
+    }
+
+    /// Check a value before storing or forwarding it.
+    pub fn validate_store(
+        &self,
+        block_type: u32,
+        key: &[u8],
+        data: &[u8],
+    ) -> Result<(), StoreError> {
+        if data.len() > MAX_VALUE_SIZE {
+            return Err(StoreError::Unsupported(format!(
+                "Value of {} bytes is larger than {}",
+                data.len(),
+                MAX_VALUE_SIZE
+            )));
+        }
+        match self.validators.get(&block_type) {
+            Some(validator) => validator.validate_store(key, data),
+            None => Err(StoreError::Unsupported(format!(
+                "No validator for the block type {}",
+                block_type
+            ))),
+        }
+    }
+
+    /// Check a value returned for the key.
+    pub fn evaluate_reply(&self, block_type: u32, key: &[u8], data: &[u8]) -> ReplyEvaluation {
+        if data.len() > MAX_VALUE_SIZE {
+            return ReplyEvaluation::Invalid;
+        }
+        match self.validators.get(&block_type) {
+            Some(validator) => validator.evaluate_reply(key, data),
+            None => ReplyEvaluation::Invalid,
+        }
+    }
+}
+
+/// ValueReplies
+/// Values received for a get request, deduplicated.
+pub struct ValueReplies {
+    key: Vec<u8>,
+    validator: Arc<dyn BlockValidator>,
+    hashes: HashSet<Vec<u8>>,
+    values: Vec<Vec<u8>>,
+    is_finished: bool,
+}
+
+impl ValueReplies {
+    pub fn new(key: &[u8], validator: Arc<dyn BlockValidator>) -> Self {
+        ValueReplies {
+            key: key.to_vec(),
+            validator: validator,
+            hashes: HashSet::new(),
+            values: Vec::new(),
+            is_finished: false,
+        }
+    }
+
+    /// Add a received value.
+    /// Returns false if the value does not answer the request.
+    pub fn add(&mut self, data: &[u8]) -> bool {
+        let evaluation = self.validator.evaluate_reply(&self.key, data);
+        if evaluation == ReplyEvaluation::Invalid {
+            return false;
+        }
+        if self.hashes.insert(self.validator.reply_hash(data)) {
+            self.values.push(data.to_vec());
+        }
+        if evaluation == ReplyEvaluation::Last {
+            self.is_finished = true;
+        }
+        true
+    }
+
+    /// Returns true if no more values are needed.
+    pub fn is_finished(&self) -> bool {
+        self.is_finished
+    }
+
+    pub fn into_values(self) -> Vec<Vec<u8>> {
+        self.values
+    }
+}
+
+/// Key of a content addressed value.
+pub fn content_hash(data: &[u8]) -> Vec<u8> {
+    hash(MessageDigest::sha3_512(), data)
+        .expect("Failed to hash data")
+        .to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        content_hash, BlockValidator, BlockValidators, ContentHashValidator, ReplyEvaluation,
+        StoreError, ValueReplies,
+    };
+    use crate::constant::MAX_VALUE_SIZE;
+    use std::sync::Arc;
+
+    /// Accepts any value, several values per key.
+    struct AnyValidator;
+
+    impl BlockValidator for AnyValidator {
+        fn validate_store(&self, _key: &[u8], _data: &[u8]) -> Result<(), StoreError> {
+            Ok(())
+        }
+
+        fn evaluate_reply(&self, _key: &[u8], _data: &[u8]) -> ReplyEvaluation {
+            ReplyEvaluation::More
+        }
+    }
+
+    #[test]
+    fn content_addressed() {
+        let mut validators = BlockValidators::new();
+        validators.register(1, Arc::new(ContentHashValidator));
+        let data = b"encrypted block";
+        let key = content_hash(data);
+        assert_eq!(key.len(), 64);
+        assert!(validators.validate_store(1, &key, data).is_ok());
+        assert!(matches!(
+            validators.validate_store(1, &key, b"other block"),
+            Err(StoreError::Invalid(_))
+        ));
+        assert!(validators.validate_store(1, &[0; 64], data).is_err());
+        //no validator, another node may have one
+        assert!(matches!(
+            validators.validate_store(2, &key, data),
+            Err(StoreError::Unsupported(_))
+        ));
+        assert!(validators.get(2).is_none());
+        assert_eq!(
+            validators.evaluate_reply(1, &key, data),
+            ReplyEvaluation::Last
+        );
+        assert_eq!(
+            validators.evaluate_reply(2, &key, data),
+            ReplyEvaluation::Invalid
+        );
+        //too large whatever the validator says
+        let large = vec![0; MAX_VALUE_SIZE + 1];
+        assert!(matches!(
+            validators.validate_store(1, &content_hash(&large), &large),
+            Err(StoreError::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    fn value_replies() {
+        let data = b"encrypted block";
+        let key = content_hash(data);
+        let mut replies = ValueReplies::new(&key, Arc::new(ContentHashValidator));
+        assert!(!replies.add(b"other block"));
+        assert!(!replies.is_finished());
+        assert!(replies.add(data));
+        assert!(replies.is_finished());
+        assert_eq!(replies.into_values(), vec![data.to_vec()]);
+
+        let mut replies = ValueReplies::new(b"keyword", Arc::new(AnyValidator));
+        assert!(replies.add(b"a"));
+        assert!(replies.add(b"b"));
+        assert!(replies.add(b"a"));
+        assert!(!replies.is_finished());
+        assert_eq!(replies.into_values(), vec![b"a".to_vec(), b"b".to_vec()]);
+    }
+}
@@ -1,4 +1,5 @@
-use cocoon_virtual::VirtualNetworkManager;
+use cocoon_core::content_hash;
+use cocoon_virtual::{VirtualNetworkManager, VIRTUAL_BLOCK_TYPE};
 use openssl::rand::rand_bytes;
 use std::str::FromStr;
 use tokio::sync::mpsc;
@@ -40,14 +41,16 @@ async fn main() -> anyhow::Result<()> {
             "store" => {
                 println!("Store");
                 let vp = &vnm.virtual_peers[0];
-                rand_bytes(&mut r_key).unwrap();
                 rand_bytes(&mut r_data).unwrap();
-                vp.dht_manager.do_store(&r_key, &r_data).await;
+                r_key = content_hash(&r_data);
+                vp.dht_manager
+                    .do_store(&r_key, &r_data, VIRTUAL_BLOCK_TYPE)
+                    .await;
             }
             "fstore" => {
                 println!("Force store");
-                rand_bytes(&mut r_key).unwrap();
                 rand_bytes(&mut r_data).unwrap();
+                r_key = content_hash(&r_data);
                 for i in 1..vnm.virtual_peers.len() {
                     let vp = &vnm.virtual_peers[i];
                     vp.force_store(&r_key, &r_data)?;
@@ -59,14 +62,25 @@ async fn main() -> anyhow::Result<()> {
                     hex::encode(&r_key)
                 );
                 let vp = &vnm.virtual_peers[0];
-                vp.dht_manager.do_find_value(&r_key).await;
+                match vp
+                    .dht_manager
+                    .do_find_value(&r_key, VIRTUAL_BLOCK_TYPE)
+                    .await?
+                {
+                    Some(data) => println!("Found {} bytes", data.len()),
+                    None => println!("Not found"),
+                }
             }
             "findn" => {
                 println!("Find node");
                 let vp = &vnm.virtual_peers[0];
                 let mut r_id = vec![0; 64];
                 rand_bytes(&mut r_id)?;
-                vp.dht_manager.do_find_node(&r_id).await;
+                let nodes = vp.dht_manager.do_find_node(&r_id).await?;
+                println!("Found {} nodes", nodes.len());
+                for node in &nodes {
+                    println!("{}", node);
+                }
             }
             "connectall" => {
                 println!("Connect all nodes each other");
@@ -1,5 +1,8 @@
 use cocoon_core::DHTManager;
-use cocoon_core::{KVDatabaseConfig, SqliteConfig};
+use cocoon_core::{
+    content_hash, ContentHashValidator, DatagramTransport, Identity, KVDatabaseConfig,
+    MemoryNetwork, NetworkManagerConfig, SqliteConfig, UdpTransport,
+};
 use openssl::rand::rand_bytes;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::PathBuf;
@@ -8,6 +11,10 @@ use tokio::sync::RwLock;
 use tracing::{event, Level};
 //https://www.reddit.com/r/rust/comments/f4zldz/i_audited_3_different_implementation_of_async/
 
+/// Block type of the values stored by the virtual peers.
+/// The values are stored under the hash of the data.
+pub const VIRTUAL_BLOCK_TYPE: u32 = 1;
+
 pub struct VirtualPeer {
     pub dht_manager: Arc<DHTManager>,
     pub name: String,
@@ -15,21 +22,65 @@ pub struct VirtualPeer {
 
 impl VirtualPeer {
     pub async fn new(name: &str) -> anyhow::Result<Self> {
-        let dummy = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0);
+        let network_config = NetworkManagerConfig {
+            bind_addresses: vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)],
+            ..Default::default()
+        };
+        Self::with_network_config(name, &network_config).await
+    }
+
+    /// Create a peer with its own bind addresses etc.
+    pub async fn with_network_config(
+        name: &str,
+        network_config: &NetworkManagerConfig,
+    ) -> anyhow::Result<Self> {
+        let transport = UdpTransport::bind(&network_config.bind_addresses)?;
+        Self::with_transport(name, network_config, Arc::new(transport)).await
+    }
+
+    /// Create a peer on the address of the memory network, without a real socket.
+    /// The bind addresses of the config are not used.
+    pub async fn in_memory(
+        name: &str,
+        network: &MemoryNetwork,
+        address: &SocketAddr,
+        network_config: &NetworkManagerConfig,
+    ) -> anyhow::Result<Self> {
+        let transport = network.bind(address)?;
+        Self::with_transport(name, network_config, Arc::new(transport)).await
+    }
+
+    async fn with_transport(
+        name: &str,
+        network_config: &NetworkManagerConfig,
+        transport: Arc<dyn DatagramTransport>,
+    ) -> anyhow::Result<Self> {
         let mut db_path = std::env::current_dir()?;
         db_path.push("kvdb_".to_owned() + name);
-        let dummy_config = KVDatabaseConfig { db_path };
+        let dummy_config = KVDatabaseConfig {
+            db_path,
+            quota: 1 << 30,
+        };
         let sqlite_config = SqliteConfig {
             db_path: PathBuf::from(":memory:"),
         };
+        let dht_manager = DHTManager::with_transport(
+            &dummy_config,
+            &sqlite_config,
+            network_config,
+            Identity::generate()?,
+            transport,
+        )
+        .await?;
+        dht_manager.register_validator(VIRTUAL_BLOCK_TYPE, Arc::new(ContentHashValidator));
         Ok(Self {
-            dht_manager: Arc::new(DHTManager::new(&dummy_config, &sqlite_config, &dummy).await?),
+            dht_manager: Arc::new(dht_manager),
             name: name.to_string(),
         })
     }
 
-    /// Store random data at random key.
-    /// Returns stored (key,data)
+    /// Store the data on the local kvdb.
+    /// The key must be the hash of the data.
     pub fn force_store(&self, key: &[u8], data: &[u8]) -> anyhow::Result<()> {
         event!(
             Level::INFO,
@@ -37,7 +88,8 @@ impl VirtualPeer {
             self.name,
             hex::encode(key)
         );
-        self.dht_manager.store_on_local(&key, &data)?;
+        self.dht_manager
+            .store_on_local(&key, &data, VIRTUAL_BLOCK_TYPE)?;
         Ok(())
     }
 }
@@ -61,6 +113,30 @@ impl VirtualNetworkManager {
         })
     }
 
+    /// Start the peers on the memory network, at 10.0.0.1:4870, 10.0.0.2:4870 and so on.
+    pub async fn in_memory(peers: usize, network: &MemoryNetwork) -> anyhow::Result<Self> {
+        let mut vpeers = Vec::new();
+        for i in 0..peers {
+            let ip = Ipv4Addr::from(u32::from(Ipv4Addr::new(10, 0, 0, 1)) + i as u32);
+            let address = SocketAddr::new(IpAddr::V4(ip), 4870);
+            let vp = Arc::new(
+                VirtualPeer::in_memory(
+                    &format!("mvp {}", i),
+                    network,
+                    &address,
+                    &NetworkManagerConfig::default(),
+                )
+                .await?,
+            );
+            vpeers.push(vp.clone());
+            vp.dht_manager.start_receive().await;
+        }
+        Ok(Self {
+            virtual_peers: vpeers,
+            last_stored_key: Arc::new(RwLock::new(vec![0; 64])),
+        })
+    }
+
     pub async fn connect_all_each_other(&self) -> anyhow::Result<()> {
         for i in 0..self.virtual_peers.len() - 1 {
             for j in i + 1..self.virtual_peers.len() {
@@ -120,11 +196,13 @@ impl VirtualNetworkManager {
                     choosed_vp.name,
                     other_vp.name
                 );
-                let mut rk = vec![0; 64];
                 let mut rd = vec![0; 64];
-                rand_bytes(&mut rk)?;
                 rand_bytes(&mut rd)?;
-                choosed_vp.dht_manager.do_store(&rk, &rd).await;
+                let rk = content_hash(&rd);
+                choosed_vp
+                    .dht_manager
+                    .do_store(&rk, &rd, VIRTUAL_BLOCK_TYPE)
+                    .await;
                 {
                     let mut w = self.last_stored_key.write().await;
                     *w = rk;
@@ -138,10 +216,11 @@ impl VirtualNetworkManager {
                     choosed_vp.name,
                     other_vp.name
                 );
-                choosed_vp
+                let value = choosed_vp
                     .dht_manager
-                    .do_find_value(&*self.last_stored_key.read().await)
-                    .await;
+                    .do_find_value(&*self.last_stored_key.read().await, VIRTUAL_BLOCK_TYPE)
+                    .await?;
+                event!(Level::INFO, "Found: {}", value.is_some());
             }
             3 => {
                 //find node
@@ -151,10 +230,11 @@ impl VirtualNetworkManager {
                     choosed_vp.name,
                     other_vp.name
                 );
-                choosed_vp
+                let nodes = choosed_vp
                     .dht_manager
                     .do_find_node(&*self.last_stored_key.read().await)
-                    .await;
+                    .await?;
+                event!(Level::INFO, "Found {} nodes", nodes.len());
             }
             _ => {
                 unreachable!();
@@ -171,11 +251,12 @@ mod tests {
     async fn force_store_test() -> anyhow::Result<()> {
         let vnm = VirtualNetworkManager::new(1).await?;
         let peer = &vnm.virtual_peers[0];
-        let mut rk = vec![0; 64];
         let mut rd = vec![0; 64];
-        rand_bytes(&mut rk)?;
         rand_bytes(&mut rd)?;
+        let rk = content_hash(&rd);
         peer.force_store(&rk, &rd)?;
+        //not content addressed
+        assert!(peer.force_store(&[0; 64], &rd).is_err());
         assert!(peer.dht_manager.is_available_on_local(&rk)?);
         Ok(())
     }
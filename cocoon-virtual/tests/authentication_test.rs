@@ -0,0 +1,38 @@
+use cocoon_virtual::VirtualNetworkManager;
+use tracing::Level;
+
+/// Send unsigned and forged datagrams to a virtual peer.
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn authentication_test() -> anyhow::Result<()> {
+    tracing_subscriber::fmt()
+        .with_thread_names(true)
+        .with_max_level(Level::DEBUG)
+        .init();
+
+    let vnm = VirtualNetworkManager::new(1).await?;
+    let vp = &vnm.virtual_peers[0];
+    let target = vp.dht_manager.local_endpoint()?;
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+
+    //too short to be signed
+    socket.send_to(&[1, 0, 0, 0, 7, 0, 0, 0], target).await?;
+    //ping request (type 1) with a trailer of zeros
+    let mut forged = vec![1, 0, 0, 0, 7, 0, 0, 0];
+    forged.extend_from_slice(&[0; 96]);
+    socket.send_to(&forged, target).await?;
+
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+    assert_eq!(vp.dht_manager.rejected_datagram_count(), 2);
+    assert_eq!(vp.dht_manager.route_table.lock().await.len(), 0);
+
+    //no pong for them
+    let mut buffer = vec![0; 1024];
+    let received = tokio::time::timeout(
+        std::time::Duration::from_millis(500),
+        socket.recv_from(&mut buffer),
+    )
+    .await;
+    assert!(received.is_err());
+
+    Ok(())
+}
@@ -0,0 +1,39 @@
+use cocoon_virtual::VirtualNetworkManager;
+use tracing::Level;
+
+/// Start three virtual peers and bootstrap the last one from the first one.
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn bootstrap_test() -> anyhow::Result<()> {
+    tracing_subscriber::fmt()
+        .with_thread_names(true)
+        .with_max_level(Level::DEBUG)
+        .init();
+
+    let vnm = VirtualNetworkManager::new(3).await?;
+    let vp1 = &vnm.virtual_peers[0];
+    let vp2 = &vnm.virtual_peers[1];
+    let vp3 = &vnm.virtual_peers[2];
+
+    //no seeds and no known nodes
+    let status = vp3.dht_manager.bootstrap(&[]).await?;
+    assert!(!status.is_success());
+    assert_eq!(status.responded_seeds, 0);
+
+    //vp1 and vp2 know each other
+    vp1.dht_manager
+        .do_ping(&vp2.dht_manager.local_endpoint()?)
+        .await?;
+
+    let seed = vp1.dht_manager.local_endpoint()?;
+    let status = vp3.dht_manager.bootstrap(&[seed]).await?;
+    assert!(status.is_success());
+    assert_eq!(status.responded_seeds, 1);
+    assert!(vp3
+        .dht_manager
+        .route_table
+        .lock()
+        .await
+        .contains(&vp1.dht_manager.node_id()));
+
+    Ok(())
+}
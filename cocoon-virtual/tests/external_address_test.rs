@@ -0,0 +1,56 @@
+use cocoon_core::NetworkManagerConfig;
+use cocoon_virtual::{VirtualNetworkManager, VirtualPeer};
+use std::net::SocketAddr;
+use tracing::Level;
+
+/// A peer bound on an unspecified address learns its endpoint from the pongs of other peers.
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn external_address_test() -> anyhow::Result<()> {
+    tracing_subscriber::fmt()
+        .with_thread_names(true)
+        .with_max_level(Level::DEBUG)
+        .init();
+
+    let vnm = VirtualNetworkManager::new(3).await?;
+    let unspecified = VirtualPeer::with_network_config(
+        "vp unspecified",
+        &NetworkManagerConfig {
+            bind_addresses: vec!["0.0.0.0:0".parse()?],
+            ..Default::default()
+        },
+    )
+    .await?;
+    unspecified.dht_manager.start_receive().await;
+    let announced_address: SocketAddr = "203.0.113.1:4870".parse()?;
+    let announced = VirtualPeer::with_network_config(
+        "vp announced",
+        &NetworkManagerConfig {
+            bind_addresses: vec!["127.0.0.1:0".parse()?],
+            announced_address: Some(announced_address),
+            ..Default::default()
+        },
+    )
+    .await?;
+    announced.dht_manager.start_receive().await;
+
+    let bound = unspecified.dht_manager.local_endpoint()?;
+    assert!(bound.ip().is_unspecified());
+    assert_eq!(unspecified.dht_manager.own_endpoint().await, bound);
+    let observed: SocketAddr = format!("127.0.0.1:{}", bound.port()).parse()?;
+
+    for vp in &vnm.virtual_peers {
+        let endpoint = vp.dht_manager.local_endpoint()?;
+        unspecified.dht_manager.do_ping(&endpoint).await?;
+        announced.dht_manager.do_ping(&endpoint).await?;
+    }
+    //taken once the majority of the 3 nodes agree
+    //(the pong is reported after the ping completes)
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    assert_eq!(unspecified.dht_manager.own_endpoint().await, observed);
+    //the announced endpoint is kept
+    assert_eq!(
+        announced.dht_manager.own_endpoint().await,
+        announced_address
+    );
+    Ok(())
+}
@@ -0,0 +1,40 @@
+use cocoon_core::MemoryNetwork;
+use cocoon_virtual::VirtualNetworkManager;
+use tracing::Level;
+
+/// Run many peers on a memory network, and drop their packets.
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn in_memory_test() -> anyhow::Result<()> {
+    tracing_subscriber::fmt()
+        .with_thread_names(true)
+        .with_max_level(Level::INFO)
+        .init();
+
+    let network = MemoryNetwork::new(42);
+    let vnm = VirtualNetworkManager::in_memory(1000, &network).await?;
+    let vp1 = &vnm.virtual_peers[0];
+    let vp2 = &vnm.virtual_peers[999];
+    let seed = vp1.dht_manager.local_endpoint()?;
+    assert_eq!(seed.to_string(), "10.0.0.1:4870");
+    assert_eq!(
+        vp2.dht_manager.local_endpoint()?.to_string(),
+        "10.0.3.232:4870"
+    );
+    for vp in &vnm.virtual_peers[1..] {
+        let status = vp.dht_manager.bootstrap(&[seed]).await?;
+        assert_eq!(status.responded_seeds, 1);
+    }
+    assert_eq!(network.dropped_count(), 0);
+    //every peer talked to the seed through an encrypted session
+    assert!(vp1.dht_manager.session_count() >= 999);
+    assert_eq!(vp1.dht_manager.rejected_datagram_count(), 0);
+
+    //every datagram is lost
+    network.set_loss_rate(1.0);
+    assert!(vp2.dht_manager.do_ping(&seed).await.is_err());
+    assert!(network.dropped_count() > 0);
+
+    network.set_loss_rate(0.0);
+    assert!(vp2.dht_manager.do_ping(&seed).await.is_ok());
+    Ok(())
+}
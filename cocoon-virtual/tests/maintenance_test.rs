@@ -0,0 +1,69 @@
+use cocoon_core::{MaintenanceConfig, MemoryNetwork, NetworkManagerConfig};
+use cocoon_virtual::{VirtualNetworkManager, VirtualPeer};
+use std::time::Duration;
+use tracing::Level;
+
+/// Ping the quiet nodes, drop the dead one and refresh the buckets.
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn maintenance_test() -> anyhow::Result<()> {
+    tracing_subscriber::fmt()
+        .with_thread_names(true)
+        .with_max_level(Level::INFO)
+        .init();
+
+    let network = MemoryNetwork::new(7);
+    let vnm = VirtualNetworkManager::in_memory(10, &network).await?;
+    let seed = vnm.virtual_peers[0].dht_manager.local_endpoint()?;
+    let network_config = NetworkManagerConfig {
+        maintenance: MaintenanceConfig {
+            bucket_refresh_secs: 0,
+            quiet_node_secs: 0,
+            max_failures: 1,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let vp = VirtualPeer::in_memory(
+        "maintainer",
+        &network,
+        &"10.0.1.1:4870".parse()?,
+        &network_config,
+    )
+    .await?;
+    vp.dht_manager.start_receive().await;
+    vp.dht_manager.bootstrap(&[seed]).await?;
+
+    //a node which stops answering
+    let dead = &vnm.virtual_peers[5].dht_manager;
+    vp.dht_manager.do_ping(&dead.local_endpoint()?).await?;
+    //the response is added to the route table after do_ping returns
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    dead.shutdown().await?;
+    //let the receive loop stop
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let known = vp.dht_manager.route_table.lock().await.len();
+    assert!(vp
+        .dht_manager
+        .route_table
+        .lock()
+        .await
+        .contains(&dead.node_id()));
+
+    let status = vp.dht_manager.maintain().await;
+    assert_eq!(status.pinged_nodes, known);
+    assert_eq!(status.unresponsive_nodes, 1);
+    assert_eq!(status.removed_nodes, 1);
+    assert!(status.refreshed_buckets >= 1);
+    assert!(!vp
+        .dht_manager
+        .route_table
+        .lock()
+        .await
+        .contains(&dead.node_id()));
+
+    //the others answered
+    let status = vp.dht_manager.maintain().await;
+    assert_eq!(status.unresponsive_nodes, 0);
+    assert_eq!(status.removed_nodes, 0);
+    Ok(())
+}
@@ -0,0 +1,48 @@
+use cocoon_core::{MemoryNetwork, NetworkManagerConfig};
+use cocoon_virtual::{VirtualNetworkManager, VirtualPeer};
+use std::time::Duration;
+use tracing::Level;
+
+/// Messages which do not fit in the receive queue are dropped, and retransmitted by the requester.
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn receive_queue_test() -> anyhow::Result<()> {
+    tracing_subscriber::fmt()
+        .with_thread_names(true)
+        .with_max_level(Level::INFO)
+        .init();
+
+    let network = MemoryNetwork::new(11);
+    let vnm = VirtualNetworkManager::in_memory(1, &network).await?;
+    let pinger = vnm.virtual_peers[0].dht_manager.clone();
+    let network_config = NetworkManagerConfig {
+        receive_queue_size: 1,
+        receive_workers: 1,
+        ..Default::default()
+    };
+    let vp = VirtualPeer::in_memory("busy", &network, &"10.0.1.1:4870".parse()?, &network_config)
+        .await?;
+    vp.dht_manager.start_receive().await;
+    let endpoint = vp.dht_manager.local_endpoint()?;
+
+    let pings = {
+        //the worker waits for the route table (unless it was slower than the burst),
+        //one more message fits in the queue
+        let _route_table = vp.dht_manager.route_table.lock().await;
+        let pings: Vec<_> = (0..5)
+            .map(|_| {
+                let pinger = pinger.clone();
+                tokio::spawn(async move { pinger.do_ping(&endpoint).await })
+            })
+            .collect();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(vp.dht_manager.dropped_message_count() >= 3);
+        pings
+    };
+
+    //the dropped requests are sent again, and handled
+    for ping in pings {
+        ping.await??;
+    }
+    assert_eq!(vp.dht_manager.rejected_datagram_count(), 0);
+    Ok(())
+}
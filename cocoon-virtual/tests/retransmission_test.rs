@@ -0,0 +1,41 @@
+use cocoon_core::MemoryNetwork;
+use cocoon_virtual::VirtualNetworkManager;
+use std::time::Duration;
+use tracing::Level;
+
+/// Ping over a lossy network, the lost requests and responses are retransmitted.
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn retransmission_test() -> anyhow::Result<()> {
+    tracing_subscriber::fmt()
+        .with_thread_names(true)
+        .with_max_level(Level::INFO)
+        .init();
+
+    let network = MemoryNetwork::new(3);
+    let vnm = VirtualNetworkManager::in_memory(2, &network).await?;
+    let vp1 = &vnm.virtual_peers[0].dht_manager;
+    let vp2 = &vnm.virtual_peers[1].dht_manager;
+    let endpoint = vp2.local_endpoint()?;
+
+    //establish the session and measure the round trip time without losses
+    vp1.do_ping(&endpoint).await?;
+    //the response is added to the route table after do_ping returns
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    {
+        let route_table = vp1.route_table.lock().await;
+        let node = route_table.get_node(&vp2.node_id());
+        assert!(node.lock().unwrap().rtt.srtt().is_some());
+    }
+
+    //30% of the datagrams are lost, a single try would succeed about half the time
+    network.set_loss_rate(0.3);
+    let mut answered = 0;
+    for _ in 0..20 {
+        if vp1.do_ping(&endpoint).await.is_ok() {
+            answered += 1;
+        }
+    }
+    assert!(answered >= 14, "answered {}", answered);
+    assert!(network.dropped_count() > 0);
+    Ok(())
+}
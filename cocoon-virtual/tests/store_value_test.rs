@@ -0,0 +1,66 @@
+use cocoon_core::content_hash;
+use cocoon_virtual::{VirtualNetworkManager, VIRTUAL_BLOCK_TYPE};
+use openssl::rand::rand_bytes;
+use tracing::Level;
+
+/// Store values from the first virtual peer and find them from the last one.
+/// The requests are routed through the random walk phase first.
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn store_value_test() -> anyhow::Result<()> {
+    tracing_subscriber::fmt()
+        .with_thread_names(true)
+        .with_max_level(Level::DEBUG)
+        .init();
+
+    let vnm = VirtualNetworkManager::new(5).await?;
+    vnm.connect_all_each_other().await?;
+    let vp1 = &vnm.virtual_peers[0];
+    let vp5 = &vnm.virtual_peers[4];
+    assert!(vp1.dht_manager.network_size_estimate().await >= 5);
+
+    let mut data = vec![0; 64];
+    rand_bytes(&mut data)?;
+    let key = content_hash(&data);
+    let replicas = vp1
+        .dht_manager
+        .do_store(&key, &data, VIRTUAL_BLOCK_TYPE)
+        .await?;
+    //the replicas confirmed, other nodes than the publisher
+    assert!(!replicas.is_empty());
+    assert!(replicas
+        .iter()
+        .all(|replica| replica.public_key != vp1.dht_manager.public_key()));
+    //not kept as a local record unless asked to
+    assert_eq!(vp1.dht_manager.storage_usage().local_bytes, 0);
+    vp1.dht_manager
+        .publish_local(&key, &data, VIRTUAL_BLOCK_TYPE)?;
+    assert_eq!(vp1.dht_manager.storage_usage().local_bytes, 64);
+
+    assert_eq!(
+        vp5.dht_manager
+            .do_find_value(&key, VIRTUAL_BLOCK_TYPE)
+            .await?,
+        Some(data)
+    );
+
+    //larger than the MTU, sent in fragments
+    let mut large_data = vec![0; 40000];
+    rand_bytes(&mut large_data)?;
+    let large_key = content_hash(&large_data);
+    let replicas = vp1
+        .dht_manager
+        .do_store(&large_key, &large_data, VIRTUAL_BLOCK_TYPE)
+        .await?;
+    assert!(!replicas.is_empty());
+    assert_eq!(
+        vp5.dht_manager
+            .do_find_value(&large_key, VIRTUAL_BLOCK_TYPE)
+            .await?,
+        Some(large_data)
+    );
+
+    vp1.dht_manager.unpublish(&key)?;
+    assert_eq!(vp1.dht_manager.storage_usage().local_bytes, 0);
+
+    Ok(())
+}
@@ -1,7 +1,8 @@
 use clap::{Parser, Subcommand};
 use ilnyaplus_messages::ilnyaplus::ilnyaplus_rpc_service_client::IlnyaplusRpcServiceClient;
 use ilnyaplus_messages::{
-    Request, StartUploadTaskRequestMessage, UploadRequestMessage, UploadTaskInfoRequestMessage,
+    RemoveUploadTaskRequestMessage, Request, StartUploadTaskRequestMessage,
+    StorageUsageRequestMessage, UploadRequestMessage, UploadTaskInfoRequestMessage,
 };
 use std::env;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
@@ -32,6 +33,10 @@ enum Commands {
     StartUploadTask {
         task_uuid: String,
     },
+    RemoveUploadTask {
+        task_uuid: String,
+    },
+    StorageUsage {},
 }
 
 //https://github.com/clap-rs/clap/blob/master/examples/git-derive.rs
@@ -89,6 +94,22 @@ async fn main() -> anyhow::Result<()> {
             let response = client.start_upload_task(request).await?;
             println!("Started upload task, id:{}", task_uuid);
         }
+        Commands::RemoveUploadTask { task_uuid } => {
+            let request = Request::new(RemoveUploadTaskRequestMessage {
+                task_uuid: task_uuid.to_owned(),
+            });
+            client.remove_upload_task(request).await?;
+            println!("Removed upload task, id:{}", task_uuid);
+        }
+        Commands::StorageUsage {} => {
+            let request = Request::new(StorageUsageRequestMessage {});
+            let response = client.storage_usage(request).await?;
+            let usage = response.get_ref();
+            println!(
+                "Records: {}\nUsedBytes: {}\nLocalBytes: {}\nQuotaBytes: {}",
+                usage.records, usage.used_bytes, usage.local_bytes, usage.quota_bytes
+            );
+        }
     }
 
     println!("Bye.");
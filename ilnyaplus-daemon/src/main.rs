@@ -3,7 +3,7 @@ use cirrus_core::upload_manager::UploadManager;
 use cirrus_core::Uuid;
 use cocoon_core::DHTManager;
 use cocoon_core::DaemonConfig;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use cocoon_core::Identity;
 use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::Arc;
@@ -132,6 +132,44 @@ impl IlnyaplusRpcService for DaemonRpcService {
         let reply = StartUploadTaskResponseMessage {};
         Ok(Response::new(reply))
     }
+
+    async fn remove_upload_task(
+        &self,
+        request: Request<RemoveUploadTaskRequestMessage>,
+    ) -> Result<Response<RemoveUploadTaskResponseMessage>, Status> {
+        let request_msg = request.into_inner();
+        let task_uuid = match Uuid::from_str(&request_msg.task_uuid) {
+            Ok(task_uuid) => task_uuid,
+            Err(_) => return Err(Status::new(Code::InvalidArgument, "Invalid uuid string.")),
+        };
+        let result;
+        {
+            let mut ul_manager = self.ul_manager.lock().await;
+            result = ul_manager.remove_task(&task_uuid).await;
+        }
+        if let Err(e) = result {
+            event!(Level::WARN, "Failed to remove the upload task: {}", e);
+            return Err(Status::new(
+                Code::Internal,
+                "Failed to remove the upload task.",
+            ));
+        }
+        Ok(Response::new(RemoveUploadTaskResponseMessage {}))
+    }
+
+    async fn storage_usage(
+        &self,
+        _request: Request<StorageUsageRequestMessage>,
+    ) -> Result<Response<StorageUsageResponseMessage>, Status> {
+        let usage = self.dht_manager.storage_usage();
+        let reply = StorageUsageResponseMessage {
+            records: usage.records,
+            used_bytes: usage.used_bytes,
+            local_bytes: usage.local_bytes,
+            quota_bytes: usage.quota_bytes,
+        };
+        Ok(Response::new(reply))
+    }
 }
 
 #[tokio::main]
@@ -170,13 +208,16 @@ async fn main() -> anyhow::Result<()> {
     event!(Level::DEBUG, "{:?}", daemon_config);
 
     //dht manager stuffs
-    let bind_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0); //todo from config
+    let identity =
+        Identity::load_or_generate(&daemon_config.working_directory.join("identity.pem"))?;
     let dht_manager = DHTManager::new(
         &daemon_config.kv_database_config,
         &daemon_config.sqlite_config,
-        &bind_addr,
+        &daemon_config.network_manager_config,
+        identity,
     )
     .await?;
+    cirrus_core::ecrs::register_block_validators(&dht_manager);
     let dht_manager = Arc::new(dht_manager);
     let cloned_dht_manager = dht_manager.clone();
 
@@ -188,10 +229,21 @@ async fn main() -> anyhow::Result<()> {
     let ul_manager = UploadManager::new(&daemon_config.working_directory, &dht_manager).await?;
     let ul_manager = Arc::new(tokio::sync::Mutex::new(ul_manager));
 
-    //todo bootstrap and get own address or maybe use public key as an id
+    //todo get own address
 
     //everything set! start the dht manager.
     dht_manager.start_receive().await;
+    dht_manager.start_maintenance();
+
+    //join the network
+    let bootstrap_status = dht_manager
+        .bootstrap(&daemon_config.network_manager_config.bootstrap_nodes)
+        .await?;
+    if bootstrap_status.is_success() {
+        event!(Level::INFO, "Bootstrap done: {:?}", bootstrap_status);
+    } else {
+        event!(Level::WARN, "Bootstrap failed: {:?}", bootstrap_status);
+    }
     // tokio::join!(handle); //   loop {}
     let rpc_sevice_server = DaemonRpcService {
         dht_manager: cloned_dht_manager,
@@ -205,7 +257,13 @@ async fn main() -> anyhow::Result<()> {
 
     Server::builder()
         .add_service(IlnyaplusRpcServiceServer::new(rpc_sevice_server))
-        .serve(addr)
+        .serve_with_shutdown(addr, async {
+            let _ = tokio::signal::ctrl_c().await;
+            event!(Level::INFO, "Received ctrl-c");
+        })
         .await?;
+
+    //stop the dht manager and save the route table
+    dht_manager.shutdown().await?;
     Ok(())
 }